@@ -0,0 +1,177 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use anyhow::Result;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::bit_writer::BitWriter;
+use crate::deflate::DeflateWriter;
+use crate::gzip::MemberFooter;
+use crate::resumable::{ResumableDecoder, Step};
+use crate::CompressOptions;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Decompresses a gzip stream (possibly multi-member) read from `R`.
+pub struct AsyncGzipDecoder<R> {
+    inner: R,
+    decoder: ResumableDecoder,
+    block: Vec<u8>,
+    block_pos: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncGzipDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            decoder: ResumableDecoder::new(),
+            block: Vec::new(),
+            block_pos: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for AsyncGzipDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.block_pos < this.block.len() {
+                let available = &this.block[this.block_pos..];
+                let size = available.len().min(buf.remaining());
+                buf.put_slice(&available[..size]);
+                this.block_pos += size;
+                return Poll::Ready(Ok(()));
+            }
+
+            let step = this
+                .decoder
+                .step()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            match step {
+                Step::Block(block) => {
+                    this.block = block;
+                    this.block_pos = 0;
+                }
+                Step::NeedInput => {
+                    let data = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+                    if data.is_empty() {
+                        this.decoder.finish_input();
+                    } else {
+                        let size = data.len();
+                        this.decoder.feed(data);
+                        Pin::new(&mut this.inner).consume(size);
+                    }
+                }
+                Step::Finished => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Compresses the data written to it into a single gzip member written to
+/// `W`. The member is completed by `shutdown`.
+pub struct AsyncGzipEncoder<W> {
+    inner: W,
+    deflate_writer: Option<DeflateWriter<Vec<u8>>>,
+    /// Compressed data not yet written to `inner`.
+    output: Vec<u8>,
+}
+
+/// Amount of compressed data buffered before `poll_write` waits for `inner`.
+const MAX_BUFFERED: usize = 1 << 16;
+
+impl<W: AsyncWrite + Unpin> AsyncGzipEncoder<W> {
+    /// Compresses with the default options, see [`CompressOptions`].
+    pub fn new(inner: W) -> Self {
+        Self::with_options(inner, &CompressOptions::default())
+            .expect("the default options are valid")
+    }
+
+    pub fn with_options(inner: W, options: &CompressOptions) -> Result<Self> {
+        let mut header = options.header.clone();
+        if options.reproducible {
+            header.make_reproducible();
+        }
+        let mut output = Vec::new();
+        header.write_to(&mut output)?;
+        let mut deflate_writer =
+            DeflateWriter::with_level(BitWriter::new(Vec::new()), options.level)?;
+        deflate_writer.set_rsyncable(options.rsyncable);
+        Ok(Self {
+            inner,
+            deflate_writer: Some(deflate_writer),
+            output,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Writes out the buffered compressed data until at most `limit` bytes
+    /// are left.
+    fn poll_drain(&mut self, cx: &mut Context<'_>, limit: usize) -> Poll<io::Result<()>> {
+        if let Some(deflate_writer) = &mut self.deflate_writer {
+            self.output.append(deflate_writer.get_mut());
+        }
+        while self.output.len() > limit {
+            let size = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.output))?;
+            if size == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.output.drain(..size);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncGzipEncoder<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx, MAX_BUFFERED))?;
+        let deflate_writer = this
+            .deflate_writer
+            .as_mut()
+            .ok_or_else(|| io::Error::other("write after shutdown"))?;
+        Poll::Ready(deflate_writer.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(deflate_writer) = &mut this.deflate_writer {
+            deflate_writer.flush()?;
+        }
+        ready!(this.poll_drain(cx, 0))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(deflate_writer) = this.deflate_writer.take() {
+            let (data, tracker) = deflate_writer.finish().map_err(io::Error::other)?;
+            this.output.extend_from_slice(&data);
+            MemberFooter::new(tracker)
+                .write_to(&mut this.output)
+                .map_err(io::Error::other)?;
+        }
+        ready!(this.poll_drain(cx, 0))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,436 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+
+use anyhow::{ensure, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::bit_writer::BitWriter;
+use crate::deflate::DeflateWriter;
+use crate::gzip::{CompressionMethod, MemberFooter, MemberHeader, MemberReader};
+use crate::tracking_writer::TrackingWriter;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// See the SAM/BAM format specification, section 4.1.
+const BGZF_SUBFIELD: [u8; 2] = *b"BC";
+const BLOCK_HEADER_SIZE: usize = 18;
+const BLOCK_DATA_SIZE: usize = 0xff00;
+const MAX_BLOCK_SIZE: usize = 1 << 16;
+
+/// The empty block terminating every BGZF file.
+pub const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Packs a compressed block offset and an offset inside the uncompressed
+/// block into a BGZF virtual offset.
+pub fn virtual_offset(block_offset: u64, data_offset: u16) -> u64 {
+    (block_offset << 16) | data_offset as u64
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Contents of a `.gzi` file: starting offsets (compressed, uncompressed) of
+/// every block but the first one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GziIndex {
+    entries: Vec<(u64, u64)>,
+}
+
+impl GziIndex {
+    pub fn entries(&self) -> &[(u64, u64)] {
+        &self.entries
+    }
+
+    /// Returns the start of the block containing the uncompressed `offset`.
+    pub fn block_for(&self, offset: u64) -> (u64, u64) {
+        let pos = self.entries.partition_point(|entry| entry.1 <= offset);
+        pos.checked_sub(1).map_or((0, 0), |pos| self.entries[pos])
+    }
+
+    /// Scans the BGZF file block by block, without decompressing it.
+    pub fn build<R: BufRead>(mut input: R) -> Result<Self> {
+        let mut entries = Vec::new();
+        let (mut compressed, mut uncompressed) = (0, 0);
+        while !input.fill_buf()?.is_empty() {
+            if compressed != 0 {
+                entries.push((compressed, uncompressed));
+            }
+            let (header, deflate_reader) = MemberReader::new(&mut input).into_deflate_reader()?;
+            let block_size = block_size(&header)?;
+            let header_size = header_size(&header);
+            let (mut rdr, _) = deflate_reader.into_inners();
+            let data_size = block_size
+                .checked_sub(header_size + 8)
+                .context("invalid BGZF block size")?;
+            io::copy(&mut rdr.by_ref().take(data_size as u64), &mut io::sink())?;
+            let footer = MemberReader::read_footer(&mut rdr)?;
+            compressed += block_size as u64;
+            uncompressed += footer.data_size as u64;
+        }
+        if entries.last() == Some(&(compressed - BGZF_EOF.len() as u64, uncompressed)) {
+            entries.pop();
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn write_to<W: Write>(&self, mut output: W) -> Result<()> {
+        output.write_u64::<LittleEndian>(self.entries.len() as u64)?;
+        for (compressed, uncompressed) in &self.entries {
+            output.write_u64::<LittleEndian>(*compressed)?;
+            output.write_u64::<LittleEndian>(*uncompressed)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut input: R) -> Result<Self> {
+        let count = input.read_u64::<LittleEndian>()?;
+        let mut entries = Vec::new();
+        for _i in 0..count {
+            let compressed = input.read_u64::<LittleEndian>()?;
+            let uncompressed = input.read_u64::<LittleEndian>()?;
+            entries.push((compressed, uncompressed));
+        }
+        ensure!(
+            entries.windows(2).all(|pair| pair[0].0 < pair[1].0),
+            "gzi entries are not sorted"
+        );
+        Ok(Self { entries })
+    }
+}
+
+fn block_size(header: &MemberHeader) -> Result<usize> {
+    ensure!(
+        header.compression_method == CompressionMethod::Deflate,
+        "unsupported compression method"
+    );
+    let subfield = header
+        .extra_subfield(BGZF_SUBFIELD)
+        .filter(|data| data.len() == 2)
+        .context("not a BGZF block")?;
+    Ok(u16::from_le_bytes([subfield[0], subfield[1]]) as usize + 1)
+}
+
+fn header_size(header: &MemberHeader) -> usize {
+    let strings = [&header.name, &header.comment]
+        .into_iter()
+        .flatten()
+        .map(|field| field.len() + 1)
+        .sum::<usize>();
+    let crc = if header.flags().has_crc() { 2 } else { 0 };
+    12 + header.extra.as_ref().map_or(0, Vec::len) + strings + crc
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    level: u32,
+    buffer: Vec<u8>,
+    compressed_offset: u64,
+    uncompressed_offset: u64,
+    index: GziIndex,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    /// Compresses the blocks with the default level 6.
+    pub fn new(inner: W) -> Self {
+        Self::with_level(inner, 6).expect("the default level is valid")
+    }
+
+    /// Compresses the blocks with the given level, see
+    /// [`DeflateWriter::with_level`].
+    pub fn with_level(inner: W, level: u32) -> Result<Self> {
+        // Checks the level up front rather than on the first block.
+        DeflateWriter::with_level(BitWriter::new(io::sink()), level)?;
+        Ok(Self {
+            inner,
+            level,
+            buffer: Vec::with_capacity(BLOCK_DATA_SIZE),
+            compressed_offset: 0,
+            uncompressed_offset: 0,
+            index: GziIndex::default(),
+        })
+    }
+
+    /// Virtual offset of the next byte to be written.
+    pub fn virtual_position(&self) -> u64 {
+        virtual_offset(self.compressed_offset, self.buffer.len() as u16)
+    }
+
+    /// Index of the blocks written so far.
+    pub fn index(&self) -> &GziIndex {
+        &self.index
+    }
+
+    /// Writes the buffered data as a separate block, if there is any.
+    pub fn flush_block(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        if self.compressed_offset != 0 {
+            self.index
+                .entries
+                .push((self.compressed_offset, self.uncompressed_offset));
+        }
+
+        let (mut data, mut tracker) = deflate_block(&self.buffer, self.level)?;
+        if BLOCK_HEADER_SIZE + data.len() + 8 > MAX_BLOCK_SIZE {
+            // Incompressible data grows, and is stored instead like htslib
+            // does, which always fits.
+            (data, tracker) = deflate_block(&self.buffer, 0)?;
+        }
+        let block_size = BLOCK_HEADER_SIZE + data.len() + 8;
+        ensure!(block_size <= MAX_BLOCK_SIZE, "BGZF block is too large");
+
+        let mut header = MemberHeader::default();
+        header.push_extra_subfield(BGZF_SUBFIELD, &((block_size - 1) as u16).to_le_bytes())?;
+        header.write_to(&mut self.inner)?;
+        self.inner.write_all(&data)?;
+        MemberFooter::new(tracker).write_to(&mut self.inner)?;
+
+        self.compressed_offset += block_size as u64;
+        self.uncompressed_offset += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Writes the remaining data and the EOF marker block.
+    pub fn finish(mut self) -> Result<(W, GziIndex)> {
+        self.flush_block()?;
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()?;
+        Ok((self.inner, self.index))
+    }
+}
+
+fn deflate_block(data: &[u8], level: u32) -> Result<(Vec<u8>, TrackingWriter<io::Sink>)> {
+    let mut deflate_writer = DeflateWriter::with_level(BitWriter::new(Vec::new()), level)?;
+    deflate_writer.write_all(data)?;
+    deflate_writer.finish()
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = buf.len().min(BLOCK_DATA_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..size]);
+        if self.buffer.len() == BLOCK_DATA_SIZE {
+            self.flush_block().map_err(io::Error::other)?;
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct BgzfReader<R> {
+    inner: BufReader<R>,
+    block: Vec<u8>,
+    block_pos: usize,
+    block_offset: u64,
+    next_block_offset: u64,
+}
+
+impl<R: Read> BgzfReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+            block: Vec::new(),
+            block_pos: 0,
+            block_offset: 0,
+            next_block_offset: 0,
+        }
+    }
+
+    /// Virtual offset of the next byte to be read.
+    pub fn virtual_position(&self) -> u64 {
+        if self.block_pos == self.block.len() {
+            virtual_offset(self.next_block_offset, 0)
+        } else {
+            virtual_offset(self.block_offset, self.block_pos as u16)
+        }
+    }
+
+    /// Reads the next block, returns false at the end of the file.
+    fn read_block(&mut self) -> Result<bool> {
+        if self.inner.fill_buf()?.is_empty() {
+            return Ok(false);
+        }
+        let (header, mut deflate_reader) =
+            MemberReader::new(&mut self.inner).into_deflate_reader()?;
+        let block_size = block_size(&header)?;
+
+        self.block.clear();
+        self.block_pos = 0;
+        while let Some(block) = deflate_reader.next_block() {
+            self.block.extend_from_slice(&block?);
+        }
+        let (mut rdr, writer) = deflate_reader.into_inners();
+        MemberReader::read_footer(&mut rdr)?.check(writer)?;
+
+        self.block_offset = self.next_block_offset;
+        self.next_block_offset += block_size as u64;
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> BgzfReader<R> {
+    pub fn seek_virtual(&mut self, offset: u64) -> Result<()> {
+        let (block_offset, data_offset) = (offset >> 16, (offset & 0xffff) as usize);
+        self.inner.seek(SeekFrom::Start(block_offset))?;
+        self.next_block_offset = block_offset;
+        self.block.clear();
+        self.block_pos = 0;
+        if self.read_block()? {
+            ensure!(data_offset <= self.block.len(), "invalid virtual offset");
+            self.block_pos = data_offset;
+        } else {
+            ensure!(data_offset == 0, "invalid virtual offset");
+        }
+        Ok(())
+    }
+
+    /// Moves to the uncompressed `offset` with the help of a `.gzi` index.
+    pub fn seek_uncompressed(&mut self, index: &GziIndex, offset: u64) -> Result<()> {
+        let (compressed, uncompressed) = index.block_for(offset);
+        self.seek_virtual(virtual_offset(compressed, 0))?;
+        io::copy(
+            &mut self.by_ref().take(offset - uncompressed),
+            &mut io::sink(),
+        )?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.block_pos == self.block.len() {
+            let has_block = self
+                .read_block()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            if !has_block {
+                return Ok(0);
+            }
+        }
+        let available = &self.block[self.block_pos..];
+        let size = available.len().min(buf.len());
+        buf[..size].copy_from_slice(&available[..size]);
+        self.block_pos += size;
+        Ok(size)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::ZOPFLI_LEVEL;
+    use crate::test_util::{random_bytes, text};
+    use std::io::Cursor;
+
+    fn sample_data() -> Vec<u8> {
+        (0..200_000_u64).map(|i| (i * i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn write_and_read() -> Result<()> {
+        let data = sample_data();
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(&data[..100_000])?;
+        let position = writer.virtual_position();
+        writer.write_all(&data[100_000..])?;
+        let (compressed, index) = writer.finish()?;
+
+        assert!(compressed.ends_with(&BGZF_EOF));
+        assert_eq!(index.entries().len(), 3);
+        assert_eq!(GziIndex::build(compressed.as_slice())?, index);
+
+        let mut decompressed = Vec::new();
+        crate::decompress(compressed.as_slice(), &mut decompressed)?;
+        assert_eq!(decompressed, data);
+
+        let mut reader = BgzfReader::new(Cursor::new(&compressed));
+        let mut decompressed = Vec::new();
+        reader.read_to_end(&mut decompressed)?;
+        assert_eq!(decompressed, data);
+
+        reader.seek_virtual(position)?;
+        assert_eq!(reader.virtual_position(), position);
+        let mut buf = [0; 1000];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(buf, data[100_000..101_000]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn gzi_index() -> Result<()> {
+        let data = sample_data();
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(&data)?;
+        let (compressed, index) = writer.finish()?;
+
+        let mut buf = Vec::new();
+        index.write_to(&mut buf)?;
+        assert_eq!(buf.len(), 8 + 16 * index.entries().len());
+        let index = GziIndex::read_from(buf.as_slice())?;
+
+        let mut reader = BgzfReader::new(Cursor::new(&compressed));
+        for offset in [150_000, 0, 65_280, 199_999] {
+            reader.seek_uncompressed(&index, offset)?;
+            let mut byte = [0];
+            reader.read_exact(&mut byte)?;
+            assert_eq!(byte[0], data[offset as usize]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn levels() -> Result<()> {
+        let text = &text()[..BLOCK_DATA_SIZE];
+        let random = random_bytes(3 * BLOCK_DATA_SIZE, 1);
+        for level in [0, 1, 6, ZOPFLI_LEVEL] {
+            let mut writer = BgzfWriter::with_level(Vec::new(), level)?;
+            writer.write_all(text)?;
+            let text_size = writer.virtual_position() >> 16;
+            // Random data goes in stored blocks rather than in too large
+            // compressed ones.
+            writer.write_all(&random)?;
+            let (compressed, index) = writer.finish()?;
+            assert_eq!(index.entries().len(), 3, "level {}", level);
+            if level == 0 {
+                assert!(text_size > text.len() as u64);
+            } else {
+                assert!(text_size < text.len() as u64 / 2, "level {}", level);
+            }
+
+            let mut reader = BgzfReader::new(Cursor::new(&compressed));
+            let mut decompressed = Vec::new();
+            reader.read_to_end(&mut decompressed)?;
+            assert_eq!(decompressed, [text, &random].concat(), "level {}", level);
+        }
+        assert!(BgzfWriter::with_level(Vec::new(), 10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_block_size() -> Result<()> {
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(&sample_data())?;
+        let (mut compressed, _) = writer.finish()?;
+        // BSIZE follows the 12 bytes of the fixed header and the 4 bytes of
+        // the subfield header.
+        compressed[16..18].copy_from_slice(&10_u16.to_le_bytes());
+        let err = GziIndex::build(compressed.as_slice()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid BGZF block size");
+        Ok(())
+    }
+}
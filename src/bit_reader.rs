@@ -1,7 +1,7 @@
 #![forbid(unsafe_code)]
 
 use byteorder::ReadBytesExt;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Read};
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -14,7 +14,7 @@ pub struct BitSequence {
 impl BitSequence {
     pub fn new(bits: u16, len: u8) -> Self {
         BitSequence {
-            bits: bits & ((1 << len) - 1),
+            bits: bits & ((1_u32 << len) - 1) as u16,
             len,
         }
     }
@@ -83,6 +83,97 @@ impl<T: BufRead> BitReader<T> {
     }
 }
 
+impl<T: BufRead> BitReader<CountingReader<T>> {
+    /// Position of the next unread bit, counted from the start of the stream.
+    pub fn position(&self) -> u64 {
+        self.stream.count() * 8 - self.len as u64
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A reader that keeps track of how many bytes were consumed from it.
+pub struct CountingReader<T> {
+    inner: T,
+    count: u64,
+    /// Bytes taken from the inner reader by [`CountingReader::peek`] but not
+    /// consumed yet, which come first.
+    lookahead: Vec<u8>,
+}
+
+impl<T> CountingReader<T> {
+    pub fn new(inner: T) -> Self {
+        Self::with_offset(inner, 0)
+    }
+
+    /// Creates a reader whose count starts from `offset`, e.g. after a seek.
+    pub fn with_offset(inner: T, offset: u64) -> Self {
+        Self {
+            inner,
+            count: offset,
+            lookahead: Vec::new(),
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<T: BufRead> CountingReader<T> {
+    /// Returns at least `len` bytes of the remaining input, or all of it if
+    /// it is shorter, without consuming them. Unlike [`BufRead::fill_buf`],
+    /// this doesn't stop at the end of the buffer of the inner reader.
+    pub fn peek(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.lookahead.is_empty() && self.inner.fill_buf()?.len() >= len {
+            return self.inner.fill_buf();
+        }
+        while self.lookahead.len() < len {
+            let buf = self.inner.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let size = buf.len().min(len - self.lookahead.len());
+            self.lookahead.extend_from_slice(&buf[..size]);
+            self.inner.consume(size);
+        }
+        Ok(&self.lookahead)
+    }
+}
+
+impl<T: Read> Read for CountingReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = if self.lookahead.is_empty() {
+            self.inner.read(buf)?
+        } else {
+            let size = self.lookahead.as_slice().read(buf)?;
+            self.lookahead.drain(..size);
+            size
+        };
+        self.count += size as u64;
+        Ok(size)
+    }
+}
+
+impl<T: BufRead> BufRead for CountingReader<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.lookahead.is_empty() {
+            self.inner.fill_buf()
+        } else {
+            Ok(&self.lookahead)
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.count += amt as u64;
+        if self.lookahead.is_empty() {
+            self.inner.consume(amt)
+        } else {
+            self.lookahead.drain(..amt);
+        }
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 #[cfg(test)]
@@ -116,4 +207,39 @@ mod tests {
         assert_eq!(reader.read_bits(8)?, BitSequence::new(0b10101111, 8));
         Ok(())
     }
+
+    #[test]
+    fn position() -> io::Result<()> {
+        let data: &[u8] = &[0b01100011, 0b11011011, 0b10101111];
+        let mut reader = BitReader::new(CountingReader::new(data));
+        assert_eq!(reader.position(), 0);
+        reader.read_bits(3)?;
+        assert_eq!(reader.position(), 3);
+        reader.read_bits(7)?;
+        assert_eq!(reader.position(), 10);
+        reader.borrow_reader_from_boundary().read_u8()?;
+        assert_eq!(reader.position(), 24);
+        Ok(())
+    }
+
+    #[test]
+    fn peek_across_buffers() -> io::Result<()> {
+        let data: &[u8] = &[1, 2, 3, 4, 5];
+        let mut reader = CountingReader::new(io::BufReader::with_capacity(2, data));
+        assert_eq!(reader.fill_buf()?, [1, 2]);
+        reader.consume(1);
+        assert_eq!(reader.fill_buf()?, [2]);
+        assert_eq!(reader.peek(3)?, [2, 3, 4]);
+        assert_eq!(reader.count(), 1);
+        assert_eq!(reader.read_u8()?, 2);
+        assert_eq!(reader.fill_buf()?, [3, 4]);
+        reader.consume(2);
+        assert_eq!(reader.peek(2)?, [5]);
+        assert_eq!(reader.count(), 4);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest)?;
+        assert_eq!(rest, [5]);
+        assert_eq!(reader.count(), 5);
+        Ok(())
+    }
 }
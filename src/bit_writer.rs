@@ -0,0 +1,111 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, Write};
+
+use crate::bit_reader::BitSequence;
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct BitWriter<T> {
+    stream: T,
+    buffer: u8,
+    len: u8,
+}
+
+impl<T: Write> BitWriter<T> {
+    pub fn new(stream: T) -> Self {
+        Self {
+            stream,
+            buffer: 0,
+            len: 0,
+        }
+    }
+
+    pub fn write_bits(&mut self, seq: BitSequence) -> io::Result<()> {
+        let mut bits = seq.bits() as u32;
+        let mut len = seq.len();
+        while len > 0 {
+            let chunk = len.min(8 - self.len);
+            self.buffer |= ((bits & ((1 << chunk) - 1)) as u8) << self.len;
+            self.len += chunk;
+            bits >>= chunk;
+            len -= chunk;
+            if self.len == 8 {
+                self.stream.write_all(&[self.buffer])?;
+                self.buffer = 0;
+                self.len = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pads the current byte with zero bits and gives access to the stream.
+    pub fn borrow_writer_from_boundary(&mut self) -> io::Result<&mut T> {
+        if self.len > 0 {
+            self.stream.write_all(&[self.buffer])?;
+            self.buffer = 0;
+            self.len = 0;
+        }
+        Ok(&mut self.stream)
+    }
+
+    /// Number of bits written to the current byte.
+    pub fn pending_bits(&self) -> u8 {
+        self.len
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    /// Gives access to the stream, without the bits of the current byte.
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.stream
+    }
+
+    pub fn finish(mut self) -> io::Result<T> {
+        self.borrow_writer_from_boundary()?;
+        Ok(self.stream)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_reader::BitReader;
+
+    #[test]
+    fn write_bits() -> io::Result<()> {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bits(BitSequence::new(0b1, 1))?;
+        writer.write_bits(BitSequence::new(0b01, 2))?;
+        writer.write_bits(BitSequence::new(0b100, 3))?;
+        writer.write_bits(BitSequence::new(0b1101, 4))?;
+        writer.write_bits(BitSequence::new(0b10110, 5))?;
+        writer.write_bits(BitSequence::new(0b01011111, 8))?;
+        assert_eq!(writer.finish()?, [0b01100011, 0b11011011, 0b00101111]);
+        Ok(())
+    }
+
+    #[test]
+    fn borrow_writer_from_boundary() -> io::Result<()> {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bits(BitSequence::new(0b011, 3))?;
+        writer
+            .borrow_writer_from_boundary()?
+            .write_all(&[0b11011011])?;
+        writer.write_bits(BitSequence::new(0b1010111110101, 13))?;
+        let data = writer.finish()?;
+        assert_eq!(data, [0b011, 0b11011011, 0b11110101, 0b10101]);
+
+        let mut reader = BitReader::new(data.as_slice());
+        assert_eq!(reader.read_bits(3)?, BitSequence::new(0b011, 3));
+        reader.borrow_reader_from_boundary();
+        assert_eq!(reader.read_bits(8)?, BitSequence::new(0b11011011, 8));
+        assert_eq!(reader.read_bits(13)?, BitSequence::new(0b1010111110101, 13));
+        Ok(())
+    }
+}
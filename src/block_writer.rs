@@ -0,0 +1,584 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::bit_reader::BitSequence;
+use crate::bit_writer::BitWriter;
+use crate::deflate::{CompressionType, Token};
+use crate::huffman_coding::{
+    encoding_codes, fixed_code_lengths, lengths_from_frequencies, CODE_LENGTH_ORDER, DISTANCE_BASE,
+    DISTANCE_EXTRA_BITS, LENGTH_BASE, LENGTH_EXTRA_BITS,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub const END_OF_BLOCK: usize = 256;
+pub const LITLEN_SYMBOLS: usize = 286;
+pub const DISTANCE_SYMBOLS: usize = 30;
+
+pub const MAX_STORED_BLOCK_SIZE: usize = 65535;
+
+/// Tokens of the longest block.
+pub const MAX_BLOCK_TOKENS: usize = 1 << 16;
+
+/// Number of tokens in which [`BlockSplitter`] looks for a change.
+const SPLIT_CHECK_TOKENS: usize = 1024;
+
+/// Bits that separate codes must save to end a block, roughly the size of a
+/// dynamic block header.
+const SPLIT_THRESHOLD: f64 = 800.0;
+
+const MAX_BITS: u8 = 15;
+const MAX_CODE_LENGTH_BITS: u8 = 7;
+
+/// Literal/length symbol of a match length and its extra bits.
+pub fn length_symbol(length: u32) -> (usize, BitSequence) {
+    let index = LENGTH_BASE.partition_point(|base| *base as u32 <= length) - 1;
+    let extra = length - LENGTH_BASE[index] as u32;
+    (
+        257 + index,
+        BitSequence::new(extra as u16, LENGTH_EXTRA_BITS[index]),
+    )
+}
+
+/// Distance symbol of a match distance and its extra bits.
+pub fn distance_symbol(distance: u32) -> (usize, BitSequence) {
+    let index = DISTANCE_BASE.partition_point(|base| *base as u32 <= distance) - 1;
+    let extra = distance - DISTANCE_BASE[index] as u32;
+    (
+        index,
+        BitSequence::new(extra as u16, DISTANCE_EXTRA_BITS[index]),
+    )
+}
+
+/// Number of extra bits of a literal/length symbol.
+pub fn litlen_extra_bits(symbol: usize) -> u8 {
+    if symbol > END_OF_BLOCK {
+        LENGTH_EXTRA_BITS[symbol - 257]
+    } else {
+        0
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// How often every symbol occurs in a block, the end of block included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymbolCounts {
+    pub litlen: Vec<u32>,
+    pub distance: Vec<u32>,
+}
+
+impl Default for SymbolCounts {
+    fn default() -> Self {
+        let mut litlen = vec![0; LITLEN_SYMBOLS];
+        litlen[END_OF_BLOCK] = 1;
+        Self {
+            litlen,
+            distance: vec![0; DISTANCE_SYMBOLS],
+        }
+    }
+}
+
+impl SymbolCounts {
+    pub fn from_tokens(tokens: &[Token]) -> Self {
+        let mut counts = Self::default();
+        for token in tokens {
+            counts.add(token);
+        }
+        counts
+    }
+
+    /// Adds the counts of `other`, except for its end of block.
+    pub fn merge(&mut self, other: &SymbolCounts) {
+        for (count, other) in self.litlen.iter_mut().zip(&other.litlen) {
+            *count += other;
+        }
+        for (count, other) in self.distance.iter_mut().zip(&other.distance) {
+            *count += other;
+        }
+        self.litlen[END_OF_BLOCK] -= 1;
+    }
+
+    pub fn add(&mut self, token: &Token) {
+        match *token {
+            Token::Literal(byte) => self.litlen[byte as usize] += 1,
+            Token::Match { length, distance } => {
+                self.litlen[length_symbol(length).0] += 1;
+                self.distance[distance_symbol(distance).0] += 1;
+            }
+            Token::BlockBoundary(_) => {}
+        }
+    }
+
+    /// Size in bits of the symbols coded with the given code lengths,
+    /// including extra bits.
+    pub fn data_size(&self, litlen_lengths: &[u8], distance_lengths: &[u8]) -> u64 {
+        let litlen = (0..)
+            .zip(&self.litlen)
+            .zip(litlen_lengths)
+            .map(|((symbol, count), len)| {
+                *count as u64 * (*len + litlen_extra_bits(symbol)) as u64
+            });
+        let distance = self
+            .distance
+            .iter()
+            .zip(distance_lengths)
+            .zip(DISTANCE_EXTRA_BITS)
+            .map(|((count, len), extra_bits)| *count as u64 * (*len + extra_bits) as u64);
+        litlen.sum::<u64>() + distance.sum::<u64>()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Huffman codes of a dynamic block along with their encoding in the block
+/// header, see RFC 1951, section 3.2.7.
+pub struct DynamicHeader {
+    pub litlen_lengths: Vec<u8>,
+    pub distance_lengths: Vec<u8>,
+    /// Code length symbols with their extra bits.
+    code_length_symbols: Vec<(usize, BitSequence)>,
+    code_length_lengths: Vec<u8>,
+    /// Number of code length code lengths written.
+    code_length_count: usize,
+}
+
+impl DynamicHeader {
+    pub fn new(counts: &SymbolCounts) -> Self {
+        let litlen_lengths = lengths_from_frequencies(&counts.litlen, MAX_BITS);
+        let distance_lengths = lengths_from_frequencies(&counts.distance, MAX_BITS);
+        let litlen_count = 257.max(last_used(&litlen_lengths));
+        let distance_count = 1.max(last_used(&distance_lengths));
+
+        // Both sequences of lengths are encoded as one.
+        let lengths = [
+            &litlen_lengths[..litlen_count],
+            &distance_lengths[..distance_count],
+        ]
+        .concat();
+        let code_length_symbols = run_length_encode(&lengths);
+        let mut frequencies = [0; 19];
+        for (symbol, _) in &code_length_symbols {
+            frequencies[*symbol] += 1;
+        }
+        let code_length_lengths = lengths_from_frequencies(&frequencies, MAX_CODE_LENGTH_BITS);
+        let code_length_count = 4.max(
+            CODE_LENGTH_ORDER
+                .iter()
+                .rposition(|symbol| code_length_lengths[*symbol] != 0)
+                .map_or(0, |index| index + 1),
+        );
+
+        Self {
+            litlen_lengths: litlen_lengths[..litlen_count].to_vec(),
+            distance_lengths: distance_lengths[..distance_count].to_vec(),
+            code_length_symbols,
+            code_length_lengths,
+            code_length_count,
+        }
+    }
+
+    /// Size of the header in bits, without the three bits every block starts
+    /// with.
+    pub fn size(&self) -> u64 {
+        let symbols: u64 = self
+            .code_length_symbols
+            .iter()
+            .map(|(symbol, extra)| (self.code_length_lengths[*symbol] + extra.len()) as u64)
+            .sum();
+        5 + 5 + 4 + 3 * self.code_length_count as u64 + symbols
+    }
+
+    fn write<T: Write>(&self, bit_writer: &mut BitWriter<T>) -> io::Result<()> {
+        let litlen_count = self.litlen_lengths.len() as u16;
+        let distance_count = self.distance_lengths.len() as u16;
+        bit_writer.write_bits(BitSequence::new(litlen_count - 257, 5))?;
+        bit_writer.write_bits(BitSequence::new(distance_count - 1, 5))?;
+        bit_writer.write_bits(BitSequence::new(self.code_length_count as u16 - 4, 4))?;
+        for symbol in &CODE_LENGTH_ORDER[..self.code_length_count] {
+            bit_writer.write_bits(BitSequence::new(
+                self.code_length_lengths[*symbol] as u16,
+                3,
+            ))?;
+        }
+        let codes = encoding_codes(&self.code_length_lengths);
+        for (symbol, extra) in &self.code_length_symbols {
+            bit_writer.write_bits(codes[*symbol])?;
+            bit_writer.write_bits(*extra)?;
+        }
+        Ok(())
+    }
+}
+
+fn last_used(lengths: &[u8]) -> usize {
+    lengths
+        .iter()
+        .rposition(|len| *len != 0)
+        .map_or(0, |index| index + 1)
+}
+
+/// Encodes code lengths with the repeat symbols 16, 17 and 18.
+fn run_length_encode(lengths: &[u8]) -> Vec<(usize, BitSequence)> {
+    let mut symbols = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let len = lengths[i];
+        let run = lengths[i..]
+            .iter()
+            .take_while(|other| **other == len)
+            .count();
+        if len == 0 && run >= 11 {
+            let count = run.min(138);
+            symbols.push((18, BitSequence::new(count as u16 - 11, 7)));
+            i += count;
+        } else if len == 0 && run >= 3 {
+            symbols.push((17, BitSequence::new(run as u16 - 3, 3)));
+            i += run;
+        } else if len != 0 && run >= 4 {
+            // The first length is written as is, the rest repeat it.
+            symbols.push((len as usize, BitSequence::new(0, 0)));
+            let count = (run - 1).min(6);
+            symbols.push((16, BitSequence::new(count as u16 - 3, 2)));
+            i += 1 + count;
+        } else {
+            symbols.push((len as usize, BitSequence::new(0, 0)));
+            i += 1;
+        }
+    }
+    symbols
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Size in bits of a dynamic block of tokens with `counts`.
+pub fn dynamic_block_size(counts: &SymbolCounts) -> u64 {
+    let header = DynamicHeader::new(counts);
+    3 + header.size() + counts.data_size(&header.litlen_lengths, &header.distance_lengths)
+}
+
+/// Size in bits of a fixed Huffman block of tokens with `counts`.
+pub fn fixed_block_size(counts: &SymbolCounts) -> u64 {
+    let (litlen_lengths, distance_lengths) = fixed_code_lengths();
+    3 + counts.data_size(&litlen_lengths, &distance_lengths)
+}
+
+/// Size in bits of `len` bytes written as stored blocks, starting
+/// `pending_bits` bits into a byte.
+pub fn stored_blocks_size(len: usize, pending_bits: u8) -> u64 {
+    let blocks = len.div_ceil(MAX_STORED_BLOCK_SIZE).max(1) as u64;
+    let first_padding = (8 - (pending_bits as u64 + 3) % 8) % 8;
+    // Later blocks start on a byte boundary.
+    blocks * (3 + 32) + first_padding + (blocks - 1) * 5 + 8 * len as u64
+}
+
+/// Number of bytes of data `tokens` stand for.
+pub fn token_bytes(tokens: &[Token]) -> usize {
+    tokens
+        .iter()
+        .map(|token| match token {
+            Token::Literal(_) => 1,
+            Token::Match { length, .. } => *length as usize,
+            Token::BlockBoundary(_) => 0,
+        })
+        .sum()
+}
+
+/// Writes `tokens`, which stand for `data`, as the block type that takes the
+/// fewest bits: stored, fixed or dynamic. Returns the type chosen.
+pub fn write_block<T: Write>(
+    bit_writer: &mut BitWriter<T>,
+    tokens: &[Token],
+    data: &[u8],
+    is_final: bool,
+) -> io::Result<CompressionType> {
+    let counts = SymbolCounts::from_tokens(tokens);
+    let header = DynamicHeader::new(&counts);
+    let dynamic_size =
+        3 + header.size() + counts.data_size(&header.litlen_lengths, &header.distance_lengths);
+    let fixed_size = fixed_block_size(&counts);
+    let stored_size = stored_blocks_size(data.len(), bit_writer.pending_bits());
+
+    if stored_size <= fixed_size.min(dynamic_size) {
+        write_stored_blocks(bit_writer, data, is_final)?;
+        Ok(CompressionType::Uncompressed)
+    } else if fixed_size <= dynamic_size {
+        let (litlen_lengths, distance_lengths) = fixed_code_lengths();
+        write_block_header(bit_writer, CompressionType::FixedTree, is_final)?;
+        write_tokens(bit_writer, tokens, &litlen_lengths, &distance_lengths)?;
+        Ok(CompressionType::FixedTree)
+    } else {
+        write_block_header(bit_writer, CompressionType::DynamicTree, is_final)?;
+        header.write(bit_writer)?;
+        write_tokens(
+            bit_writer,
+            tokens,
+            &header.litlen_lengths,
+            &header.distance_lengths,
+        )?;
+        Ok(CompressionType::DynamicTree)
+    }
+}
+
+/// Writes `data` as stored blocks, at least one.
+pub fn write_stored_blocks<T: Write>(
+    bit_writer: &mut BitWriter<T>,
+    data: &[u8],
+    is_final: bool,
+) -> io::Result<()> {
+    let mut chunks = data.chunks(MAX_STORED_BLOCK_SIZE).peekable();
+    if chunks.peek().is_none() {
+        return write_stored_block(bit_writer, &[], is_final);
+    }
+    while let Some(chunk) = chunks.next() {
+        write_stored_block(bit_writer, chunk, is_final && chunks.peek().is_none())?;
+    }
+    Ok(())
+}
+
+fn write_stored_block<T: Write>(
+    bit_writer: &mut BitWriter<T>,
+    data: &[u8],
+    is_final: bool,
+) -> io::Result<()> {
+    write_block_header(bit_writer, CompressionType::Uncompressed, is_final)?;
+    let wtr = bit_writer.borrow_writer_from_boundary()?;
+    wtr.write_u16::<LittleEndian>(data.len() as u16)?;
+    wtr.write_u16::<LittleEndian>(!(data.len() as u16))?;
+    wtr.write_all(data)
+}
+
+fn write_block_header<T: Write>(
+    bit_writer: &mut BitWriter<T>,
+    compression_type: CompressionType,
+    is_final: bool,
+) -> io::Result<()> {
+    bit_writer.write_bits(BitSequence::new(is_final as u16, 1))?;
+    bit_writer.write_bits(BitSequence::new(compression_type as u16, 2))
+}
+
+/// Writes `tokens` and the end of block with the given codes.
+fn write_tokens<T: Write>(
+    bit_writer: &mut BitWriter<T>,
+    tokens: &[Token],
+    litlen_lengths: &[u8],
+    distance_lengths: &[u8],
+) -> io::Result<()> {
+    let litlen_codes = encoding_codes(litlen_lengths);
+    let distance_codes = encoding_codes(distance_lengths);
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => bit_writer.write_bits(litlen_codes[byte as usize])?,
+            Token::Match { length, distance } => {
+                let (symbol, extra) = length_symbol(length);
+                bit_writer.write_bits(litlen_codes[symbol])?;
+                bit_writer.write_bits(extra)?;
+                let (symbol, extra) = distance_symbol(distance);
+                bit_writer.write_bits(distance_codes[symbol])?;
+                bit_writer.write_bits(extra)?;
+            }
+            Token::BlockBoundary(_) => {}
+        }
+    }
+    bit_writer.write_bits(litlen_codes[END_OF_BLOCK])
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Ends blocks where the statistics of the tokens change, so that every block
+/// gets codes that suit it, or when they grow too long.
+#[derive(Default)]
+pub struct BlockSplitter {
+    /// Counts of the tokens of the current block checked so far.
+    counts: SymbolCounts,
+    checked: usize,
+}
+
+impl BlockSplitter {
+    /// Finds the end of the block that `tokens` start with. Returns `None`
+    /// when more tokens are needed to tell.
+    pub fn find_end(&mut self, tokens: &[Token]) -> Option<usize> {
+        while let Some(chunk) = tokens.get(self.checked..self.checked + SPLIT_CHECK_TOKENS) {
+            let chunk_counts = SymbolCounts::from_tokens(chunk);
+            if self.checked > 0 && split_gain(&self.counts, &chunk_counts) > SPLIT_THRESHOLD {
+                return Some(self.checked);
+            }
+            self.counts.merge(&chunk_counts);
+            self.checked += chunk.len();
+            if self.checked >= MAX_BLOCK_TOKENS {
+                return Some(self.checked);
+            }
+        }
+        None
+    }
+
+    /// Starts the next block.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Bits saved by coding the symbols of `block` and `chunk` with separate
+/// codes rather than one, if codes matched the symbol entropy.
+fn split_gain(block: &SymbolCounts, chunk: &SymbolCounts) -> f64 {
+    fn entropy_bits(counts: &[u32]) -> f64 {
+        let total = counts.iter().map(|count| *count as f64).sum::<f64>();
+        counts
+            .iter()
+            .filter(|count| **count > 0)
+            .map(|count| *count as f64 * (total / *count as f64).log2())
+            .sum()
+    }
+    fn gain(block: &[u32], chunk: &[u32]) -> f64 {
+        let merged: Vec<u32> = block.iter().zip(chunk).map(|(a, b)| a + b).collect();
+        entropy_bits(&merged) - entropy_bits(block) - entropy_bits(chunk)
+    }
+    gain(&block.litlen, &chunk.litlen) + gain(&block.distance, &chunk.distance)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_reader::BitReader;
+    use crate::deflate::TokenReader;
+    use crate::lz77::{MatchFinder, MatchParams};
+    use crate::test_util::{random_bytes, text};
+
+    #[test]
+    fn symbols() {
+        assert_eq!(length_symbol(3), (257, BitSequence::new(0, 0)));
+        assert_eq!(length_symbol(12), (265, BitSequence::new(1, 1)));
+        assert_eq!(length_symbol(257), (284, BitSequence::new(30, 5)));
+        assert_eq!(length_symbol(258), (285, BitSequence::new(0, 0)));
+        assert_eq!(distance_symbol(1), (0, BitSequence::new(0, 0)));
+        assert_eq!(distance_symbol(6), (4, BitSequence::new(1, 1)));
+        assert_eq!(distance_symbol(32768), (29, BitSequence::new(8191, 13)));
+    }
+
+    #[test]
+    fn run_lengths() {
+        let lengths = [[0; 150].as_slice(), &[5; 9], &[0; 4], &[3, 3]].concat();
+        let symbols: Vec<_> = run_length_encode(&lengths)
+            .into_iter()
+            .map(|(symbol, extra)| (symbol, extra.bits()))
+            .collect();
+        assert_eq!(
+            symbols,
+            [
+                (18, 127),
+                (18, 1),
+                (5, 0),
+                (16, 3),
+                (5, 0),
+                (5, 0),
+                (17, 1),
+                (3, 0),
+                (3, 0)
+            ]
+        );
+    }
+
+    fn tokenize(data: &[u8]) -> Vec<Token> {
+        let mut finder = MatchFinder::new(MatchParams::default());
+        let mut tokens = Vec::new();
+        finder.push(data);
+        finder.tokenize(true, &mut tokens);
+        tokens
+    }
+
+    /// Random bytes below `modulus`.
+    fn random_symbols(len: usize, modulus: u8) -> Vec<u8> {
+        random_bytes(len, 1)
+            .into_iter()
+            .map(|byte| byte % modulus)
+            .collect()
+    }
+
+    /// Writes a block of `data` after `pending_bits` bits, checks that it
+    /// decodes to the tokens and has the estimated size.
+    fn check_block(data: &[u8], pending_bits: u8) -> anyhow::Result<CompressionType> {
+        let tokens = tokenize(data);
+        let mut bit_writer = BitWriter::new(Vec::new());
+        bit_writer.write_bits(BitSequence::new(0, pending_bits))?;
+        let compression_type = write_block(&mut bit_writer, &tokens, data, true)?;
+        let size = (bit_writer.get_ref().len() * 8) as u64 + bit_writer.pending_bits() as u64;
+
+        let counts = SymbolCounts::from_tokens(&tokens);
+        let expected_size = pending_bits as u64
+            + match compression_type {
+                CompressionType::Uncompressed => stored_blocks_size(data.len(), pending_bits),
+                CompressionType::FixedTree => fixed_block_size(&counts),
+                _ => dynamic_block_size(&counts),
+            };
+        assert_eq!(size, expected_size);
+
+        let data_written = bit_writer.finish()?;
+        let mut bit_reader = BitReader::new(data_written.as_slice());
+        bit_reader.read_bits(pending_bits)?;
+        let mut reader = TokenReader::from_bit_reader(bit_reader, 0);
+        reader.set_strict(true);
+        let mut decoded = Vec::new();
+        for token in reader {
+            match token? {
+                Token::BlockBoundary(header) => {
+                    assert_eq!(header.compression_type, compression_type)
+                }
+                token => decoded.push(token),
+            }
+        }
+        if compression_type == CompressionType::Uncompressed {
+            assert_eq!(token_bytes(&decoded), data.len());
+        } else {
+            assert_eq!(decoded, tokens);
+        }
+        Ok(compression_type)
+    }
+
+    #[test]
+    fn block_types() -> anyhow::Result<()> {
+        let text = text();
+        for pending_bits in [0, 3, 7] {
+            assert_eq!(
+                check_block(b"abcabcabc", pending_bits)?,
+                CompressionType::FixedTree
+            );
+            assert_eq!(
+                check_block(&text, pending_bits)?,
+                CompressionType::DynamicTree
+            );
+            assert_eq!(
+                check_block(&random_bytes(100_000, 1), pending_bits)?,
+                CompressionType::Uncompressed
+            );
+        }
+        assert_eq!(check_block(b"", 0)?, CompressionType::FixedTree);
+        Ok(())
+    }
+
+    #[test]
+    fn splitter() {
+        let first = tokenize(&random_symbols(20_000, 16));
+        let second: Vec<Token> = tokenize(&random_bytes(20_000, 1))
+            .into_iter()
+            .map(|token| match token {
+                Token::Literal(byte) => Token::Literal(byte.wrapping_add(128)),
+                token => token,
+            })
+            .collect();
+        let tokens = [first.as_slice(), &second].concat();
+
+        let mut splitter = BlockSplitter::default();
+        assert_eq!(splitter.find_end(&first), None);
+        let end = splitter.find_end(&tokens).unwrap();
+        assert!(end.abs_diff(first.len()) <= SPLIT_CHECK_TOKENS, "{}", end);
+
+        // Similar tokens make a single block, up to the maximum size.
+        let tokens = tokenize(&random_symbols(MAX_BLOCK_TOKENS * 2, 64));
+        splitter.reset();
+        assert_eq!(splitter.find_end(&tokens), Some(MAX_BLOCK_TOKENS));
+    }
+}
@@ -0,0 +1,210 @@
+#![forbid(unsafe_code)]
+
+////////////////////////////////////////////////////////////////////////////////
+
+// CRC-32 of gzip and zip (ISO-HDLC), reflected polynomial.
+const POLY: u32 = 0xedb88320;
+
+/// Tables for slice-by-16: `TABLES[k][b]` is the CRC of byte `b` followed by
+/// `k` zero bytes.
+static TABLES: [[u32; 256]; 16] = make_tables();
+
+const fn make_tables() -> [[u32; 256]; 16] {
+    let mut tables = [[0; 256]; 16];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        tables[0][i] = crc;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < 256 {
+        let mut k = 1;
+        while k < 16 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev >> 8) ^ tables[0][(prev & 0xff) as usize];
+            k += 1;
+        }
+        i += 1;
+    }
+    tables
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Incremental CRC-32 computation.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    // Pre- and post-conditioned: holds the complement of the current CRC.
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { state: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = self.state;
+        let mut chunks = data.chunks_exact(16);
+        for chunk in &mut chunks {
+            let word =
+                |i: usize| u32::from_le_bytes([chunk[i], chunk[i + 1], chunk[i + 2], chunk[i + 3]]);
+            let (a, b, c, d) = (word(0) ^ crc, word(4), word(8), word(12));
+            crc = TABLES[15][(a & 0xff) as usize]
+                ^ TABLES[14][((a >> 8) & 0xff) as usize]
+                ^ TABLES[13][((a >> 16) & 0xff) as usize]
+                ^ TABLES[12][(a >> 24) as usize]
+                ^ TABLES[11][(b & 0xff) as usize]
+                ^ TABLES[10][((b >> 8) & 0xff) as usize]
+                ^ TABLES[9][((b >> 16) & 0xff) as usize]
+                ^ TABLES[8][(b >> 24) as usize]
+                ^ TABLES[7][(c & 0xff) as usize]
+                ^ TABLES[6][((c >> 8) & 0xff) as usize]
+                ^ TABLES[5][((c >> 16) & 0xff) as usize]
+                ^ TABLES[4][(c >> 24) as usize]
+                ^ TABLES[3][(d & 0xff) as usize]
+                ^ TABLES[2][((d >> 8) & 0xff) as usize]
+                ^ TABLES[1][((d >> 16) & 0xff) as usize]
+                ^ TABLES[0][(d >> 24) as usize];
+        }
+        for byte in chunks.remainder() {
+            crc = (crc >> 8) ^ TABLES[0][((crc ^ *byte as u32) & 0xff) as usize];
+        }
+        self.state = crc;
+    }
+
+    pub fn finalize(&self) -> u32 {
+        !self.state
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finalize()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Polynomials modulo POLY, in the reflected bit order: the top bit is x^0.
+
+/// `X2N_TABLE[k]` is x^(2^k) modulo the polynomial.
+static X2N_TABLE: [u32; 32] = make_x2n_table();
+
+const fn multmodp(a: u32, mut b: u32) -> u32 {
+    let mut m = 1 << 31;
+    let mut product = 0;
+    loop {
+        if a & m != 0 {
+            product ^= b;
+            if a & (m - 1) == 0 {
+                return product;
+            }
+        }
+        m >>= 1;
+        b = if b & 1 != 0 { (b >> 1) ^ POLY } else { b >> 1 };
+    }
+}
+
+const fn make_x2n_table() -> [u32; 32] {
+    let mut table = [0; 32];
+    let mut p = 1 << 30; // x^1
+    table[0] = p;
+    let mut k = 1;
+    while k < 32 {
+        p = multmodp(p, p);
+        table[k] = p;
+        k += 1;
+    }
+    table
+}
+
+/// Returns x^(n * 2^k) modulo the polynomial.
+fn x2nmodp(mut n: u64, mut k: usize) -> u32 {
+    let mut p = 1 << 31; // x^0
+    while n != 0 {
+        if n & 1 != 0 {
+            p = multmodp(X2N_TABLE[k & 31], p);
+        }
+        n >>= 1;
+        k += 1;
+    }
+    p
+}
+
+/// Given the CRC-32 of two sequences and the length of the second one,
+/// returns the CRC-32 of their concatenation, as zlib's `crc32_combine`.
+pub fn crc32_combine(crc1: u32, crc2: u32, len2: u64) -> u32 {
+    // Appending len2 bytes multiplies crc1 by x^(8 * len2).
+    multmodp(x2nmodp(len2, 3), crc1) ^ crc2
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitwise_crc32(data: &[u8]) -> u32 {
+        let mut crc = !0_u32;
+        for byte in data {
+            crc ^= *byte as u32;
+            for _i in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ POLY
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn slicing_matches_bitwise() {
+        let data: Vec<u8> = (0..1000_u32).map(|i| (i * 31 % 251) as u8).collect();
+        for len in [0, 1, 15, 16, 17, 33, 999, 1000] {
+            assert_eq!(crc32(&data[..len]), bitwise_crc32(&data[..len]));
+        }
+
+        let mut crc = Crc32::new();
+        for chunk in data.chunks(7) {
+            crc.update(chunk);
+        }
+        assert_eq!(crc.finalize(), bitwise_crc32(&data));
+    }
+
+    #[test]
+    fn combine() {
+        let data: Vec<u8> = (0..5000_u32).map(|i| (i * 17 % 253) as u8).collect();
+        for split in [0, 1, 100, 4096, 5000] {
+            let (first, second) = data.split_at(split);
+            assert_eq!(
+                crc32_combine(crc32(first), crc32(second), second.len() as u64),
+                crc32(&data)
+            );
+        }
+    }
+}
@@ -1,26 +1,41 @@
 #![forbid(unsafe_code)]
 
-use std::iter::repeat;
-use std::{convert::TryFrom, io::BufRead, mem};
+use std::{
+    convert::TryFrom,
+    fmt,
+    io::{self, BufRead, Write},
+    mem,
+    sync::OnceLock,
+};
 
-use anyhow::{bail, ensure, Result};
+use anyhow::{bail, ensure, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 use crate::bit_reader::BitReader;
+use crate::bit_writer::BitWriter;
+use crate::block_writer::{
+    token_bytes, write_block, write_stored_blocks, BlockSplitter, MAX_STORED_BLOCK_SIZE,
+};
 use crate::huffman_coding::{
-    decode_litlen_distance_trees, DistanceToken, HuffmanCoding, LitLenToken,
+    decode_litlen_distance_lengths, fixed_code_lengths, DistanceToken, HuffmanCoding, LitLenToken,
+    DEFLATE64_DISTANCE_BASE, DEFLATE64_DISTANCE_EXTRA_BITS, DEFLATE64_LENGTH_BASE,
+    DEFLATE64_LENGTH_EXTRA_BITS, DISTANCE_BASE, DISTANCE_EXTRA_BITS, LENGTH_BASE,
+    LENGTH_EXTRA_BITS,
 };
-use crate::tracking_writer::TrackingWriter;
+use crate::lz77::{MatchFinder, MatchParams};
+use crate::rsyncable::RsyncSplitter;
+use crate::tracking_writer::{TrackingWriter, HISTORY_SIZE};
+use crate::zopfli::{self, ZopfliParams};
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct BlockHeader {
     pub is_final: bool,
     pub compression_type: CompressionType,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CompressionType {
     Uncompressed = 0,
     FixedTree = 1,
@@ -42,175 +57,971 @@ impl TryFrom<u16> for CompressionType {
     }
 }
 
+impl fmt::Display for CompressionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CompressionType::Uncompressed => "stored",
+            CompressionType::FixedTree => "fixed",
+            CompressionType::DynamicTree => "dynamic",
+            CompressionType::Reserved => "reserved",
+        })
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
-pub struct DeflateReader<T> {
+/// Window size and length and distance tables of a variant of deflate. The
+/// block structure and the Huffman codes are the same for all of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeflateFormat {
+    pub window_size: usize,
+    /// Base length and number of extra bits of length codes 257 to 285.
+    pub length_base: &'static [u16],
+    pub length_extra_bits: &'static [u8],
+    /// Base distance and number of extra bits of every valid distance code.
+    pub distance_base: &'static [u16],
+    pub distance_extra_bits: &'static [u8],
+}
+
+impl DeflateFormat {
+    /// Deflate of RFC 1951.
+    pub const DEFLATE: Self = Self {
+        window_size: HISTORY_SIZE,
+        length_base: &LENGTH_BASE,
+        length_extra_bits: &LENGTH_EXTRA_BITS,
+        distance_base: &DISTANCE_BASE,
+        distance_extra_bits: &DISTANCE_EXTRA_BITS,
+    };
+
+    /// Deflate64, or enhanced deflate, method 9 of ZIP: a 64 KiB window,
+    /// lengths up to 65538 with length code 285 and distance codes 30 and 31.
+    pub const DEFLATE64: Self = Self {
+        window_size: 1 << 16,
+        length_base: &DEFLATE64_LENGTH_BASE,
+        length_extra_bits: &DEFLATE64_LENGTH_EXTRA_BITS,
+        distance_base: &DEFLATE64_DISTANCE_BASE,
+        distance_extra_bits: &DEFLATE64_DISTANCE_EXTRA_BITS,
+    };
+}
+
+impl Default for DeflateFormat {
+    fn default() -> Self {
+        Self::DEFLATE
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// An LZ77 token of a deflate stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    Literal(u8),
+    Match {
+        length: u32,
+        distance: u32,
+    },
+    /// Start of a new block.
+    BlockBoundary(BlockHeader),
+}
+
+/// Details of the last decoded block, collected when tracing is enabled.
+#[derive(Debug, Default)]
+pub struct BlockTrace {
+    /// Code lengths of a dynamic block, empty for other block types.
+    pub litlen_lengths: Vec<u8>,
+    pub distance_lengths: Vec<u8>,
+    /// Literals and matches of the block, empty unless token tracing is
+    /// enabled.
+    pub tokens: Vec<Token>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+enum BlockState {
+    /// The next thing in the stream is a block header.
+    Idle,
+    Stored {
+        remaining: u16,
+    },
+    Fixed,
+    Dynamic {
+        litlen_coding: HuffmanCoding<LitLenToken>,
+        distance_coding: HuffmanCoding<DistanceToken>,
+    },
+}
+
+/// Decodes a deflate stream into LZ77 tokens without producing the output.
+pub struct TokenReader<T> {
     bit_reader: BitReader<T>,
-    tracker: TrackingWriter<Vec<u8>>,
+    state: BlockState,
     is_avail: bool,
+    history: usize,
+    litlen_lengths: Vec<u8>,
+    distance_lengths: Vec<u8>,
+    strict: bool,
+    format: DeflateFormat,
 }
 
-impl<T: BufRead> DeflateReader<T> {
-    pub fn new(bit_reader: BitReader<T>) -> Self {
+impl<T: BufRead> TokenReader<T> {
+    /// Reads a raw deflate stream (RFC 1951).
+    pub fn new(input: T) -> Self {
+        Self::from_bit_reader(BitReader::new(input), 0)
+    }
+
+    /// Reads a stream from a block boundary, with `history` bytes of already
+    /// produced output available for back references.
+    pub fn from_bit_reader(bit_reader: BitReader<T>, history: usize) -> Self {
+        Self::with_format(bit_reader, history, DeflateFormat::DEFLATE)
+    }
+
+    /// Like [`TokenReader::from_bit_reader`], for a stream of another
+    /// variant of deflate.
+    pub fn with_format(bit_reader: BitReader<T>, history: usize, format: DeflateFormat) -> Self {
         Self {
             bit_reader,
-            tracker: TrackingWriter::new(Vec::new()),
+            state: BlockState::Idle,
             is_avail: true,
+            history: history.min(format.window_size),
+            litlen_lengths: Vec::new(),
+            distance_lengths: Vec::new(),
+            strict: false,
+            format,
         }
     }
 
-    pub fn next_block(&mut self) -> Option<Result<Vec<u8>>> {
-        if self.is_avail {
-            Some(self.read_block())
-        } else {
-            None
-        }
+    /// Enables rejecting malformed Huffman codes of dynamic blocks that
+    /// decode fine otherwise: over-subscribed or incomplete codes, too many
+    /// symbols and a missing end-of-block code.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Whether the final block of the stream has been read to the end.
+    pub fn is_finished(&self) -> bool {
+        !self.is_avail && matches!(self.state, BlockState::Idle)
     }
 
-    fn read_block(&mut self) -> Result<Vec<u8>> {
-        self.is_avail = self.bit_reader.read_bits(1)?.bits() == 0;
-        let compression_type: CompressionType = self.bit_reader.read_bits(2)?.bits().try_into()?;
-        match compression_type {
+    /// Code lengths of the literal/length and distance trees of the current
+    /// block, empty unless it is a dynamic one.
+    pub fn code_lengths(&self) -> (&[u8], &[u8]) {
+        (&self.litlen_lengths, &self.distance_lengths)
+    }
+
+    /// Reads the header of the next block, returns `None` after the final one.
+    pub fn read_block_header(&mut self) -> Result<Option<BlockHeader>> {
+        ensure!(
+            matches!(self.state, BlockState::Idle),
+            "previous block is not finished"
+        );
+        if !self.is_avail {
+            return Ok(None);
+        }
+        let header = BlockHeader {
+            is_final: self.bit_reader.read_bits(1)?.bits() == 1,
+            compression_type: self.bit_reader.read_bits(2)?.bits().try_into()?,
+        };
+        self.is_avail = !header.is_final;
+        self.litlen_lengths.clear();
+        self.distance_lengths.clear();
+        self.state = match header.compression_type {
             CompressionType::Uncompressed => {
                 let rdr = self.bit_reader.borrow_reader_from_boundary();
                 let len = rdr.read_u16::<LittleEndian>()?;
                 let not_len = rdr.read_u16::<LittleEndian>()?;
                 ensure!(len == !not_len, "nlen check failed");
-                for _i in 0..len {
-                    self.tracker.write_u8(rdr.read_u8()?)?;
+                BlockState::Stored { remaining: len }
+            }
+            CompressionType::FixedTree => BlockState::Fixed,
+            CompressionType::DynamicTree => {
+                let (litlen_lengths, distance_lengths) = decode_litlen_distance_lengths(
+                    &mut self.bit_reader,
+                    self.format.distance_base.len(),
+                    self.strict,
+                )?;
+                let state = BlockState::Dynamic {
+                    litlen_coding: HuffmanCoding::from_lengths(&litlen_lengths)?,
+                    distance_coding: HuffmanCoding::from_lengths(&distance_lengths)?,
+                };
+                self.litlen_lengths = litlen_lengths;
+                self.distance_lengths = distance_lengths;
+                state
+            }
+            CompressionType::Reserved => {
+                bail!("unsupported block type")
+            }
+        };
+        Ok(Some(header))
+    }
+
+    /// Reads the next literal or match of the current block, returns `None`
+    /// at the end of the block. Only the input is consumed when it fails, so
+    /// that the token can be read again from where it starts.
+    pub fn read_token(&mut self) -> Result<Option<Token>> {
+        let token = match &mut self.state {
+            BlockState::Idle => None,
+            BlockState::Stored { remaining: 0 } => None,
+            BlockState::Stored { remaining } => {
+                let rdr = self.bit_reader.borrow_reader_from_boundary();
+                let byte = rdr.read_u8()?;
+                *remaining -= 1;
+                Some(Token::Literal(byte))
+            }
+            BlockState::Fixed => {
+                let (litlen_coding, distance_coding) = fixed_codings();
+                read_huffman_token(
+                    &mut self.bit_reader,
+                    &self.format,
+                    litlen_coding,
+                    distance_coding,
+                )?
+            }
+            BlockState::Dynamic {
+                litlen_coding,
+                distance_coding,
+            } => read_huffman_token(
+                &mut self.bit_reader,
+                &self.format,
+                litlen_coding,
+                distance_coding,
+            )?,
+        };
+
+        match token {
+            None => self.state = BlockState::Idle,
+            Some(Token::Literal(_)) => {
+                self.history = (self.history + 1).min(self.format.window_size)
+            }
+            Some(Token::Match { length, distance }) => {
+                ensure!(
+                    distance as usize <= self.history,
+                    "distance is too far back"
+                );
+                self.history = (self.history + length as usize).min(self.format.window_size);
+            }
+            Some(Token::BlockBoundary(_)) => unreachable!(),
+        }
+        Ok(token)
+    }
+
+    pub fn bit_reader(&self) -> &BitReader<T> {
+        &self.bit_reader
+    }
+
+    pub fn into_inner(self) -> T {
+        self.bit_reader.into_inner()
+    }
+
+    /// Splits the reader into its input and the state of the decoding, which
+    /// [`TokenReader::from_parts`] continues with the input read from the
+    /// same position, e.g. once more of it is available.
+    pub fn into_parts(self) -> (BitReader<T>, TokenReaderState) {
+        let state = TokenReaderState {
+            state: self.state,
+            is_avail: self.is_avail,
+            history: self.history,
+            litlen_lengths: self.litlen_lengths,
+            distance_lengths: self.distance_lengths,
+            strict: self.strict,
+            format: self.format,
+        };
+        (self.bit_reader, state)
+    }
+
+    pub fn from_parts(bit_reader: BitReader<T>, state: TokenReaderState) -> Self {
+        Self {
+            bit_reader,
+            state: state.state,
+            is_avail: state.is_avail,
+            history: state.history,
+            litlen_lengths: state.litlen_lengths,
+            distance_lengths: state.distance_lengths,
+            strict: state.strict,
+            format: state.format,
+        }
+    }
+}
+
+/// State of a [`TokenReader`] without its input, see
+/// [`TokenReader::into_parts`].
+pub struct TokenReaderState {
+    state: BlockState,
+    is_avail: bool,
+    history: usize,
+    litlen_lengths: Vec<u8>,
+    distance_lengths: Vec<u8>,
+    strict: bool,
+    format: DeflateFormat,
+}
+
+impl<T: BufRead> Iterator for TokenReader<T> {
+    type Item = Result<Token>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = match self.read_token() {
+            Ok(None) => self
+                .read_block_header()
+                .map(|header| header.map(Token::BlockBoundary))
+                .transpose(),
+            token => token.transpose(),
+        };
+        if let Some(Err(_)) = token {
+            self.state = BlockState::Idle;
+            self.is_avail = false;
+        }
+        token
+    }
+}
+
+fn read_huffman_token<T: BufRead>(
+    bit_reader: &mut BitReader<T>,
+    format: &DeflateFormat,
+    litlen_coding: &HuffmanCoding<LitLenToken>,
+    distance_coding: &HuffmanCoding<DistanceToken>,
+) -> Result<Option<Token>> {
+    Ok(match litlen_coding.read_symbol(bit_reader)? {
+        LitLenToken::EndOfBlock => None,
+        LitLenToken::Literal(byte) => Some(Token::Literal(byte)),
+        LitLenToken::Length(index) => {
+            let index = index as usize;
+            let extra = bit_reader.read_bits(format.length_extra_bits[index])?;
+            let length = format.length_base[index] as u32 + extra.bits() as u32;
+            let DistanceToken(code) = distance_coding.read_symbol(bit_reader)?;
+            let code = code as usize;
+            ensure!(code < format.distance_base.len(), "invalid distance code");
+            let extra = bit_reader.read_bits(format.distance_extra_bits[code])?;
+            let distance = format.distance_base[code] as u32 + extra.bits() as u32;
+            Some(Token::Match { length, distance })
+        }
+        LitLenToken::Reserved => bail!("invalid literal/length code"),
+    })
+}
+
+/// Codings of fixed Huffman blocks, see RFC 1951, section 3.2.6.
+fn fixed_codings() -> &'static (HuffmanCoding<LitLenToken>, HuffmanCoding<DistanceToken>) {
+    static CODINGS: OnceLock<(HuffmanCoding<LitLenToken>, HuffmanCoding<DistanceToken>)> =
+        OnceLock::new();
+    CODINGS.get_or_init(|| {
+        let (litlen_lengths, distance_lengths) = fixed_code_lengths();
+        (
+            HuffmanCoding::from_lengths(&litlen_lengths).expect("fixed codes are valid"),
+            HuffmanCoding::from_lengths(&distance_lengths).expect("fixed codes are valid"),
+        )
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct DeflateReader<T> {
+    token_reader: TokenReader<T>,
+    tracker: TrackingWriter<Vec<u8>>,
+    last_header: Option<BlockHeader>,
+    trace: Option<BlockTrace>,
+    trace_tokens: bool,
+}
+
+impl<T: BufRead> DeflateReader<T> {
+    pub fn new(bit_reader: BitReader<T>) -> Self {
+        Self::with_format(bit_reader, DeflateFormat::DEFLATE)
+    }
+
+    /// Reads a stream of another variant of deflate, such as Deflate64.
+    pub fn with_format(bit_reader: BitReader<T>, format: DeflateFormat) -> Self {
+        Self {
+            token_reader: TokenReader::with_format(bit_reader, 0, format),
+            tracker: TrackingWriter::with_history_size(Vec::new(), format.window_size),
+            last_header: None,
+            trace: None,
+            trace_tokens: false,
+        }
+    }
+
+    /// Resumes decoding at a block boundary in the middle of a stream, with
+    /// `window` holding the output produced before that boundary.
+    pub fn resume(bit_reader: BitReader<T>, window: &[u8]) -> Self {
+        Self {
+            token_reader: TokenReader::from_bit_reader(bit_reader, window.len()),
+            tracker: TrackingWriter::with_window(Vec::new(), window),
+            last_header: None,
+            trace: None,
+            trace_tokens: false,
+        }
+    }
+
+    /// Enables collecting a [`BlockTrace`] for every decoded block.
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.trace = enabled.then(BlockTrace::default);
+    }
+
+    /// Also collects the tokens of every block in its trace, which holds a
+    /// whole block in memory.
+    pub fn set_token_tracing(&mut self, enabled: bool) {
+        self.trace_tokens = enabled;
+    }
+
+    /// See [`TokenReader::set_strict`].
+    pub fn set_strict(&mut self, strict: bool) {
+        self.token_reader.set_strict(strict);
+    }
+
+    /// Header of the last decoded block.
+    pub fn last_header(&self) -> Option<BlockHeader> {
+        self.last_header
+    }
+
+    /// Trace of the last decoded block, if tracing is enabled.
+    pub fn trace(&self) -> Option<&BlockTrace> {
+        self.trace.as_ref()
+    }
+
+    pub fn next_block(&mut self) -> Option<Result<Vec<u8>>> {
+        if self.token_reader.is_finished() {
+            None
+        } else {
+            Some(
+                self.decode_block()
+                    .map(|()| mem::take(self.tracker.get_mut_ref_inner())),
+            )
+        }
+    }
+
+    /// Like [`DeflateReader::next_block`], but only updates the window, size
+    /// and CRC32 and returns the size of the block's output.
+    pub fn skip_block(&mut self) -> Option<Result<usize>> {
+        if self.token_reader.is_finished() {
+            None
+        } else {
+            let start = self.tracker.byte_count();
+            self.tracker.set_discard(true);
+            let result = self.decode_block();
+            self.tracker.set_discard(false);
+            Some(result.map(|()| self.tracker.byte_count() - start))
+        }
+    }
+
+    fn decode_block(&mut self) -> Result<()> {
+        let header = self
+            .token_reader
+            .read_block_header()?
+            .context("no more blocks")?;
+        self.last_header = Some(header);
+        if let Some(trace) = &mut self.trace {
+            let (litlen_lengths, distance_lengths) = self.token_reader.code_lengths();
+            trace.litlen_lengths = litlen_lengths.to_vec();
+            trace.distance_lengths = distance_lengths.to_vec();
+            trace.tokens.clear();
+        }
+
+        while let Some(token) = self.token_reader.read_token()? {
+            if let Some(trace) = self.trace.as_mut().filter(|_| self.trace_tokens) {
+                trace.tokens.push(token);
+            }
+            match token {
+                Token::Literal(byte) => self.tracker.write_u8(byte)?,
+                Token::Match { length, distance } => self
+                    .tracker
+                    .write_previous(distance as usize, length as usize)?,
+                Token::BlockBoundary(_) => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the final block of the stream has been read.
+    pub fn is_finished(&self) -> bool {
+        self.token_reader.is_finished()
+    }
+
+    pub fn bit_reader(&self) -> &BitReader<T> {
+        self.token_reader.bit_reader()
+    }
+
+    pub fn tracker(&self) -> &TrackingWriter<Vec<u8>> {
+        &self.tracker
+    }
+
+    pub fn into_inners(self) -> (T, TrackingWriter<Vec<u8>>) {
+        (self.token_reader.into_inner(), self.tracker)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Input per round of optimal parsing.
+const ZOPFLI_SEGMENT_SIZE: usize = 1 << 20;
+
+/// The highest compression level, with optimal parsing.
+pub const ZOPFLI_LEVEL: u32 = 11;
+
+/// How the data is turned into blocks. Every block is stored, fixed or
+/// dynamic, whichever is smallest, except with `Stored`.
+enum Encoder {
+    /// Stored blocks of the data in `DeflateWriter::buffer`.
+    Stored,
+    /// Blocks of tokens from a match finder, ended by a splitter. The
+    /// buffer holds the data of the tokens not written yet.
+    Lz77 {
+        finder: Box<MatchFinder>,
+        tokens: Vec<Token>,
+        splitter: BlockSplitter,
+    },
+    /// Blocks of optimally parsed segments of the data in the buffer, which
+    /// starts with `history` bytes of the previous segment.
+    Zopfli {
+        params: ZopfliParams,
+        history: usize,
+    },
+}
+
+pub struct DeflateWriter<T: Write> {
+    bit_writer: BitWriter<T>,
+    buffer: Vec<u8>,
+    tracker: TrackingWriter<io::Sink>,
+    encoder: Encoder,
+    /// Whether nothing has been written since the last flush.
+    is_flushed: bool,
+    rsync_splitter: Option<RsyncSplitter>,
+}
+
+impl<T: Write> DeflateWriter<T> {
+    /// Writes the data as is, in stored blocks.
+    pub fn new(bit_writer: BitWriter<T>) -> Self {
+        Self {
+            bit_writer,
+            buffer: Vec::new(),
+            tracker: TrackingWriter::new(io::sink()),
+            encoder: Encoder::Stored,
+            is_flushed: true,
+            rsync_splitter: None,
+        }
+    }
+
+    /// Compresses the data with the given level: 0 stores it, 1 (fastest)
+    /// to 9 (best) are the levels of zlib and [`ZOPFLI_LEVEL`] parses it
+    /// optimally, which is many times slower still.
+    pub fn with_level(bit_writer: BitWriter<T>, level: u32) -> Result<Self> {
+        let encoder = match level {
+            0 => Encoder::Stored,
+            ZOPFLI_LEVEL => Encoder::Zopfli {
+                params: ZopfliParams::default(),
+                history: 0,
+            },
+            level => Encoder::Lz77 {
+                finder: Box::new(MatchFinder::new(MatchParams::for_level(level)?)),
+                tokens: Vec::new(),
+                splitter: BlockSplitter::default(),
+            },
+        };
+        Ok(Self {
+            encoder,
+            ..Self::new(bit_writer)
+        })
+    }
+
+    /// Enables a [`DeflateWriter::full_flush`] at the boundaries chosen by
+    /// an [`RsyncSplitter`], at the cost of slightly worse compression.
+    pub fn set_rsyncable(&mut self, enabled: bool) {
+        self.rsync_splitter = enabled.then(RsyncSplitter::default);
+    }
+
+    fn write_stored_block(&mut self, len: usize, is_final: bool) -> io::Result<()> {
+        write_stored_blocks(&mut self.bit_writer, &self.buffer[..len], is_final)?;
+        self.buffer.drain(..len);
+        Ok(())
+    }
+
+    /// Writes the blocks of LZ77 tokens that the splitter has found the end
+    /// of, and with `flush` all the tokens, the last block being final if
+    /// `is_final` is set.
+    fn write_token_blocks(&mut self, flush: bool, is_final: bool) -> io::Result<()> {
+        let Encoder::Lz77 {
+            tokens, splitter, ..
+        } = &mut self.encoder
+        else {
+            unreachable!()
+        };
+        loop {
+            let end = match splitter.find_end(tokens) {
+                Some(end) => end,
+                None if flush && (!tokens.is_empty() || is_final) => tokens.len(),
+                None => return Ok(()),
+            };
+            let len = token_bytes(&tokens[..end]);
+            let is_last = is_final && end == tokens.len();
+            write_block(
+                &mut self.bit_writer,
+                &tokens[..end],
+                &self.buffer[..len],
+                is_last,
+            )?;
+            tokens.drain(..end);
+            self.buffer.drain(..len);
+            splitter.reset();
+            if is_last {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Compresses the first `len` bytes of the segment in the buffer.
+    fn write_zopfli_segment(&mut self, len: usize, is_final: bool) -> io::Result<()> {
+        let Encoder::Zopfli { params, history } = &mut self.encoder else {
+            unreachable!()
+        };
+        let end = *history + len;
+        let tokens = zopfli::optimal_tokens(&self.buffer[..end], *history, params);
+        let ends = zopfli::split_blocks(&tokens, params.max_blocks);
+        let (mut start, mut data_start) = (0, *history);
+        for (index, block_end) in ends.iter().enumerate() {
+            let block = &tokens[start..*block_end];
+            let data_end = data_start + token_bytes(block);
+            let is_last = index + 1 == ends.len();
+            write_block(
+                &mut self.bit_writer,
+                block,
+                &self.buffer[data_start..data_end],
+                is_final && is_last,
+            )?;
+            start = *block_end;
+            data_start = data_end;
+        }
+
+        let new_history = end.min(HISTORY_SIZE);
+        self.buffer.drain(..end - new_history);
+        *history = new_history;
+        Ok(())
+    }
+
+    /// Adds data to compress, writing out the blocks that are complete.
+    fn write_data(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.tracker.write_all(buf)?;
+        self.buffer.extend_from_slice(buf);
+        self.is_flushed &= buf.is_empty();
+        match &mut self.encoder {
+            Encoder::Stored => {
+                while self.buffer.len() > MAX_STORED_BLOCK_SIZE {
+                    self.write_stored_block(MAX_STORED_BLOCK_SIZE, false)?;
                 }
             }
-            CompressionType::FixedTree => {
-                let litlen_lengths: Vec<u8> = repeat(8).take(144)
-                    .chain(repeat(9).take(112))
-                    .chain(repeat(7).take(24))
-                    .chain(repeat(8).take(8))
-                    .collect();
-                let distance_lengts: Vec<u8> = repeat(5).take(32).collect();
-                let litlen_coding = HuffmanCoding::<LitLenToken>::from_lengths(&litlen_lengths)?;
-                let distance_coding =
-                    HuffmanCoding::<DistanceToken>::from_lengths(&distance_lengts)?;
-
-                loop {
-                    let token = litlen_coding.read_symbol(&mut self.bit_reader)?;
-                    match token {
-                        LitLenToken::EndOfBlock => {
-                            break;
-                        }
-                        LitLenToken::Literal(byte) => {
-                            self.tracker.write_u8(byte)?;
-                        }
-                        LitLenToken::Length {
-                            base: length_base,
-                            extra_bits: length_extra_bits,
-                        } => {
-                            let length_extra_bits =
-                                self.bit_reader.read_bits(length_extra_bits)?.bits();
-                            let distance_token =
-                                distance_coding.read_symbol(&mut self.bit_reader)?;
-                            let distance_base = distance_token.base;
-                            let distance_extra_bits =
-                                self.bit_reader.read_bits(distance_token.extra_bits)?.bits();
-                            let length = match length_base {
-                                257..=264 => length_base - 254,
-                                265..=268 => 11 + (length_base - 265) * 2,
-                                269..=272 => 19 + (length_base - 269) * 4,
-                                273..=276 => 35 + (length_base - 273) * 8,
-                                277..=280 => 67 + (length_base - 277) * 16,
-                                281..=284 => 131 + (length_base - 281) * 32,
-                                285 => 258,
-                                _ => bail!("invalid length base!"),
-                            } + length_extra_bits;
-                            let distance = match distance_base {
-                                0..=3 => distance_base + 1,
-                                4..=5 => 5 + (distance_base - 4) * 2,
-                                6..=7 => 9 + (distance_base - 6) * 4,
-                                8..=9 => 17 + (distance_base - 8) * 8,
-                                10..=11 => 33 + (distance_base - 10) * 16,
-                                12..=13 => 65 + (distance_base - 12) * 32,
-                                14..=15 => 129 + (distance_base - 14) * 64,
-                                16..=17 => 257 + (distance_base - 16) * 128,
-                                18..=19 => 513 + (distance_base - 18) * 256,
-                                20..=21 => 1025 + (distance_base - 20) * 512,
-                                22..=23 => 2049 + (distance_base - 22) * 1024,
-                                24..=25 => 4097 + (distance_base - 24) * 2048,
-                                26..=27 => 8193 + (distance_base - 26) * 4096,
-                                28..=29 => 16385 + (distance_base - 28) * 8192,
-                                _ => bail!("invalid distance base!"),
-                            } + distance_extra_bits;
-                            self.tracker
-                                .write_previous(distance as usize, length as usize)?;
-                        }
+            Encoder::Lz77 { finder, tokens, .. } => {
+                finder.push(buf);
+                finder.tokenize(false, tokens);
+                self.write_token_blocks(false, false)?;
+            }
+            Encoder::Zopfli { .. } => {
+                while let Encoder::Zopfli { history, .. } = self.encoder {
+                    if self.buffer.len() - history < ZOPFLI_SEGMENT_SIZE {
+                        break;
                     }
+                    self.write_zopfli_segment(ZOPFLI_SEGMENT_SIZE, false)?;
                 }
             }
-            CompressionType::DynamicTree => {
-                let (litlen_coding, distance_coding) =
-                    decode_litlen_distance_trees(&mut self.bit_reader)?;
-                loop {
-                    let token = litlen_coding.read_symbol(&mut self.bit_reader)?;
-                    match token {
-                        LitLenToken::EndOfBlock => {
-                            break;
-                        }
-                        LitLenToken::Literal(byte) => {
-                            self.tracker.write_u8(byte)?;
-                        }
-                        LitLenToken::Length {
-                            base: length_base,
-                            extra_bits: length_extra_bits,
-                        } => {
-                            let length_extra_bits =
-                                self.bit_reader.read_bits(length_extra_bits)?.bits();
-                            let distance_token =
-                                distance_coding.read_symbol(&mut self.bit_reader)?;
-                            let distance_base = distance_token.base;
-                            let distance_extra_bits =
-                                self.bit_reader.read_bits(distance_token.extra_bits)?.bits();
-                            let length = match length_base {
-                                257..=264 => length_base - 254,
-                                265..=268 => 11 + (length_base - 265) * 2,
-                                269..=272 => 19 + (length_base - 269) * 4,
-                                273..=276 => 35 + (length_base - 273) * 8,
-                                277..=280 => 67 + (length_base - 277) * 16,
-                                281..=284 => 131 + (length_base - 281) * 32,
-                                285 => 258,
-                                _ => bail!("invalid length base!"),
-                            } + length_extra_bits;
-                            let distance = match distance_base {
-                                0..=3 => distance_base + 1,
-                                4..=5 => 5 + (distance_base - 4) * 2,
-                                6..=7 => 9 + (distance_base - 6) * 4,
-                                8..=9 => 17 + (distance_base - 8) * 8,
-                                10..=11 => 33 + (distance_base - 10) * 16,
-                                12..=13 => 65 + (distance_base - 12) * 32,
-                                14..=15 => 129 + (distance_base - 14) * 64,
-                                16..=17 => 257 + (distance_base - 16) * 128,
-                                18..=19 => 513 + (distance_base - 18) * 256,
-                                20..=21 => 1025 + (distance_base - 20) * 512,
-                                22..=23 => 2049 + (distance_base - 22) * 1024,
-                                24..=25 => 4097 + (distance_base - 24) * 2048,
-                                26..=27 => 8193 + (distance_base - 26) * 4096,
-                                28..=29 => 16385 + (distance_base - 28) * 8192,
-                                _ => bail!("invalid distance base!"),
-                            } + distance_extra_bits;
-                            self.tracker
-                                .write_previous(distance as usize, length as usize)?;
-                        }
-                    }
+        }
+        Ok(())
+    }
+
+    /// Writes out everything written so far in blocks that are not final.
+    fn write_pending(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Encoder::Stored => {
+                while !self.buffer.is_empty() {
+                    self.write_stored_block(self.buffer.len().min(MAX_STORED_BLOCK_SIZE), false)?;
                 }
             }
-            CompressionType::Reserved => {
-                bail!("unsupported block type")
+            Encoder::Lz77 { finder, tokens, .. } => {
+                finder.tokenize(true, tokens);
+                self.write_token_blocks(true, false)?;
+            }
+            Encoder::Zopfli { history, .. } => {
+                let len = self.buffer.len() - *history;
+                if len > 0 {
+                    self.write_zopfli_segment(len, false)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.bit_writer.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.bit_writer.get_mut()
+    }
+
+    /// Writes out the buffered data followed by an empty stored block, so that
+    /// the output ends on a byte boundary and everything written so far can
+    /// be decoded from it (Z_SYNC_FLUSH). Later data may still refer back to
+    /// the data before the flush.
+    pub fn sync_flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.write_stored_block(0, false)?;
+        self.is_flushed = true;
+        Ok(())
+    }
+
+    /// Like [`DeflateWriter::sync_flush`], but also forgets the data written
+    /// so far, so that the data after the flush can be decoded without any
+    /// history (Z_FULL_FLUSH).
+    pub fn full_flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        match &mut self.encoder {
+            Encoder::Stored => {}
+            Encoder::Lz77 { finder, .. } => finder.reset(),
+            Encoder::Zopfli { history, .. } => {
+                self.buffer.clear();
+                *history = 0;
             }
         }
+        self.write_stored_block(0, false)?;
+        self.is_flushed = true;
+        Ok(())
+    }
 
-        Ok(mem::take(self.tracker.get_mut_ref_inner()))
+    /// Writes out the buffered data as the final block and returns the inner
+    /// writer along with the tracker of the uncompressed data.
+    pub fn finish(mut self) -> Result<(T, TrackingWriter<io::Sink>)> {
+        match &mut self.encoder {
+            Encoder::Stored => self.write_stored_block(self.buffer.len(), true)?,
+            Encoder::Lz77 { finder, tokens, .. } => {
+                finder.tokenize(true, tokens);
+                self.write_token_blocks(true, true)?;
+            }
+            Encoder::Zopfli { history, .. } => {
+                let len = self.buffer.len() - *history;
+                self.write_zopfli_segment(len, true)?;
+            }
+        }
+        Ok((self.bit_writer.finish()?, self.tracker))
     }
+}
 
-    pub fn into_inners(self) -> (T, TrackingWriter<Vec<u8>>) {
-        (self.bit_reader.into_inner(), self.tracker)
+impl<T: Write> Write for DeflateWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while let Some(len) = self
+            .rsync_splitter
+            .as_mut()
+            .and_then(|splitter| splitter.find_boundary(rest))
+        {
+            self.write_data(&rest[..len])?;
+            self.full_flush()?;
+            rest = &rest[len..];
+        }
+        self.write_data(rest)?;
+        Ok(buf.len())
+    }
+
+    /// Does a [`DeflateWriter::sync_flush`] unless nothing has been written
+    /// since the last flush, then flushes the inner writer.
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.is_flushed {
+            self.sync_flush()?;
+        }
+        self.get_mut().flush()
     }
 }
 
-// TODO: your code goes here.
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_reader::BitSequence;
+    use crate::huffman_coding::encoding_codes;
+    use crate::test_util::{random_bytes, text};
+
+    /// Decodes the blocks of a stream cut off after a flush, up to the empty
+    /// stored block the flush ended with.
+    fn decode_flushed(mut reader: DeflateReader<&[u8]>) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        loop {
+            let block = reader.next_block().context("stream is finished")??;
+            let header = reader.last_header().unwrap();
+            assert!(!header.is_final);
+            if header.compression_type == CompressionType::Uncompressed && block.is_empty() {
+                // The rest of the input is consumed and the stream is aligned.
+                let (rest, _) = reader.into_inners();
+                assert!(rest.is_empty());
+                return Ok(output);
+            }
+            output.extend(block);
+        }
+    }
+
+    #[test]
+    fn skip_block() -> Result<()> {
+        let data = text();
+        let mut writer = DeflateWriter::with_level(BitWriter::new(Vec::new()), 6)?;
+        writer.write_all(&data)?;
+        let (compressed, expected) = writer.finish()?;
+
+        let mut reader = DeflateReader::new(BitReader::new(compressed.as_slice()));
+        let mut size = 0;
+        while let Some(block_size) = reader.skip_block() {
+            size += block_size?;
+        }
+        let (_, mut tracker) = reader.into_inners();
+        assert_eq!(size, data.len());
+        assert!(tracker.get_mut_ref_inner().is_empty());
+        assert_eq!(tracker.byte_count(), data.len());
+        assert_eq!(tracker.crc32(), expected.crc32());
+        Ok(())
+    }
+
+    #[test]
+    fn sync_flush() -> Result<()> {
+        let data = text();
+        let (first, second) = data.split_at(data.len() / 2);
+        for level in [0, 1, 6, ZOPFLI_LEVEL] {
+            let mut writer = DeflateWriter::with_level(BitWriter::new(Vec::new()), level)?;
+            writer.write_all(first)?;
+            writer.flush()?;
+            let flushed = writer.get_ref().clone();
+            assert!(flushed.ends_with(&[0, 0, 0xff, 0xff]));
+            // Nothing was written since, so no more empty blocks.
+            writer.flush()?;
+            assert_eq!(writer.get_ref().len(), flushed.len());
+
+            let reader = DeflateReader::new(BitReader::new(flushed.as_slice()));
+            assert_eq!(decode_flushed(reader)?, first, "level {}", level);
+
+            writer.write_all(second)?;
+            let (compressed, tracker) = writer.finish()?;
+            assert_eq!(tracker.byte_count(), data.len());
+            let mut reader = DeflateReader::new(BitReader::new(compressed.as_slice()));
+            let mut output = Vec::new();
+            while let Some(block) = reader.next_block() {
+                output.extend(block?);
+            }
+            assert_eq!(output, data, "level {}", level);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn full_flush() -> Result<()> {
+        let data = text();
+        for level in [0, 1, 6, ZOPFLI_LEVEL] {
+            let mut writer = DeflateWriter::with_level(BitWriter::new(Vec::new()), level)?;
+            writer.write_all(&data)?;
+            writer.full_flush()?;
+            let boundary = writer.get_ref().len();
+            writer.write_all(&data)?;
+            writer.sync_flush()?;
+            let compressed = writer.get_ref();
+
+            let reader = DeflateReader::new(BitReader::new(&compressed[..boundary]));
+            assert_eq!(decode_flushed(reader)?, data, "level {}", level);
+            // The repeated data can't refer back to its first copy.
+            let mut reader = TokenReader::new(&compressed[boundary..]);
+            reader.set_strict(true);
+            let mut size = 0;
+            for token in reader.by_ref() {
+                match token? {
+                    Token::Literal(_) => size += 1,
+                    Token::Match { length, .. } => size += length as usize,
+                    Token::BlockBoundary(header) => assert!(!header.is_final),
+                }
+                if size == data.len() {
+                    break;
+                }
+            }
+            assert_eq!(size, data.len(), "level {}", level);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn full_flush_small_writes() -> Result<()> {
+        let data = &text()[..200];
+        for level in 1..=9 {
+            let mut writer = DeflateWriter::with_level(BitWriter::new(Vec::new()), level)?;
+            writer.write_all(&data[..100])?;
+            writer.full_flush()?;
+            writer.write_all(&data[100..])?;
+            let (compressed, tracker) = writer.finish()?;
+            assert_eq!(tracker.byte_count(), data.len());
+
+            let mut reader = DeflateReader::new(BitReader::new(compressed.as_slice()));
+            let mut output = Vec::new();
+            while let Some(block) = reader.next_block() {
+                output.extend(block?);
+            }
+            assert_eq!(output, data, "level {}", level);
+        }
+        Ok(())
+    }
+
+    /// Encodes `data` in stored blocks followed by a final fixed block of
+    /// the given length and distance codes and their Deflate64 extra bits.
+    fn fixed_matches(data: &[u8], matches: &[(usize, u16, usize, u16)]) -> io::Result<Vec<u8>> {
+        let (litlen_lengths, distance_lengths) = fixed_code_lengths();
+        let litlen_codes = encoding_codes(&litlen_lengths);
+        let distance_codes = encoding_codes(&distance_lengths);
+        let format = DeflateFormat::DEFLATE64;
+        let mut bit_writer = BitWriter::new(Vec::new());
+        write_stored_blocks(&mut bit_writer, data, false)?;
+        bit_writer.write_bits(BitSequence::new(1, 1))?;
+        bit_writer.write_bits(BitSequence::new(CompressionType::FixedTree as u16, 2))?;
+        for (length_code, length_extra, distance_code, distance_extra) in matches {
+            bit_writer.write_bits(litlen_codes[*length_code])?;
+            bit_writer.write_bits(BitSequence::new(
+                *length_extra,
+                format.length_extra_bits[length_code - 257],
+            ))?;
+            bit_writer.write_bits(distance_codes[*distance_code])?;
+            bit_writer.write_bits(BitSequence::new(
+                *distance_extra,
+                format.distance_extra_bits[*distance_code],
+            ))?;
+        }
+        bit_writer.write_bits(litlen_codes[256])?;
+        bit_writer.finish()
+    }
+
+    #[test]
+    fn deflate64() -> Result<()> {
+        let data = random_bytes(70000, 1);
+        // Length and distance codes with their extra bits, and the match.
+        let compressed = fixed_matches(
+            &data,
+            &[(285, 39997, 31, 16383), (285, 0, 30, 0), (284, 3, 0, 0)],
+        )?;
+        let expected_matches = [(40000_u32, 65536_u32), (3, 32769), (230, 1)];
+
+        let mut expected = data;
+        for (length, distance) in expected_matches {
+            for _ in 0..length {
+                expected.push(expected[expected.len() - distance as usize]);
+            }
+        }
+        let mut reader =
+            DeflateReader::with_format(BitReader::new(&compressed[..]), DeflateFormat::DEFLATE64);
+        let mut output = Vec::new();
+        while let Some(block) = reader.next_block() {
+            output.extend(block?);
+        }
+        assert!(output == expected);
+        assert_eq!(reader.tracker().window().len(), 1 << 16);
+
+        let reader =
+            TokenReader::with_format(BitReader::new(&compressed[..]), 0, DeflateFormat::DEFLATE64);
+        let matches = reader
+            .filter_map(|token| match token {
+                Ok(Token::Match { length, distance }) => Some(Ok((length, distance))),
+                Ok(_) => None,
+                Err(err) => Some(Err(err)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(matches, expected_matches);
+
+        // Distance code 30 is invalid in deflate.
+        let compressed = fixed_matches(&[0; 100], &[(257, 0, 30, 0)])?;
+        let mut reader = DeflateReader::new(BitReader::new(&compressed[..]));
+        reader.next_block().unwrap()?;
+        let err = reader.next_block().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "invalid distance code");
+        Ok(())
+    }
+}
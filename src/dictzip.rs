@@ -0,0 +1,314 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+
+use anyhow::{ensure, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::bit_reader::{BitReader, CountingReader};
+use crate::bit_writer::BitWriter;
+use crate::deflate::{DeflateReader, DeflateWriter};
+use crate::gzip::{CompressionMethod, MemberFooter, MemberHeader, MemberReader};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// See dictzip(1).
+const RA_SUBFIELD: [u8; 2] = *b"RA";
+const RA_VERSION: u16 = 1;
+
+/// Chunk length used by dictzip itself.
+pub const DEFAULT_CHUNK_LEN: u16 = 58315;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Writes a dictzip file: a single gzip member whose deflate stream is fully
+/// flushed every `chunk_len` bytes, with the chunk table in the header.
+///
+/// The table goes before the data, so the compressed data is kept in memory
+/// until [`DictzipWriter::finish`].
+pub struct DictzipWriter<W> {
+    inner: W,
+    header: MemberHeader,
+    chunk_len: usize,
+    deflate_writer: DeflateWriter<Vec<u8>>,
+    chunk_fill: usize,
+    chunk_sizes: Vec<u16>,
+    chunk_start: usize,
+}
+
+impl<W: Write> DictzipWriter<W> {
+    /// Compresses the chunks with the default level 6.
+    pub fn new(inner: W, header: MemberHeader, chunk_len: u16) -> Result<Self> {
+        Self::with_level(inner, header, chunk_len, 6)
+    }
+
+    /// Compresses the chunks with the given level, see
+    /// [`DeflateWriter::with_level`].
+    pub fn with_level(inner: W, header: MemberHeader, chunk_len: u16, level: u32) -> Result<Self> {
+        ensure!(chunk_len > 0, "chunk length must be positive");
+        ensure!(header.extra.is_none(), "header already has an extra field");
+        Ok(Self {
+            inner,
+            header,
+            chunk_len: chunk_len as usize,
+            deflate_writer: DeflateWriter::with_level(BitWriter::new(Vec::new()), level)?,
+            chunk_fill: 0,
+            chunk_sizes: Vec::new(),
+            chunk_start: 0,
+        })
+    }
+
+    fn end_chunk(&mut self) -> io::Result<()> {
+        self.deflate_writer.full_flush()?;
+        let end = self.deflate_writer.get_ref().len();
+        let size = u16::try_from(end - self.chunk_start).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "compressed chunk is too large")
+        })?;
+        self.chunk_sizes.push(size);
+        self.chunk_start = end;
+        self.chunk_fill = 0;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        if self.chunk_fill > 0 {
+            self.end_chunk()?;
+        }
+        let (data, tracker) = self.deflate_writer.finish()?;
+
+        ensure!(
+            self.chunk_sizes.len() <= u16::MAX as usize,
+            "too many chunks for the dictzip table"
+        );
+        let mut table = Vec::with_capacity(6 + 2 * self.chunk_sizes.len());
+        table.extend_from_slice(&RA_VERSION.to_le_bytes());
+        table.extend_from_slice(&(self.chunk_len as u16).to_le_bytes());
+        table.extend_from_slice(&(self.chunk_sizes.len() as u16).to_le_bytes());
+        for size in &self.chunk_sizes {
+            table.extend_from_slice(&size.to_le_bytes());
+        }
+        self.header.push_extra_subfield(RA_SUBFIELD, &table)?;
+
+        self.header.write_to(&mut self.inner)?;
+        self.inner.write_all(&data)?;
+        MemberFooter::new(tracker).write_to(&mut self.inner)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for DictzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = buf.len().min(self.chunk_len - self.chunk_fill);
+        self.deflate_writer.write_all(&buf[..size])?;
+        self.chunk_fill += size;
+        if self.chunk_fill == self.chunk_len {
+            self.end_chunk()?;
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Random access reader over a dictzip file.
+pub struct DictzipReader<R> {
+    inner: R,
+    header: MemberHeader,
+    chunk_len: u64,
+    chunk_offsets: Vec<u64>,
+    size: u64,
+    position: u64,
+    chunk_index: Option<usize>,
+    chunk: Vec<u8>,
+}
+
+impl<R: Read + Seek> DictzipReader<R> {
+    pub fn new(mut inner: R) -> Result<Self> {
+        inner.seek(SeekFrom::Start(0))?;
+        let (header, deflate_reader) =
+            MemberReader::new(CountingReader::new(BufReader::new(&mut inner)))
+                .into_deflate_reader()?;
+        let data_offset = deflate_reader.bit_reader().position() / 8;
+        ensure!(
+            header.compression_method == CompressionMethod::Deflate,
+            "unsupported compression method"
+        );
+
+        let mut table = header
+            .extra_subfield(RA_SUBFIELD)
+            .context("not a dictzip file")?;
+        ensure!(
+            table.read_u16::<LittleEndian>()? == RA_VERSION,
+            "unsupported dictzip version"
+        );
+        let chunk_len = table.read_u16::<LittleEndian>()? as u64;
+        let chunk_count = table.read_u16::<LittleEndian>()? as usize;
+        ensure!(chunk_len > 0, "invalid dictzip chunk length");
+        let mut chunk_offsets = Vec::with_capacity(chunk_count);
+        let mut offset = data_offset;
+        for _i in 0..chunk_count {
+            chunk_offsets.push(offset);
+            offset += table.read_u16::<LittleEndian>()? as u64;
+        }
+
+        inner.seek(SeekFrom::End(-4))?;
+        let size = inner.read_u32::<LittleEndian>()? as u64;
+        ensure!(
+            size.div_ceil(chunk_len) == chunk_count as u64,
+            "dictzip chunk table doesn't match the data size"
+        );
+
+        Ok(Self {
+            inner,
+            header,
+            chunk_len,
+            chunk_offsets,
+            size,
+            position: 0,
+            chunk_index: None,
+            chunk: Vec::new(),
+        })
+    }
+
+    pub fn header(&self) -> &MemberHeader {
+        &self.header
+    }
+
+    /// Size of the uncompressed data.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn load_chunk(&mut self, index: usize) -> Result<()> {
+        let expected_len = self
+            .chunk_len
+            .min(self.size - index as u64 * self.chunk_len) as usize;
+        self.chunk_index = None;
+        self.chunk.clear();
+
+        self.inner
+            .seek(SeekFrom::Start(self.chunk_offsets[index]))?;
+        let mut deflate_reader =
+            DeflateReader::new(BitReader::new(BufReader::new(&mut self.inner)));
+        while self.chunk.len() < expected_len {
+            let block = deflate_reader
+                .next_block()
+                .context("unexpected end of dictzip chunk")??;
+            self.chunk.extend_from_slice(&block);
+        }
+        ensure!(
+            self.chunk.len() == expected_len,
+            "dictzip chunk has wrong length"
+        );
+
+        self.chunk_index = Some(index);
+        Ok(())
+    }
+
+    fn read_at_position(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let index = (self.position / self.chunk_len) as usize;
+        if self.chunk_index != Some(index) {
+            self.load_chunk(index)?;
+        }
+        let available = &self.chunk[(self.position % self.chunk_len) as usize..];
+        let size = available.len().min(buf.len());
+        buf[..size].copy_from_slice(&available[..size]);
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Read for DictzipReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.size {
+            return Ok(0);
+        }
+        self.read_at_position(buf)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+impl<R: Read + Seek> Seek for DictzipReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek position"))?;
+        Ok(self.position)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::text;
+    use std::io::Cursor;
+
+    #[test]
+    fn write_and_read() -> Result<()> {
+        let data: Vec<u8> = (0..100_000_u64).map(|i| (i * 7 % 253) as u8).collect();
+        let header = MemberHeader {
+            name: Some("dict.txt".to_string()),
+            ..MemberHeader::default()
+        };
+        let mut writer = DictzipWriter::new(Vec::new(), header, 4096)?;
+        writer.write_all(&data)?;
+        let compressed = writer.finish()?;
+
+        let mut decompressed = Vec::new();
+        crate::decompress(compressed.as_slice(), &mut decompressed)?;
+        assert_eq!(decompressed, data);
+
+        let mut reader = DictzipReader::new(Cursor::new(compressed))?;
+        assert_eq!(reader.size(), data.len() as u64);
+        assert_eq!(reader.header().name.as_deref(), Some("dict.txt"));
+        for offset in [50_000, 4095, 99_000, 0] {
+            reader.seek(SeekFrom::Start(offset))?;
+            let mut buf = vec![0; 1000];
+            reader.read_exact(&mut buf)?;
+            let offset = offset as usize;
+            assert_eq!(buf, &data[offset..offset + 1000]);
+        }
+
+        reader.seek(SeekFrom::End(-10))?;
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail)?;
+        assert_eq!(tail, &data[data.len() - 10..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn levels() -> Result<()> {
+        let data = text();
+        let mut sizes = Vec::new();
+        for level in [0, 1, 9] {
+            let mut writer =
+                DictzipWriter::with_level(Vec::new(), MemberHeader::default(), 4096, level)?;
+            writer.write_all(&data)?;
+            let compressed = writer.finish()?;
+            sizes.push(compressed.len());
+
+            let mut reader = DictzipReader::new(Cursor::new(compressed))?;
+            reader.seek(SeekFrom::Start(10_000))?;
+            let mut buf = vec![0; 5000];
+            reader.read_exact(&mut buf)?;
+            assert_eq!(buf, &data[10_000..15_000], "level {}", level);
+        }
+        assert!(sizes[0] > data.len());
+        assert!(sizes[1] < data.len() / 2);
+        assert!(sizes[2] <= sizes[1]);
+        assert!(DictzipWriter::with_level(Vec::new(), MemberHeader::default(), 4096, 10).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+#![forbid(unsafe_code)]
+
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Result};
+
+use crate::bit_reader::CountingReader;
+use crate::deflate::{BlockTrace, Token};
+use crate::gzip::{CompressionMethod, MemberHeader, MemberReader};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Human-readable text, in the spirit of infgen.
+    #[default]
+    Text,
+    /// One JSON object per line: a member header or a block.
+    Json,
+}
+
+impl FromStr for DumpFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "text" => Self::Text,
+            "json" => Self::Json,
+            _ => bail!("unknown dump format: {}", s),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DumpOptions {
+    pub format: DumpFormat,
+    /// Also print the code lengths of dynamic blocks.
+    pub code_lengths: bool,
+    /// Also print every literal and match.
+    pub tokens: bool,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct BlockInfo<'a> {
+    member: usize,
+    index: usize,
+    is_final: bool,
+    compression_type: String,
+    start_bit: u64,
+    end_bit: u64,
+    output_size: usize,
+    trace: Option<&'a BlockTrace>,
+}
+
+/// Decodes `input` and prints the structure of every deflate block.
+pub fn dump_blocks<R: BufRead, W: Write>(
+    input: R,
+    mut output: W,
+    options: &DumpOptions,
+) -> Result<()> {
+    let mut input = CountingReader::new(input);
+    let mut member = 0;
+    while !input.fill_buf()?.is_empty() {
+        let (header, mut deflate_reader) = MemberReader::new(input).into_deflate_reader()?;
+        ensure!(
+            header.compression_method == CompressionMethod::Deflate,
+            "unsupported compression method"
+        );
+        print_member(&mut output, options, member, &header)?;
+
+        deflate_reader.set_tracing(options.code_lengths || options.tokens);
+        deflate_reader.set_token_tracing(options.tokens);
+        let mut index = 0;
+        loop {
+            let start_bit = deflate_reader.bit_reader().position();
+            let Some(block) = deflate_reader.next_block() else {
+                break;
+            };
+            let output_size = block?.len();
+            let block_header = deflate_reader.last_header().unwrap();
+            let info = BlockInfo {
+                member,
+                index,
+                is_final: block_header.is_final,
+                compression_type: block_header.compression_type.to_string(),
+                start_bit,
+                end_bit: deflate_reader.bit_reader().position(),
+                output_size,
+                trace: deflate_reader.trace(),
+            };
+            match options.format {
+                DumpFormat::Text => print_block_text(&mut output, options, &info)?,
+                DumpFormat::Json => print_block_json(&mut output, options, &info)?,
+            }
+            index += 1;
+        }
+
+        let (reader, writer) = deflate_reader.into_inners();
+        input = reader;
+        MemberReader::read_footer(&mut input)?.check(writer)?;
+        member += 1;
+    }
+    Ok(())
+}
+
+fn print_member<W: Write>(
+    output: &mut W,
+    options: &DumpOptions,
+    member: usize,
+    header: &MemberHeader,
+) -> Result<()> {
+    match options.format {
+        DumpFormat::Text => {
+            write!(
+                output,
+                "member {}: mtime={}",
+                member, header.modification_time
+            )?;
+            if let Some(name) = &header.name {
+                write!(output, " name={:?}", name)?;
+            }
+            writeln!(output)?;
+        }
+        DumpFormat::Json => {
+            write!(
+                output,
+                "{{\"member\":{},\"mtime\":{},\"os\":{}",
+                member, header.modification_time, header.os
+            )?;
+            if let Some(name) = &header.name {
+                write!(output, ",\"name\":{}", json_string(name))?;
+            }
+            writeln!(output, "}}")?;
+        }
+    }
+    Ok(())
+}
+
+fn print_block_text<W: Write>(
+    output: &mut W,
+    options: &DumpOptions,
+    info: &BlockInfo,
+) -> Result<()> {
+    writeln!(
+        output,
+        "block {}: {}{} bits={}..{} output={}",
+        info.index,
+        info.compression_type,
+        if info.is_final { " final" } else { "" },
+        info.start_bit,
+        info.end_bit,
+        info.output_size
+    )?;
+    let Some(trace) = info.trace else {
+        return Ok(());
+    };
+    if options.code_lengths && !trace.litlen_lengths.is_empty() {
+        writeln!(output, "  litlen {}", join(&trace.litlen_lengths, " "))?;
+        writeln!(output, "  dist {}", join(&trace.distance_lengths, " "))?;
+    }
+    if options.tokens {
+        for token in &trace.tokens {
+            match token {
+                Token::Literal(byte) if byte.is_ascii_graphic() || *byte == b' ' => {
+                    writeln!(output, "  literal '{}", *byte as char)?
+                }
+                Token::Literal(byte) => writeln!(output, "  literal {}", byte)?,
+                Token::Match { length, distance } => {
+                    writeln!(output, "  match {} {}", length, distance)?
+                }
+                Token::BlockBoundary(_) => unreachable!(),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_block_json<W: Write>(
+    output: &mut W,
+    options: &DumpOptions,
+    info: &BlockInfo,
+) -> Result<()> {
+    write!(
+        output,
+        "{{\"member\":{},\"block\":{},\"final\":{},\"type\":\"{}\",\"start_bit\":{},\"end_bit\":{},\"output_size\":{}",
+        info.member,
+        info.index,
+        info.is_final,
+        info.compression_type,
+        info.start_bit,
+        info.end_bit,
+        info.output_size
+    )?;
+    if let Some(trace) = info.trace {
+        print_trace_json(output, options, trace)?;
+    }
+    writeln!(output, "}}")?;
+    Ok(())
+}
+
+fn print_trace_json<W: Write>(
+    output: &mut W,
+    options: &DumpOptions,
+    trace: &BlockTrace,
+) -> Result<()> {
+    if options.code_lengths && !trace.litlen_lengths.is_empty() {
+        write!(
+            output,
+            ",\"litlen_lengths\":[{}],\"distance_lengths\":[{}]",
+            join(&trace.litlen_lengths, ","),
+            join(&trace.distance_lengths, ",")
+        )?;
+    }
+    if options.tokens {
+        // A literal is a number, a match is a [length, distance] pair.
+        let tokens = trace
+            .tokens
+            .iter()
+            .map(|token| match token {
+                Token::Literal(byte) => byte.to_string(),
+                Token::Match { length, distance } => format!("[{},{}]", length, distance),
+                Token::BlockBoundary(_) => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        write!(output, ",\"tokens\":[{}]", tokens.join(","))?;
+    }
+    Ok(())
+}
+
+fn join(lengths: &[u8], separator: &str) -> String {
+    lengths
+        .iter()
+        .map(u8::to_string)
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = include_bytes!("../data/ok/00-Cargo.toml.gz");
+
+    #[test]
+    fn dump_text() -> Result<()> {
+        let mut output = Vec::new();
+        let options = DumpOptions {
+            format: DumpFormat::Text,
+            code_lengths: false,
+            tokens: true,
+        };
+        dump_blocks(DATA, &mut output, &options)?;
+        let output = String::from_utf8(output)?;
+        let mut lines = output.lines();
+        assert!(lines.next().unwrap().starts_with("member 0:"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("block 0: dynamic final bits=80.."));
+        assert!(output.contains("\n  literal '["));
+        assert!(output.contains("\n  match "));
+        assert!(!output.contains("\n  litlen "));
+        Ok(())
+    }
+
+    #[test]
+    fn dump_json() -> Result<()> {
+        let mut output = Vec::new();
+        let options = DumpOptions {
+            format: DumpFormat::Json,
+            code_lengths: true,
+            tokens: false,
+        };
+        dump_blocks(DATA, &mut output, &options)?;
+        let output = String::from_utf8(output)?;
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with(
+            "{\"member\":0,\"block\":0,\"final\":true,\"type\":\"dynamic\",\"start_bit\":80,"
+        ));
+        assert!(lines[1].contains("\"litlen_lengths\":["));
+        assert!(!lines[1].contains("\"tokens\""));
+        Ok(())
+    }
+
+    #[test]
+    fn tracing() -> Result<()> {
+        let (_, mut deflate_reader) = MemberReader::new(DATA).into_deflate_reader()?;
+        deflate_reader.set_tracing(true);
+        deflate_reader.next_block().unwrap()?;
+        let trace = deflate_reader.trace().unwrap();
+        assert!(!trace.litlen_lengths.is_empty());
+        assert!(trace.tokens.is_empty());
+
+        let (_, mut deflate_reader) = MemberReader::new(DATA).into_deflate_reader()?;
+        deflate_reader.set_tracing(true);
+        deflate_reader.set_token_tracing(true);
+        deflate_reader.next_block().unwrap()?;
+        assert!(!deflate_reader.trace().unwrap().tokens.is_empty());
+
+        let (_, mut deflate_reader) = MemberReader::new(DATA).into_deflate_reader()?;
+        deflate_reader.next_block().unwrap()?;
+        assert!(deflate_reader.trace().is_none());
+        Ok(())
+    }
+}
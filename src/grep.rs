@@ -0,0 +1,188 @@
+#![forbid(unsafe_code)]
+
+use std::io::{BufRead, Read, Write};
+
+use anyhow::Result;
+use regex::bytes::{Regex, RegexBuilder};
+
+use crate::members::MultiMemberReader;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Options of [`Grep`], named after the grep flags.
+#[derive(Clone, Debug, Default)]
+pub struct GrepOptions {
+    /// `-i`: ignore case.
+    pub ignore_case: bool,
+    /// `-c`: print the number of matching lines instead of the lines.
+    pub count: bool,
+    /// `-l`: print the name of the input on the first match and stop.
+    pub files_with_matches: bool,
+    /// `-n`: prefix lines with their line number.
+    pub line_number: bool,
+    /// `-H`: prefix output with the name of the input.
+    pub with_filename: bool,
+}
+
+/// Searches the decompressed data of gzip files for lines matching a regular
+/// expression, like zgrep.
+pub struct Grep {
+    regex: Regex,
+    options: GrepOptions,
+}
+
+impl Grep {
+    pub fn new(pattern: &str, options: GrepOptions) -> Result<Self> {
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(options.ignore_case)
+            .build()?;
+        Ok(Self { regex, options })
+    }
+
+    /// Searches every member of `input`, as a single stream of lines, and
+    /// prints the results for it under `name`. Returns the number of
+    /// matching lines.
+    pub fn search<R: BufRead, W: Write>(&self, input: R, name: &str, mut output: W) -> Result<u64> {
+        let mut reader = MultiMemberReader::new(input);
+        let mut buf = vec![0; 1 << 16];
+        let mut line = Vec::new();
+        let mut line_number = 0;
+        let mut count = 0;
+        let mut is_stopped = false;
+
+        'members: while let Some(member) = reader.next_member() {
+            let mut member = member?;
+            loop {
+                let size = member.read(&mut buf)?;
+                if size == 0 {
+                    break;
+                }
+                let mut data = &buf[..size];
+                while let Some(end) = data.iter().position(|byte| *byte == b'\n') {
+                    line.extend_from_slice(&data[..end]);
+                    data = &data[end + 1..];
+                    line_number += 1;
+                    if self.check_line(&line, line_number, name, &mut output)? {
+                        count += 1;
+                        if self.options.files_with_matches {
+                            is_stopped = true;
+                            break 'members;
+                        }
+                    }
+                    line.clear();
+                }
+                line.extend_from_slice(data);
+            }
+        }
+        // The last line may lack a newline.
+        if !line.is_empty() && !is_stopped {
+            line_number += 1;
+            if self.check_line(&line, line_number, name, &mut output)? {
+                count += 1;
+            }
+        }
+
+        if self.options.files_with_matches {
+            if count > 0 {
+                writeln!(output, "{}", name)?;
+            }
+        } else if self.options.count {
+            if self.options.with_filename {
+                write!(output, "{}:", name)?;
+            }
+            writeln!(output, "{}", count)?;
+        }
+        Ok(count)
+    }
+
+    /// Prints `line` if it matches and lines are printed, returns whether it
+    /// matches.
+    fn check_line<W: Write>(
+        &self,
+        line: &[u8],
+        line_number: u64,
+        name: &str,
+        output: &mut W,
+    ) -> Result<bool> {
+        if !self.regex.is_match(line) {
+            return Ok(false);
+        }
+        if !self.options.count && !self.options.files_with_matches {
+            if self.options.with_filename {
+                write!(output, "{}:", name)?;
+            }
+            if self.options.line_number {
+                write!(output, "{}:", line_number)?;
+            }
+            output.write_all(line)?;
+            output.write_all(b"\n")?;
+        }
+        Ok(true)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two members, with a line split between them.
+    fn data() -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        crate::compress(&b"first\nfoo"[..], &mut data)?;
+        crate::compress(&b"bar\nsecond FOOBAR\nlast foobar"[..], &mut data)?;
+        Ok(data)
+    }
+
+    fn search(pattern: &str, options: GrepOptions) -> Result<(u64, String)> {
+        let mut output = Vec::new();
+        let count =
+            Grep::new(pattern, options)?.search(data()?.as_slice(), "log.gz", &mut output)?;
+        Ok((count, String::from_utf8(output)?))
+    }
+
+    #[test]
+    fn lines() -> Result<()> {
+        let options = GrepOptions {
+            line_number: true,
+            ..GrepOptions::default()
+        };
+        assert_eq!(
+            search("foobar", options)?,
+            (2, "2:foobar\n4:last foobar\n".to_string())
+        );
+
+        let options = GrepOptions {
+            ignore_case: true,
+            with_filename: true,
+            ..GrepOptions::default()
+        };
+        assert_eq!(
+            search("^s.*foobar", options)?,
+            (1, "log.gz:second FOOBAR\n".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn count_and_list() -> Result<()> {
+        let options = GrepOptions {
+            count: true,
+            ..GrepOptions::default()
+        };
+        assert_eq!(search("o", options)?, (3, "3\n".to_string()));
+
+        let options = GrepOptions {
+            files_with_matches: true,
+            ..GrepOptions::default()
+        };
+        assert_eq!(search("o", options)?, (1, "log.gz\n".to_string()));
+        let options = GrepOptions {
+            files_with_matches: true,
+            ..GrepOptions::default()
+        };
+        assert_eq!(search("missing", options)?, (0, String::new()));
+        Ok(())
+    }
+}
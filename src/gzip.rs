@@ -1,12 +1,17 @@
 #![forbid(unsafe_code)]
 
-use std::io::BufRead;
+use std::io::{self, BufRead, Write};
 
-use anyhow::{ensure, Result};
-use byteorder::{LittleEndian, ReadBytesExt};
-use crc::Crc;
+use anyhow::{ensure, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use crate::{bit_reader::BitReader, deflate::DeflateReader};
+use crate::{
+    bit_reader::{BitReader, CountingReader},
+    bit_writer::BitWriter,
+    crc32::Crc32,
+    deflate::{DeflateReader, DeflateWriter, TokenReader},
+    tracking_writer::TrackingWriter,
+};
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -21,9 +26,12 @@ const FEXTRA_OFFSET: u8 = 2;
 const FNAME_OFFSET: u8 = 3;
 const FCOMMENT_OFFSET: u8 = 4;
 
+/// OS value for "unknown" operating system, see RFC 1952, section 2.3.1.
+pub const OS_UNKNOWN: u8 = 255;
+
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct MemberHeader {
     pub compression_method: CompressionMethod,
     pub flags: MemberFlags,
@@ -37,8 +45,7 @@ pub struct MemberHeader {
 
 impl MemberHeader {
     pub fn crc16(&self) -> u16 {
-        let crc = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
-        let mut digest = crc.digest();
+        let mut digest = Crc32::new();
 
         digest.update(&[ID1, ID2, self.compression_method.into(), self.flags().0]);
         digest.update(&self.modification_time.to_le_bytes());
@@ -62,8 +69,89 @@ impl MemberHeader {
         (digest.finalize() & 0xffff) as u16
     }
 
+    /// Returns the flags, with FEXTRA, FNAME and FCOMMENT matching the fields.
     pub fn flags(&self) -> MemberFlags {
-        self.flags
+        let mut flags = self.flags;
+        flags.set_has_extra(self.extra.is_some());
+        flags.set_has_name(self.name.is_some());
+        flags.set_has_comment(self.comment.is_some());
+        flags
+    }
+
+    /// Looks up an extra field subfield by its two-byte ID (SI1, SI2), see
+    /// RFC 1952, section 2.3.1.1.
+    pub fn extra_subfield(&self, id: [u8; 2]) -> Option<&[u8]> {
+        let mut extra = self.extra.as_deref()?;
+        while extra.len() >= 4 {
+            let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+            let data = extra.get(4..4 + len)?;
+            if extra[..2] == id {
+                return Some(data);
+            }
+            extra = &extra[4 + len..];
+        }
+        None
+    }
+
+    /// Appends a subfield to the extra field, creating it if necessary.
+    pub fn push_extra_subfield(&mut self, id: [u8; 2], data: &[u8]) -> Result<()> {
+        let extra = self.extra.get_or_insert_with(Vec::new);
+        ensure!(
+            extra.len() + 4 + data.len() <= u16::MAX as usize,
+            "extra field is too long"
+        );
+        extra.extend_from_slice(&id);
+        extra.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        extra.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Clears the fields that depend on where and when the data is
+    /// compressed rather than on the data: the name, the modification time
+    /// and the OS.
+    pub fn make_reproducible(&mut self) {
+        self.name = None;
+        self.modification_time = 0;
+        self.os = OS_UNKNOWN;
+    }
+
+    pub fn write_to<T: Write>(&self, wtr: &mut T) -> Result<()> {
+        let flags = self.flags();
+        wtr.write_all(&[ID1, ID2, self.compression_method.into(), flags.0])?;
+        wtr.write_u32::<LittleEndian>(self.modification_time)?;
+        wtr.write_all(&[self.extra_flags, self.os])?;
+
+        if let Some(extra) = &self.extra {
+            ensure!(extra.len() <= u16::MAX as usize, "extra field is too long");
+            wtr.write_u16::<LittleEndian>(extra.len() as u16)?;
+            wtr.write_all(extra)?;
+        }
+
+        for field in [&self.name, &self.comment].into_iter().flatten() {
+            ensure!(!field.contains('\0'), "header strings cannot contain NUL");
+            wtr.write_all(field.as_bytes())?;
+            wtr.write_u8(0)?;
+        }
+
+        if flags.has_crc() {
+            wtr.write_u16::<LittleEndian>(self.crc16())?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for MemberHeader {
+    fn default() -> Self {
+        Self {
+            compression_method: CompressionMethod::Deflate,
+            flags: MemberFlags::default(),
+            modification_time: 0,
+            extra: None,
+            name: None,
+            comment: None,
+            extra_flags: 0,
+            os: OS_UNKNOWN,
+        }
     }
 }
 
@@ -95,7 +183,7 @@ impl From<CompressionMethod> for u8 {
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct MemberFlags(u8);
 
 #[allow(unused)]
@@ -161,6 +249,31 @@ pub struct MemberFooter {
     pub data_size: u32,
 }
 
+impl MemberFooter {
+    pub fn new<T: Write>(writer: TrackingWriter<T>) -> Self {
+        Self {
+            data_size: writer.byte_count() as u32,
+            data_crc32: writer.crc32(),
+        }
+    }
+
+    pub fn write_to<T: Write>(&self, wtr: &mut T) -> Result<()> {
+        wtr.write_u32::<LittleEndian>(self.data_crc32)?;
+        wtr.write_u32::<LittleEndian>(self.data_size)?;
+        Ok(())
+    }
+
+    /// Checks the footer against the data tracked while decoding the member.
+    pub fn check<T: Write>(&self, writer: TrackingWriter<T>) -> Result<()> {
+        ensure!(
+            writer.byte_count() as u32 == self.data_size,
+            "length check failed"
+        );
+        ensure!(writer.crc32() == self.data_crc32, "crc32 check failed");
+        Ok(())
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 pub struct MemberReader<T> {
@@ -173,6 +286,20 @@ impl<T: BufRead> MemberReader<T> {
     }
 
     pub fn into_deflate_reader(mut self) -> Result<(MemberHeader, DeflateReader<T>)> {
+        let header = self.read_header()?;
+        Ok((header, DeflateReader::new(BitReader::new(self.inner))))
+    }
+
+    pub fn into_token_reader(mut self) -> Result<(MemberHeader, TokenReader<T>)> {
+        let header = self.read_header()?;
+        Ok((header, TokenReader::new(self.inner)))
+    }
+
+    #[allow(
+        clippy::unnecessary_fallible_conversions,
+        clippy::slow_vector_initialization
+    )]
+    pub fn read_header(&mut self) -> Result<MemberHeader> {
         let id1 = self.inner.read_u8()?;
         let id2 = self.inner.read_u8()?;
         ensure!(id1 == ID1 && id2 == ID2, "wrong id values");
@@ -186,11 +313,9 @@ impl<T: BufRead> MemberReader<T> {
             let len = self.inner.read_u16::<LittleEndian>()?;
             let mut extra: Vec<u8> = Vec::new();
             extra.resize(len as usize, 0);
-            let read_len = self.inner.read(extra.as_mut_slice())?;
-            ensure!(
-                read_len == len as usize,
-                "Not enough bytes for extra fields"
-            );
+            self.inner
+                .read_exact(extra.as_mut_slice())
+                .context("Not enough bytes for extra fields")?;
             Some(extra)
         } else {
             None
@@ -240,7 +365,7 @@ impl<T: BufRead> MemberReader<T> {
             ensure!(header.crc16() == crc, "header crc16 check failed");
         }
 
-        Ok((header, DeflateReader::new(BitReader::new(self.inner))))
+        Ok(header)
     }
 
     pub fn read_footer(rdr: &mut T) -> Result<MemberFooter> {
@@ -252,3 +377,71 @@ impl<T: BufRead> MemberReader<T> {
         })
     }
 }
+
+impl<R: BufRead> MemberReader<CountingReader<R>> {
+    /// Checks whether the remaining input starts with the gzip magic bytes,
+    /// without consuming anything, even when they straddle two buffers of
+    /// the inner reader. A lone trailing byte is not magic.
+    pub fn peek_magic(rdr: &mut CountingReader<R>) -> Result<bool> {
+        Ok(rdr.peek(2)?.starts_with(&[ID1, ID2]))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Writes a single gzip member: the header right away, the compressed data
+/// as it is written, and the footer on [`MemberWriter::finish`].
+pub struct MemberWriter<T: Write> {
+    deflate_writer: DeflateWriter<T>,
+}
+
+impl<T: Write> MemberWriter<T> {
+    /// Writes the data in stored blocks.
+    pub fn new(inner: T, header: &MemberHeader) -> Result<Self> {
+        Self::with_level(inner, header, 0)
+    }
+
+    /// Compresses the data with the given level, see
+    /// [`DeflateWriter::with_level`].
+    pub fn with_level(mut inner: T, header: &MemberHeader, level: u32) -> Result<Self> {
+        header.write_to(&mut inner)?;
+        Ok(Self {
+            deflate_writer: DeflateWriter::with_level(BitWriter::new(inner), level)?,
+        })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.deflate_writer.get_ref()
+    }
+
+    /// See [`DeflateWriter::set_rsyncable`].
+    pub fn set_rsyncable(&mut self, enabled: bool) {
+        self.deflate_writer.set_rsyncable(enabled);
+    }
+
+    /// See [`DeflateWriter::sync_flush`].
+    pub fn sync_flush(&mut self) -> io::Result<()> {
+        self.deflate_writer.sync_flush()
+    }
+
+    /// See [`DeflateWriter::full_flush`].
+    pub fn full_flush(&mut self) -> io::Result<()> {
+        self.deflate_writer.full_flush()
+    }
+
+    pub fn finish(self) -> Result<T> {
+        let (mut inner, tracker) = self.deflate_writer.finish()?;
+        MemberFooter::new(tracker).write_to(&mut inner)?;
+        Ok(inner)
+    }
+}
+
+impl<T: Write> Write for MemberWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.deflate_writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.deflate_writer.flush()
+    }
+}
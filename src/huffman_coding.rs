@@ -1,109 +1,247 @@
 #![forbid(unsafe_code)]
 
-use std::{collections::HashMap, convert::TryFrom, io::BufRead};
+use std::{collections::HashMap, convert::TryFrom, io::BufRead, iter::repeat_n};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 
 use crate::bit_reader::{BitReader, BitSequence};
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub fn decode_litlen_distance_trees<T: BufRead>(
+// See RFC 1951, section 3.2.5.
+pub const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+pub const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// Deflate64 gives length code 285 16 extra bits and adds distance codes 30
+// and 31 for its 64 KiB window.
+pub const DEFLATE64_LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 3,
+];
+pub const DEFLATE64_LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 16,
+];
+pub const DEFLATE64_DISTANCE_BASE: [u16; 32] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577, 32769, 49153,
+];
+pub const DEFLATE64_DISTANCE_EXTRA_BITS: [u8; 32] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13, 14, 14,
+];
+
+// See RFC 1951, section 3.2.7.
+pub const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Reads the code lengths of a dynamic block of a format with
+/// `distance_symbols` distance codes. In `strict` mode, the header is
+/// rejected in every case zlib rejects it.
+pub fn decode_litlen_distance_lengths<T: BufRead>(
     bit_reader: &mut BitReader<T>,
-) -> Result<(HuffmanCoding<LitLenToken>, HuffmanCoding<DistanceToken>)> {
+    distance_symbols: usize,
+    strict: bool,
+) -> Result<(Vec<u8>, Vec<u8>)> {
     // See RFC 1951, section 3.2.7.
-    let litlen_size = bit_reader.read_bits(5)?.bits() + 257;
-    let distance_size = bit_reader.read_bits(5)?.bits() + 1;
-    let codelen_size = bit_reader.read_bits(4)?.bits() + 4;
-
-    let mut code_lengths = Vec::<u8>::new();
-    code_lengths.resize(19, 0);
-    const MAP: [usize; 19] = [
-        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
-    ];
-    for i in 0..codelen_size {
-        code_lengths[MAP[i as usize]] = bit_reader.read_bits(3)?.bits() as u8;
+    let litlen_size = bit_reader.read_bits(5)?.bits() as usize + 257;
+    let distance_size = bit_reader.read_bits(5)?.bits() as usize + 1;
+    let codelen_size = bit_reader.read_bits(4)?.bits() as usize + 4;
+    if strict {
+        ensure!(
+            litlen_size <= 286 && distance_size <= distance_symbols,
+            "too many length or distance symbols"
+        );
     }
 
+    let mut code_lengths = vec![0_u8; 19];
+    for symbol in &CODE_LENGTH_ORDER[..codelen_size] {
+        code_lengths[*symbol] = bit_reader.read_bits(3)?.bits() as u8;
+    }
+    if strict {
+        ensure!(
+            code_completeness(&code_lengths) == Completeness::Complete,
+            "invalid code lengths set"
+        );
+    }
     let code_decoder = HuffmanCoding::<TreeCodeToken>::from_lengths(&code_lengths)?;
-    let mut litlen_codes = Vec::with_capacity(litlen_size as usize);
-    while litlen_codes.len() < litlen_size as usize {
-        let symbol = code_decoder.read_symbol(bit_reader)?;
-        match symbol {
-            TreeCodeToken::Length(len) => {
-                litlen_codes.push(len);
-            }
-            TreeCodeToken::CopyPrev => {
-                let cnt = bit_reader.read_bits(2)?.bits() + 3;
-                for _j in 0..cnt {
-                    litlen_codes.push(
-                        *litlen_codes
-                            .last()
-                            .context("Trying to repeat empty buffer")?,
-                    );
-                }
+
+    // Both code length sequences form a single one, so a repeat can run from
+    // the literal/length lengths into the distance lengths.
+    let total_size = litlen_size + distance_size;
+    let mut lengths = Vec::with_capacity(total_size);
+    while lengths.len() < total_size {
+        let (len, count) = match code_decoder.read_symbol(bit_reader)? {
+            TreeCodeToken::Length(len) => (len, 1),
+            TreeCodeToken::CopyPrev { base, extra_bits } => (
+                *lengths.last().context("Trying to repeat empty buffer")?,
+                base + bit_reader.read_bits(extra_bits)?.bits(),
+            ),
+            TreeCodeToken::RepeatZero { base, extra_bits } => {
+                (0, base + bit_reader.read_bits(extra_bits)?.bits())
             }
-            TreeCodeToken::RepeatZero {
-                base,
-                extra_bits: _,
-            } => match base {
-                17 => {
-                    let cnt = bit_reader.read_bits(3)?.bits() + 3;
-                    for _j in 0..cnt {
-                        litlen_codes.push(0);
-                    }
-                }
-                18 => {
-                    let cnt = bit_reader.read_bits(7)?.bits() + 11;
-                    for _j in 0..cnt {
-                        litlen_codes.push(0);
-                    }
-                }
-                _ => unreachable!(),
-            },
+        };
+        ensure!(
+            lengths.len() + count as usize <= total_size,
+            "code length repeat is out of bounds"
+        );
+        lengths.extend(repeat_n(len, count as usize));
+    }
+
+    let distance_lengths = lengths.split_off(litlen_size);
+    if strict {
+        ensure!(lengths[256] != 0, "invalid code -- missing end-of-block");
+        ensure!(is_acceptable_tree(&lengths), "invalid literal/lengths set");
+        ensure!(
+            is_acceptable_tree(&distance_lengths),
+            "invalid distances set"
+        );
+    }
+    Ok((lengths, distance_lengths))
+}
+
+/// Code lengths of the literal/length and distance codes of fixed Huffman
+/// blocks, see RFC 1951, section 3.2.6.
+pub fn fixed_code_lengths() -> (Vec<u8>, Vec<u8>) {
+    let litlen_lengths = repeat_n(8, 144)
+        .chain(repeat_n(9, 112))
+        .chain(repeat_n(7, 24))
+        .chain(repeat_n(8, 8))
+        .collect();
+    (litlen_lengths, vec![5; 32])
+}
+
+/// Like zlib, accepts incomplete literal/length and distance codes only when
+/// they consist of a single one-bit code.
+fn is_acceptable_tree(lengths: &[u8]) -> bool {
+    match code_completeness(lengths) {
+        Completeness::Complete | Completeness::Empty => true,
+        Completeness::Incomplete => lengths.iter().all(|len| *len <= 1),
+        Completeness::OverSubscribed => false,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Completeness {
+    /// Every bit sequence starts with a code.
+    Complete,
+    /// Some bit sequences start with no code.
+    Incomplete,
+    /// There are more codes of some length than fit in a prefix code.
+    OverSubscribed,
+    /// All lengths are zero.
+    Empty,
+}
+
+/// Checks whether `lengths` describe a complete prefix code (Kraft sum of 1).
+pub fn code_completeness(lengths: &[u8]) -> Completeness {
+    let mut length_counts = [0_i32; MAX_BITS + 1];
+    for len in lengths {
+        length_counts[*len as usize] += 1;
+    }
+    if length_counts[0] as usize == lengths.len() {
+        return Completeness::Empty;
+    }
+
+    // Number of unused codes of the current length.
+    let mut left = 1_i32;
+    for count in &length_counts[1..] {
+        left = (left << 1) - count;
+        if left < 0 {
+            return Completeness::OverSubscribed;
         }
     }
+    if left > 0 {
+        Completeness::Incomplete
+    } else {
+        Completeness::Complete
+    }
+}
 
-    let code_decoder = HuffmanCoding::<TreeCodeToken>::from_lengths(&code_lengths)?;
-    let mut distance_codes = Vec::with_capacity(distance_size as usize);
-    while distance_codes.len() < distance_size as usize {
-        let symbol = code_decoder.read_symbol(bit_reader)?;
-        match symbol {
-            TreeCodeToken::Length(len) => {
-                distance_codes.push(len);
-            }
-            TreeCodeToken::CopyPrev => {
-                let cnt = bit_reader.read_bits(2)?.bits() + 3;
-                for _j in 0..cnt {
-                    distance_codes.push(
-                        *distance_codes
-                            .last()
-                            .context("Trying to repeat empty buffer")?,
-                    );
-                }
-            }
-            TreeCodeToken::RepeatZero { base, extra_bits } => match base {
-                17 => {
-                    let cnt = bit_reader.read_bits(extra_bits)?.bits() + 3;
-                    for _j in 0..cnt {
-                        distance_codes.push(0);
-                    }
-                }
-                18 => {
-                    let cnt = bit_reader.read_bits(extra_bits)?.bits() + 11;
-                    for _j in 0..cnt {
-                        distance_codes.push(0);
-                    }
-                }
-                _ => unreachable!(),
-            },
+/// Builds the lengths of an optimal prefix code for symbols with the given
+/// frequencies, with no code longer than `max_len` bits, using the
+/// package-merge algorithm. Unused symbols get no code.
+///
+/// The code is always complete: when fewer than two symbols are used, unused
+/// ones get one-bit codes too, as zlib rejects incomplete code length codes.
+pub fn lengths_from_frequencies(frequencies: &[u32], max_len: u8) -> Vec<u8> {
+    assert!(frequencies.len() >= 2, "a code needs at least two symbols");
+    let mut leaves: Vec<(u64, usize)> = frequencies
+        .iter()
+        .enumerate()
+        .filter(|(_, frequency)| **frequency > 0)
+        .map(|(symbol, frequency)| (*frequency as u64, symbol))
+        .collect();
+    let mut unused = (0..)
+        .zip(frequencies)
+        .filter(|(_, frequency)| **frequency == 0);
+    while leaves.len() < 2 {
+        let (symbol, _) = unused.next().expect("there are at least two symbols");
+        leaves.push((0, symbol));
+    }
+    assert!(
+        max_len >= 1 && leaves.len() <= 1 << max_len,
+        "too many symbols for {}-bit codes",
+        max_len
+    );
+    leaves.sort_unstable();
+
+    // Every item is a leaf or a package of two items of the next longer
+    // length, with the symbols it covers. After `max_len` rounds, each
+    // selected item containing a symbol adds a bit to the symbol's length.
+    let leaf_items: Vec<(u64, Vec<usize>)> = leaves
+        .iter()
+        .map(|(weight, symbol)| (*weight, vec![*symbol]))
+        .collect();
+    let mut items = leaf_items.clone();
+    for _ in 1..max_len {
+        let packages = items.chunks_exact(2).map(|pair| {
+            let symbols = [pair[0].1.as_slice(), pair[1].1.as_slice()].concat();
+            (pair[0].0 + pair[1].0, symbols)
+        });
+        items = merge_by_weight(&leaf_items, packages);
+    }
+
+    let mut lengths = vec![0; frequencies.len()];
+    for (_, symbols) in &items[..2 * leaves.len() - 2] {
+        for symbol in symbols {
+            lengths[*symbol] += 1;
         }
     }
+    lengths
+}
 
-    Ok((
-        HuffmanCoding::<LitLenToken>::from_lengths(&litlen_codes)?,
-        HuffmanCoding::<DistanceToken>::from_lengths(&distance_codes)?,
-    ))
+/// Merges leaves and packages, both sorted by weight, preferring leaves on
+/// ties.
+fn merge_by_weight(
+    leaves: &[(u64, Vec<usize>)],
+    packages: impl Iterator<Item = (u64, Vec<usize>)>,
+) -> Vec<(u64, Vec<usize>)> {
+    let mut merged = Vec::with_capacity(leaves.len() * 2);
+    let mut leaves = leaves.iter().peekable();
+    for package in packages {
+        while let Some(leaf) = leaves.next_if(|leaf| leaf.0 <= package.0) {
+            merged.push(leaf.clone());
+        }
+        merged.push(package);
+    }
+    merged.extend(leaves.cloned());
+    merged
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -111,7 +249,7 @@ pub fn decode_litlen_distance_trees<T: BufRead>(
 #[derive(Clone, Copy, Debug)]
 pub enum TreeCodeToken {
     Length(u8),
-    CopyPrev,
+    CopyPrev { base: u16, extra_bits: u8 },
     RepeatZero { base: u16, extra_bits: u8 },
 }
 
@@ -120,23 +258,22 @@ impl TryFrom<HuffmanCodeWord> for TreeCodeToken {
 
     fn try_from(value: HuffmanCodeWord) -> Result<Self> {
         // See RFC 1951, section 3.2.7.
-        if (0..=15).contains(&value.0) {
-            Ok(Self::Length(value.0 as u8))
-        } else if 16 == value.0 {
-            Ok(Self::CopyPrev)
-        } else if 17 == value.0 {
-            Ok(Self::RepeatZero {
-                base: value.0,
+        Ok(match value.0 {
+            0..=15 => Self::Length(value.0 as u8),
+            16 => Self::CopyPrev {
+                base: 3,
+                extra_bits: 2,
+            },
+            17 => Self::RepeatZero {
+                base: 3,
                 extra_bits: 3,
-            })
-        } else if 18 == value.0 {
-            Ok(Self::RepeatZero {
-                base: value.0,
+            },
+            18 => Self::RepeatZero {
+                base: 11,
                 extra_bits: 7,
-            })
-        } else {
-            bail!("Unable to decode TreeCodeToken")
-        }
+            },
+            _ => bail!("Unable to decode TreeCodeToken"),
+        })
     }
 }
 
@@ -146,7 +283,11 @@ impl TryFrom<HuffmanCodeWord> for TreeCodeToken {
 pub enum LitLenToken {
     Literal(u8),
     EndOfBlock,
-    Length { base: u16, extra_bits: u8 },
+    /// A length code as an index into the length tables, which differ
+    /// between deflate and Deflate64.
+    Length(u8),
+    /// Symbols 286 and 287 take part in the code but never occur in the data.
+    Reserved,
 }
 
 impl TryFrom<HuffmanCodeWord> for LitLenToken {
@@ -154,54 +295,30 @@ impl TryFrom<HuffmanCodeWord> for LitLenToken {
 
     fn try_from(value: HuffmanCodeWord) -> Result<Self> {
         // See RFC 1951, section 3.2.5.
-        if (0..=255).contains(&value.0) {
-            Ok(Self::Literal(value.0 as u8))
-        } else if 256 == value.0 {
-            Ok(Self::EndOfBlock)
-        } else if (257..=285).contains(&value.0) {
-            if (257..=264).contains(&value.0) || 285 == value.0 {
-                Ok(Self::Length {
-                    base: value.0,
-                    extra_bits: 0,
-                })
-            } else {
-                Ok(Self::Length {
-                    base: value.0,
-                    extra_bits: ((value.0 - 261) / 4) as u8,
-                })
-            }
-        } else {
-            bail!("Unable to decode LitLetToken")
-        }
+        Ok(match value.0 {
+            0..=255 => Self::Literal(value.0 as u8),
+            256 => Self::EndOfBlock,
+            257..=285 => Self::Length((value.0 - 257) as u8),
+            286..=287 => Self::Reserved,
+            _ => bail!("Unable to decode LitLetToken"),
+        })
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// A distance code, valid if the format has an entry for it in its distance
+/// tables. Codes 30 and 31 take part in the code of deflate but never occur
+/// in the data.
 #[derive(Clone, Copy, Debug)]
-pub struct DistanceToken {
-    pub base: u16,
-    pub extra_bits: u8,
-}
+pub struct DistanceToken(pub u8);
 
 impl TryFrom<HuffmanCodeWord> for DistanceToken {
     type Error = anyhow::Error;
 
     fn try_from(value: HuffmanCodeWord) -> Result<Self> {
-        // See RFC 1951, section 3.2.5.
-        if (0..=3).contains(&value.0) {
-            Ok(Self {
-                base: value.0,
-                extra_bits: 0,
-            })
-        } else if (4..=29).contains(&value.0) {
-            Ok(Self {
-                base: value.0,
-                extra_bits: ((value.0 - 2) / 2) as u8,
-            })
-        } else {
-            bail!("Unable to decode DistanceToken")
-        }
+        ensure!(value.0 < 32, "Unable to decode DistanceToken");
+        Ok(Self(value.0 as u8))
     }
 }
 
@@ -241,42 +358,65 @@ where
     }
 
     pub fn from_lengths(code_lengths: &[u8]) -> Result<Self> {
-        // See RFC 1951, section 3.2.2.
-        let mut length_counts = Vec::new();
-        length_counts.resize(
-            *code_lengths
-                .iter()
-                .max()
-                .context("Unable to find largest code length")? as usize
-                + 1,
-            0,
+        ensure!(
+            !code_lengths.is_empty(),
+            "Unable to find largest code length"
         );
-        for len in code_lengths {
-            length_counts[*len as usize] += 1;
-        }
-        length_counts[0] = 0;
-
-        let mut code = 0_u16;
-        let mut next_codes = Vec::new();
-        next_codes.resize(length_counts.len(), 0);
-        for i in 1..next_codes.len() {
-            code = (code + length_counts[i - 1]) << 1;
-            next_codes[i] = code;
+        let mut map = HashMap::new();
+        for (i, code) in (0..).zip(canonical_codes(code_lengths)) {
+            if code.len() != 0 {
+                map.insert(code, HuffmanCodeWord(i).try_into()?);
+            }
         }
+        Ok(Self { map })
+    }
+}
 
-        let mut map = HashMap::new();
-        for (i, len) in (0..).zip(code_lengths) {
+/// Codes of the canonical Huffman code with the given lengths, first bit
+/// highest. Unused symbols get empty codes.
+pub fn canonical_codes(code_lengths: &[u8]) -> Vec<BitSequence> {
+    // See RFC 1951, section 3.2.2.
+    let max_len = code_lengths.iter().copied().max().unwrap_or(0) as usize;
+    let mut length_counts = vec![0; max_len + 1];
+    for len in code_lengths {
+        length_counts[*len as usize] += 1;
+    }
+    length_counts[0] = 0;
+
+    let mut code = 0_u16;
+    let mut next_codes = vec![0; length_counts.len()];
+    for i in 1..next_codes.len() {
+        code = (code + length_counts[i - 1]) << 1;
+        next_codes[i] = code;
+    }
+
+    code_lengths
+        .iter()
+        .map(|len| {
+            let code = BitSequence::new(next_codes[*len as usize], *len);
             if *len != 0 {
-                map.insert(
-                    BitSequence::new(next_codes[*len as usize], *len),
-                    HuffmanCodeWord(i).try_into()?,
-                );
                 next_codes[*len as usize] += 1;
             }
-        }
+            code
+        })
+        .collect()
+}
 
-        Ok(Self { map })
-    }
+/// Codes of the canonical Huffman code with the given lengths, bit-reversed
+/// so that a `BitWriter` writes them first bit first.
+pub fn encoding_codes(code_lengths: &[u8]) -> Vec<BitSequence> {
+    canonical_codes(code_lengths)
+        .into_iter()
+        .map(|code| {
+            let len = code.len();
+            let bits = if len == 0 {
+                0
+            } else {
+                code.bits().reverse_bits() >> (16 - len)
+            };
+            BitSequence::new(bits, len)
+        })
+        .collect()
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -284,6 +424,8 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bit_writer::BitWriter;
+    use proptest::prelude::*;
 
     #[derive(Clone, Copy, Debug, PartialEq)]
     struct Value(u16);
@@ -421,4 +563,336 @@ mod tests {
 
         Ok(())
     }
+
+    /// Writes a dynamic block header whose code length code gives 3-bit codes
+    /// to the symbols 0, 5, 8, 16, 17 and 18, followed by `symbols`.
+    fn dynamic_header(
+        litlen_size: u16,
+        distance_size: u16,
+        symbols: &[(u16, u16)],
+    ) -> std::io::Result<Vec<u8>> {
+        dynamic_header_with(&[0, 5, 8, 16, 17, 18], litlen_size, distance_size, symbols)
+    }
+
+    /// The code length symbols of a complete code length code of 3-bit codes.
+    const COMPLETE_CODE: [u16; 8] = [0, 1, 5, 7, 8, 16, 17, 18];
+
+    /// Writes a dynamic block header whose code length code gives 3-bit codes
+    /// to the sorted `code_symbols`, followed by `symbols`.
+    fn dynamic_header_with(
+        code_symbols: &[u16],
+        litlen_size: u16,
+        distance_size: u16,
+        symbols: &[(u16, u16)],
+    ) -> std::io::Result<Vec<u8>> {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.write_bits(BitSequence::new(litlen_size - 257, 5))?;
+        writer.write_bits(BitSequence::new(distance_size - 1, 5))?;
+        writer.write_bits(BitSequence::new(19 - 4, 4))?;
+        for symbol in &CODE_LENGTH_ORDER {
+            let len = if code_symbols.contains(&(*symbol as u16)) {
+                3
+            } else {
+                0
+            };
+            writer.write_bits(BitSequence::new(len, 3))?;
+        }
+        for (symbol, extra) in symbols {
+            // Canonical codes of the same length follow the order of the symbols.
+            let code = code_symbols.iter().position(|s| s == symbol).unwrap() as u16;
+            let extra_bits = match symbol {
+                16 => 2,
+                17 => 3,
+                18 => 7,
+                _ => 0,
+            };
+            // Huffman codes are packed starting with the most significant bit.
+            let reversed = (0..3).fold(0, |acc, i| acc | ((code >> i) & 1) << (2 - i));
+            writer.write_bits(BitSequence::new(reversed, 3))?;
+            writer.write_bits(BitSequence::new(*extra, extra_bits))?;
+        }
+        writer.finish()
+    }
+
+    /// Code length symbols for `count` lengths `len`, with repeats.
+    fn lengths_run(len: u16, mut count: u16) -> Vec<(u16, u16)> {
+        let mut symbols = Vec::new();
+        if len == 0 {
+            while count >= 11 {
+                let repeat = count.min(138);
+                symbols.push((18, repeat - 11));
+                count -= repeat;
+            }
+            if count >= 3 {
+                symbols.push((17, count - 3));
+                count = 0;
+            }
+        } else {
+            symbols.push((len, 0));
+            count -= 1;
+            while count >= 3 {
+                let repeat = count.min(6);
+                symbols.push((16, repeat - 3));
+                count -= repeat;
+            }
+        }
+        symbols.extend(repeat_n((len, 0), count as usize));
+        symbols
+    }
+
+    /// 256 literal/length codes of 8 bits, with the end-of-block code.
+    fn complete_litlen() -> Vec<(u16, u16)> {
+        [lengths_run(8, 255), lengths_run(0, 1), lengths_run(8, 1)].concat()
+    }
+
+    /// Checks that only strict mode rejects `data` with `msg`, and returns the
+    /// lengths decoded without it.
+    fn check_strict(data: &[u8], msg: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+        let mut reader = BitReader::new(data);
+        let err =
+            decode_litlen_distance_lengths(&mut reader, DISTANCE_BASE.len(), true).unwrap_err();
+        assert_eq!(err.to_string(), msg);
+        let mut reader = BitReader::new(data);
+        decode_litlen_distance_lengths(&mut reader, DISTANCE_BASE.len(), false)
+    }
+
+    #[test]
+    fn code_lengths_repeat_across_trees() -> Result<()> {
+        // 8 + 42 repeats of 6 + 1 repeat of 6 = 259 = 257 + 2.
+        let mut symbols = vec![(8, 0)];
+        symbols.extend(repeat_n((16, 3), 43));
+        let data = dynamic_header(257, 2, &symbols)?;
+        let mut reader = BitReader::new(data.as_slice());
+
+        let (litlen_lengths, distance_lengths) =
+            decode_litlen_distance_lengths(&mut reader, DISTANCE_BASE.len(), false)?;
+        assert_eq!(litlen_lengths, vec![8; 257]);
+        assert_eq!(distance_lengths, vec![8; 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn code_lengths_repeat_out_of_bounds() -> Result<()> {
+        // 138 + 119 zeros, then 5 + 10 zeros for 258 + 2 lengths.
+        let data = dynamic_header(258, 2, &[(18, 127), (18, 108), (5, 0), (17, 7)])?;
+        let mut reader = BitReader::new(data.as_slice());
+        assert!(decode_litlen_distance_lengths(&mut reader, DISTANCE_BASE.len(), false).is_err());
+
+        let data = dynamic_header(258, 2, &[(18, 127), (18, 108), (5, 0), (5, 0), (5, 0)])?;
+        let mut reader = BitReader::new(data.as_slice());
+        let (litlen_lengths, distance_lengths) =
+            decode_litlen_distance_lengths(&mut reader, DISTANCE_BASE.len(), false)?;
+        assert_eq!(litlen_lengths.iter().filter(|len| **len != 0).count(), 1);
+        assert_eq!(distance_lengths, vec![5, 5]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn completeness() {
+        assert_eq!(
+            code_completeness(&[2, 3, 4, 3, 3, 4, 2]),
+            Completeness::Complete
+        );
+        assert_eq!(code_completeness(&[1, 2, 0, 3]), Completeness::Incomplete);
+        assert_eq!(code_completeness(&[1, 1, 1]), Completeness::OverSubscribed);
+        assert_eq!(code_completeness(&[0, 0]), Completeness::Empty);
+    }
+
+    /// Checks that `lengths` form a complete prefix code covering every used
+    /// symbol, and returns its cost in bits.
+    fn check_lengths(frequencies: &[u32], lengths: &[u8], max_len: u8) -> Result<u64> {
+        assert_eq!(lengths.len(), frequencies.len());
+        assert!(lengths.iter().all(|len| *len <= max_len));
+        for (frequency, len) in frequencies.iter().zip(lengths) {
+            assert!(*frequency == 0 || *len > 0);
+        }
+        assert_eq!(code_completeness(lengths), Completeness::Complete);
+
+        let coding = HuffmanCoding::<Value>::from_lengths(lengths)?;
+        let codes: Vec<_> = coding.map.keys().collect();
+        assert_eq!(codes.len(), lengths.iter().filter(|len| **len > 0).count());
+        for a in &codes {
+            for b in &codes {
+                let is_prefix = a.len() < b.len() && b.bits() >> (b.len() - a.len()) == a.bits();
+                assert!(!is_prefix, "{:?} is a prefix of {:?}", a, b);
+            }
+        }
+
+        Ok(frequencies
+            .iter()
+            .zip(lengths)
+            .map(|(frequency, len)| *frequency as u64 * *len as u64)
+            .sum())
+    }
+
+    /// Cost and longest code of an unrestricted Huffman code.
+    fn huffman_cost(frequencies: &[u32]) -> (u64, u8) {
+        use std::cmp::Reverse;
+        use std::collections::BinaryHeap;
+
+        let mut heap: BinaryHeap<_> = frequencies
+            .iter()
+            .filter(|frequency| **frequency > 0)
+            .map(|frequency| Reverse((*frequency as u64, 0_u8)))
+            .collect();
+        // Like a deflate code, it has at least two symbols.
+        while heap.len() < 2 {
+            heap.push(Reverse((0, 0)));
+        }
+        let (mut cost, mut depth) = (0, 0);
+        while heap.len() > 1 {
+            let Reverse((weight_a, depth_a)) = heap.pop().unwrap();
+            let Reverse((weight_b, depth_b)) = heap.pop().unwrap();
+            cost += weight_a + weight_b;
+            depth = depth_a.max(depth_b) + 1;
+            heap.push(Reverse((weight_a + weight_b, depth)));
+        }
+        (cost, depth)
+    }
+
+    fn frequency() -> impl Strategy<Value = u32> {
+        prop_oneof![
+            Just(0_u32),
+            1..1000_u32,
+            // Exponential frequencies need codes longer than 15 bits.
+            (0..24_u32).prop_map(|exp| 1 << exp),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn lengths_from_frequencies_litlen(
+            frequencies in prop::collection::vec(frequency(), 2..=288),
+        ) {
+            let lengths = lengths_from_frequencies(&frequencies, 15);
+            let cost = check_lengths(&frequencies, &lengths, 15).unwrap();
+            let (optimal_cost, depth) = huffman_cost(&frequencies);
+            prop_assert!(cost >= optimal_cost);
+            if depth <= 15 {
+                prop_assert_eq!(cost, optimal_cost);
+            }
+        }
+
+        #[test]
+        fn lengths_from_frequencies_code_lengths(
+            frequencies in prop::collection::vec(frequency(), 2..=19),
+        ) {
+            let lengths = lengths_from_frequencies(&frequencies, 7);
+            let cost = check_lengths(&frequencies, &lengths, 7).unwrap();
+            let (optimal_cost, depth) = huffman_cost(&frequencies);
+            if depth <= 7 {
+                prop_assert_eq!(cost, optimal_cost);
+            }
+        }
+    }
+
+    #[test]
+    fn length_limit() -> Result<()> {
+        // Fibonacci frequencies give a Huffman code of depth 19.
+        let mut frequencies = vec![1_u32, 1];
+        while frequencies.len() < 20 {
+            frequencies
+                .push(frequencies[frequencies.len() - 1] + frequencies[frequencies.len() - 2]);
+        }
+        assert_eq!(huffman_cost(&frequencies).1, 19);
+        let lengths = lengths_from_frequencies(&frequencies, 15);
+        check_lengths(&frequencies, &lengths, 15)?;
+        assert_eq!(lengths.iter().max(), Some(&15));
+        Ok(())
+    }
+
+    #[test]
+    fn few_symbols() -> Result<()> {
+        assert_eq!(lengths_from_frequencies(&[0, 0, 0], 7), vec![1, 1, 0]);
+        assert_eq!(lengths_from_frequencies(&[0, 5, 0], 7), vec![1, 1, 0]);
+        assert_eq!(lengths_from_frequencies(&[5, 0, 0], 15), vec![1, 1, 0]);
+        assert_eq!(lengths_from_frequencies(&[3, 3, 3, 3], 2), vec![2; 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn strict_code_lengths() -> Result<()> {
+        // The code length code uses 6 of the 8 three-bit codes.
+        let mut symbols = vec![(8, 0)];
+        symbols.extend(repeat_n((16, 3), 43));
+        let data = dynamic_header(257, 2, &symbols)?;
+        let (litlen_lengths, _) = check_strict(&data, "invalid code lengths set")?;
+        assert_eq!(litlen_lengths, vec![8; 257]);
+
+        let data = dynamic_header(287, 2, &[(18, 127), (18, 127), (18, 2)])?;
+        let (litlen_lengths, _) = check_strict(&data, "too many length or distance symbols")?;
+        assert_eq!(litlen_lengths, vec![0; 287]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn strict_missing_end_of_block() -> Result<()> {
+        let symbols = [lengths_run(8, 256), lengths_run(0, 3)].concat();
+        let data = dynamic_header_with(&COMPLETE_CODE, 257, 2, &symbols)?;
+        let (litlen_lengths, distance_lengths) =
+            check_strict(&data, "invalid code -- missing end-of-block")?;
+        assert_eq!(litlen_lengths[..256], [8; 256]);
+        assert_eq!(litlen_lengths[256], 0);
+        assert_eq!(distance_lengths, vec![0; 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn strict_literal_lengths_set() -> Result<()> {
+        // Over-subscribed: 257 codes of 8 bits.
+        let symbols = [lengths_run(8, 257), lengths_run(0, 2)].concat();
+        let data = dynamic_header_with(&COMPLETE_CODE, 257, 2, &symbols)?;
+        let (litlen_lengths, _) = check_strict(&data, "invalid literal/lengths set")?;
+        assert_eq!(litlen_lengths, vec![8; 257]);
+
+        // Incomplete: 129 codes of 8 bits.
+        let symbols = [
+            lengths_run(8, 128),
+            lengths_run(0, 128),
+            lengths_run(8, 1),
+            lengths_run(0, 2),
+        ]
+        .concat();
+        let data = dynamic_header_with(&COMPLETE_CODE, 257, 2, &symbols)?;
+        let (litlen_lengths, _) = check_strict(&data, "invalid literal/lengths set")?;
+        assert_eq!(litlen_lengths[..128], [8; 128]);
+        assert_eq!(litlen_lengths[128..256], [0; 128]);
+        assert_eq!(litlen_lengths[256], 8);
+        Ok(())
+    }
+
+    #[test]
+    fn strict_distances_set() -> Result<()> {
+        // Over-subscribed: 3 codes of 1 bit.
+        let symbols = [complete_litlen(), lengths_run(1, 3)].concat();
+        let data = dynamic_header_with(&COMPLETE_CODE, 257, 3, &symbols)?;
+        let (_, distance_lengths) = check_strict(&data, "invalid distances set")?;
+        assert_eq!(distance_lengths, vec![1; 3]);
+
+        // Incomplete: 2 codes of 5 bits.
+        let symbols = [complete_litlen(), lengths_run(5, 2)].concat();
+        let data = dynamic_header_with(&COMPLETE_CODE, 257, 2, &symbols)?;
+        let (_, distance_lengths) = check_strict(&data, "invalid distances set")?;
+        assert_eq!(distance_lengths, vec![5; 2]);
+
+        // Like zlib, a single code of 1 bit is fine, as are no distances at all.
+        for distances in [[1, 0], [0, 1], [0, 0]] {
+            let symbols = [
+                complete_litlen(),
+                lengths_run(distances[0], 1),
+                lengths_run(distances[1], 1),
+            ]
+            .concat();
+            let data = dynamic_header_with(&COMPLETE_CODE, 257, 2, &symbols)?;
+            let mut reader = BitReader::new(data.as_slice());
+            let (litlen_lengths, distance_lengths) =
+                decode_litlen_distance_lengths(&mut reader, DISTANCE_BASE.len(), true)?;
+            assert_eq!(litlen_lengths[256], 8);
+            assert_eq!(distance_lengths, distances.map(|len| len as u8));
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,361 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+
+use anyhow::{ensure, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::bit_reader::{BitReader, CountingReader};
+use crate::deflate::DeflateReader;
+use crate::gzip::{CompressionMethod, MemberReader};
+use crate::tracking_writer::HISTORY_SIZE;
+
+////////////////////////////////////////////////////////////////////////////////
+
+const INDEX_MAGIC: &[u8; 8] = b"RGZINDX1";
+
+/// Default distance between checkpoints in uncompressed bytes (1 MiB).
+pub const DEFAULT_SPAN: u64 = 1 << 20;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A block boundary where decompression can be resumed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Position of the boundary in the compressed stream, in bits.
+    pub bit_offset: u64,
+    /// Position of the first byte decoded after the boundary.
+    pub output_offset: u64,
+    /// Output of the current member preceding the boundary (up to 32 KiB).
+    pub window: Vec<u8>,
+}
+
+/// Random access index of a gzip file, see zlib's `examples/zran.c`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GzipIndex {
+    span: u64,
+    output_size: u64,
+    checkpoints: Vec<Checkpoint>,
+}
+
+impl GzipIndex {
+    /// Decompresses the whole `input`, recording a checkpoint at the first
+    /// block boundary after every `span` bytes of output.
+    pub fn build<R: BufRead>(input: R, span: u64) -> Result<Self> {
+        ensure!(span > 0, "checkpoint span must be positive");
+        let mut input = CountingReader::new(input);
+        let mut checkpoints = Vec::new();
+        let mut output_offset = 0;
+        let mut last_checkpoint = 0;
+
+        while !input.fill_buf()?.is_empty() {
+            let (header, mut deflate_reader) = MemberReader::new(input).into_deflate_reader()?;
+            ensure!(
+                header.compression_method == CompressionMethod::Deflate,
+                "unsupported compression method"
+            );
+            while let Some(block) = deflate_reader.next_block() {
+                output_offset += block?.len() as u64;
+                if !deflate_reader.is_finished() && output_offset - last_checkpoint >= span {
+                    checkpoints.push(Checkpoint {
+                        bit_offset: deflate_reader.bit_reader().position(),
+                        output_offset,
+                        window: deflate_reader.tracker().window(),
+                    });
+                    last_checkpoint = output_offset;
+                }
+            }
+            let (reader, writer) = deflate_reader.into_inners();
+            input = reader;
+            MemberReader::read_footer(&mut input)?.check(writer)?;
+        }
+
+        Ok(Self {
+            span,
+            output_size: output_offset,
+            checkpoints,
+        })
+    }
+
+    pub fn span(&self) -> u64 {
+        self.span
+    }
+
+    /// Total size of the decompressed data.
+    pub fn output_size(&self) -> u64 {
+        self.output_size
+    }
+
+    pub fn checkpoints(&self) -> &[Checkpoint] {
+        &self.checkpoints
+    }
+
+    /// Returns the last checkpoint at or before `offset`, if any.
+    pub fn checkpoint_for(&self, offset: u64) -> Option<&Checkpoint> {
+        let pos = self
+            .checkpoints
+            .partition_point(|checkpoint| checkpoint.output_offset <= offset);
+        pos.checked_sub(1).map(|pos| &self.checkpoints[pos])
+    }
+
+    /// Serializes the index, e.g. into a sidecar file.
+    pub fn write_to<W: Write>(&self, mut output: W) -> Result<()> {
+        output.write_all(INDEX_MAGIC)?;
+        output.write_u64::<LittleEndian>(self.span)?;
+        output.write_u64::<LittleEndian>(self.output_size)?;
+        output.write_u64::<LittleEndian>(self.checkpoints.len() as u64)?;
+        for checkpoint in &self.checkpoints {
+            output.write_u64::<LittleEndian>(checkpoint.bit_offset)?;
+            output.write_u64::<LittleEndian>(checkpoint.output_offset)?;
+            output.write_u32::<LittleEndian>(checkpoint.window.len() as u32)?;
+            output.write_all(&checkpoint.window)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut input: R) -> Result<Self> {
+        let mut magic = [0; INDEX_MAGIC.len()];
+        input.read_exact(&mut magic)?;
+        ensure!(&magic == INDEX_MAGIC, "not a gzip index");
+        let span = input.read_u64::<LittleEndian>()?;
+        let output_size = input.read_u64::<LittleEndian>()?;
+        let count = input.read_u64::<LittleEndian>()?;
+
+        let mut checkpoints = Vec::new();
+        for _i in 0..count {
+            let bit_offset = input.read_u64::<LittleEndian>()?;
+            let output_offset = input.read_u64::<LittleEndian>()?;
+            let window_len = input.read_u32::<LittleEndian>()? as usize;
+            ensure!(window_len <= HISTORY_SIZE, "checkpoint window is too large");
+            let mut window = vec![0; window_len];
+            input.read_exact(&mut window)?;
+            checkpoints.push(Checkpoint {
+                bit_offset,
+                output_offset,
+                window,
+            });
+        }
+        ensure!(
+            checkpoints
+                .windows(2)
+                .all(|pair| pair[0].output_offset < pair[1].output_offset),
+            "checkpoints are not sorted"
+        );
+
+        Ok(Self {
+            span,
+            output_size,
+            checkpoints,
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Inflater<R> {
+    deflate_reader: DeflateReader<BufReader<R>>,
+    block: Vec<u8>,
+    block_pos: usize,
+    // Output offset of `block[block_pos]`.
+    offset: u64,
+}
+
+impl<R: Read + Seek> Inflater<R> {
+    fn start(mut inner: R, checkpoint: Option<&Checkpoint>) -> Result<Self> {
+        let (deflate_reader, offset) = match checkpoint {
+            Some(checkpoint) => {
+                inner.seek(SeekFrom::Start(checkpoint.bit_offset / 8))?;
+                let mut bit_reader = BitReader::new(BufReader::new(inner));
+                bit_reader.read_bits((checkpoint.bit_offset % 8) as u8)?;
+                (
+                    DeflateReader::resume(bit_reader, &checkpoint.window),
+                    checkpoint.output_offset,
+                )
+            }
+            None => {
+                inner.seek(SeekFrom::Start(0))?;
+                let (_, deflate_reader) =
+                    MemberReader::new(BufReader::new(inner)).into_deflate_reader()?;
+                (deflate_reader, 0)
+            }
+        };
+        Ok(Self {
+            deflate_reader,
+            block: Vec::new(),
+            block_pos: 0,
+            offset,
+        })
+    }
+
+    fn next_block(mut self) -> Result<Self> {
+        loop {
+            if let Some(block) = self.deflate_reader.next_block() {
+                self.block = block?;
+                self.block_pos = 0;
+                return Ok(self);
+            }
+            // The footer is not checked: the member may have been entered
+            // through a checkpoint, and the index builder has checked it.
+            let (mut input, _) = self.deflate_reader.into_inners();
+            MemberReader::read_footer(&mut input)?;
+            ensure!(
+                !input.fill_buf()?.is_empty(),
+                "unexpected end of gzip stream"
+            );
+            let (_, deflate_reader) = MemberReader::new(input).into_deflate_reader()?;
+            self.deflate_reader = deflate_reader;
+        }
+    }
+
+    fn into_inner(self) -> R {
+        self.deflate_reader.into_inners().0.into_inner()
+    }
+}
+
+enum State<R> {
+    Idle(R),
+    Inflating(Box<Inflater<R>>),
+}
+
+/// Random access reader over a gzip file described by a [`GzipIndex`].
+pub struct SeekableGzReader<R> {
+    index: GzipIndex,
+    position: u64,
+    state: Option<State<R>>,
+}
+
+impl<R: Read + Seek> SeekableGzReader<R> {
+    pub fn new(inner: R, index: GzipIndex) -> Self {
+        Self {
+            index,
+            position: 0,
+            state: Some(State::Idle(inner)),
+        }
+    }
+
+    pub fn index(&self) -> &GzipIndex {
+        &self.index
+    }
+
+    pub fn into_inner(self) -> Option<R> {
+        match self.state? {
+            State::Idle(inner) => Some(inner),
+            State::Inflating(inflater) => Some(inflater.into_inner()),
+        }
+    }
+
+    fn read_at_position(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let checkpoint = self.index.checkpoint_for(self.position);
+        let start = checkpoint.map_or(0, |checkpoint| checkpoint.output_offset);
+        let mut inflater = match self.state.take().context("reader is poisoned")? {
+            State::Inflating(inflater) if (start..=self.position).contains(&inflater.offset) => {
+                *inflater
+            }
+            State::Inflating(inflater) => Inflater::start(inflater.into_inner(), checkpoint)?,
+            State::Idle(inner) => Inflater::start(inner, checkpoint)?,
+        };
+
+        while inflater.offset + ((inflater.block.len() - inflater.block_pos) as u64)
+            <= self.position
+        {
+            inflater.offset += (inflater.block.len() - inflater.block_pos) as u64;
+            inflater.block_pos = inflater.block.len();
+            inflater = inflater.next_block()?;
+        }
+        let skip = (self.position - inflater.offset) as usize;
+        inflater.block_pos += skip;
+        inflater.offset += skip as u64;
+
+        let available = &inflater.block[inflater.block_pos..];
+        let size = available.len().min(buf.len());
+        buf[..size].copy_from_slice(&available[..size]);
+        inflater.block_pos += size;
+        inflater.offset += size as u64;
+        self.position += size as u64;
+
+        self.state = Some(State::Inflating(Box::new(inflater)));
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableGzReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.index.output_size {
+            return Ok(0);
+        }
+        self.read_at_position(buf)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableGzReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.index.output_size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek position"))?;
+        Ok(self.position)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn check_random_access(data: &[u8], span: u64) -> Result<()> {
+        let mut expected = Vec::new();
+        crate::decompress(data, &mut expected)?;
+
+        let index = GzipIndex::build(data, span)?;
+        assert_eq!(index.output_size(), expected.len() as u64);
+        assert!(!index.checkpoints().is_empty());
+
+        let mut reader = SeekableGzReader::new(Cursor::new(data), index);
+        let len = expected.len() as u64;
+        for offset in [len / 2, 7, len - 100, len / 3, len / 3 + 1000, 0] {
+            reader.seek(SeekFrom::Start(offset))?;
+            let mut buf = vec![0; 5000];
+            let size = reader.read(&mut buf)?;
+            assert!(size > 0);
+            let offset = offset as usize;
+            assert_eq!(&buf[..size], &expected[offset..offset + size]);
+        }
+
+        reader.seek(SeekFrom::End(-12345))?;
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail)?;
+        assert_eq!(tail, &expected[expected.len() - 12345..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn random_access() -> Result<()> {
+        check_random_access(
+            include_bytes!("../data/ok/06-war-and-peace.txt.gz"),
+            1 << 18,
+        )
+    }
+
+    #[test]
+    fn random_access_multi_member() -> Result<()> {
+        check_random_access(include_bytes!("../data/ok/09-concat.gz"), 1 << 16)
+    }
+
+    #[test]
+    fn serialization() -> Result<()> {
+        let data: &[u8] = include_bytes!("../data/ok/01-page.gz");
+        let index = GzipIndex::build(data, 1 << 12)?;
+        let mut buf = Vec::new();
+        index.write_to(&mut buf)?;
+        assert_eq!(GzipIndex::read_from(buf.as_slice())?, index);
+        assert!(GzipIndex::read_from(&buf[1..]).is_err());
+        Ok(())
+    }
+}
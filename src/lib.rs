@@ -1,37 +1,258 @@
 #![forbid(unsafe_code)]
 
-use std::io::{BufRead, Write};
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
 
-use anyhow::{ensure, Result};
+use anyhow::{bail, ensure, Result};
+use log::warn;
 
+use crate::bit_reader::CountingReader;
 use crate::gzip::{CompressionMethod, MemberReader};
 
+#[cfg(feature = "tokio")]
+mod async_io;
+mod bgzf;
 mod bit_reader;
+mod bit_writer;
+mod block_writer;
+mod crc32;
 mod deflate;
+mod dictzip;
+mod dump;
+mod grep;
 mod gzip;
 mod huffman_coding;
+mod index;
+mod lz77;
+mod members;
+mod resumable;
+mod rsyncable;
+#[cfg(test)]
+mod test_util;
 mod tracking_writer;
+mod verify;
+mod zip;
+mod zopfli;
 
-pub fn decompress<R: BufRead, W: Write>(mut input: R, mut output: W) -> Result<()> {
+#[cfg(feature = "tokio")]
+pub use async_io::{AsyncGzipDecoder, AsyncGzipEncoder};
+pub use bgzf::{virtual_offset, BgzfReader, BgzfWriter, GziIndex, BGZF_EOF};
+pub use crc32::{crc32, crc32_combine, Crc32};
+pub use deflate::{BlockHeader, CompressionType, DeflateFormat, Token, TokenReader, ZOPFLI_LEVEL};
+pub use dictzip::{DictzipReader, DictzipWriter, DEFAULT_CHUNK_LEN};
+pub use dump::{dump_blocks, DumpFormat, DumpOptions};
+pub use grep::{Grep, GrepOptions};
+pub use gzip::{MemberHeader, MemberWriter};
+pub use index::{Checkpoint, GzipIndex, SeekableGzReader, DEFAULT_SPAN};
+pub use lz77::{MatchFinder, MatchParams};
+pub use members::{Member, MultiMemberReader};
+pub use resumable::{ResumableDecoder, Step};
+pub use verify::{verify, MemberSummary};
+pub use zip::{DosDateTime, ZipArchive, ZipEntry, ZipEntryOptions, ZipMethod, ZipWriter};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// What to do with the data that follows the last gzip member.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingGarbage {
+    /// Try to decode it as another member and fail if it is not one.
+    #[default]
+    Error,
+    /// Silently discard it.
+    Ignore,
+    /// Discard it if it consists of zero bytes only (e.g. tape padding).
+    IgnoreZeros,
+    /// Discard it and log a warning, like gzip does.
+    Warn,
+}
+
+impl FromStr for TrailingGarbage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "error" => Self::Error,
+            "ignore" => Self::Ignore,
+            "ignore-zeros" => Self::IgnoreZeros,
+            "warn" => Self::Warn,
+            _ => bail!("unknown trailing garbage policy: {}", s),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DecompressOptions {
+    pub trailing_garbage: TrailingGarbage,
+    /// Reject malformed Huffman codes that zlib rejects but which can still
+    /// be decoded.
+    pub strict: bool,
+    /// Copy input that isn't gzip data unchanged, like `gzip -dcf`: either the
+    /// whole input or whatever follows a member. Takes precedence over the
+    /// trailing garbage policy.
+    pub passthrough: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompressOptions {
+    pub header: MemberHeader,
+    /// 0 for no compression, 1 (fastest) to 9 (best) or [`ZOPFLI_LEVEL`].
+    pub level: u32,
+    /// Resets the compressor at content-defined points, so that unchanged
+    /// parts of the input compress to the same bytes, see
+    /// [`MemberWriter::set_rsyncable`].
+    pub rsyncable: bool,
+    /// Makes the output depend on the data and the other options only, see
+    /// [`MemberHeader::make_reproducible`]. The compressed data never depends
+    /// on the machine or on how the input is read.
+    pub reproducible: bool,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        Self {
+            header: MemberHeader::default(),
+            level: 6,
+            rsyncable: false,
+            reproducible: false,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub fn decompress<R: BufRead, W: Write>(input: R, output: W) -> Result<()> {
+    decompress_with_options(input, output, &DecompressOptions::default())
+}
+
+pub fn decompress_with_options<R: BufRead, W: Write>(
+    input: R,
+    output: W,
+    options: &DecompressOptions,
+) -> Result<()> {
+    decompress_with_progress(input, output, options, |_| {})?;
+    Ok(())
+}
+
+/// Counters of a decompression, see [`decompress_with_progress`].
+#[derive(Clone, Debug, Default)]
+pub struct DecompressStats {
+    /// Compressed bytes consumed.
+    pub input_bytes: u64,
+    /// Decompressed bytes produced.
+    pub output_bytes: u64,
+    pub members: u64,
+    pub stored_blocks: u64,
+    pub fixed_blocks: u64,
+    pub dynamic_blocks: u64,
+}
+
+/// Like [`decompress_with_options`], but calls `progress` after every block
+/// and returns the final counters.
+pub fn decompress_with_progress<R: BufRead, W: Write, F: FnMut(&DecompressStats)>(
+    input: R,
+    mut output: W,
+    options: &DecompressOptions,
+    mut progress: F,
+) -> Result<DecompressStats> {
+    let mut input = CountingReader::new(input);
+    let mut stats = DecompressStats::default();
+    let mut is_first = true;
     while !input.fill_buf()?.is_empty() {
+        if options.passthrough && !MemberReader::peek_magic(&mut input)? {
+            stats.output_bytes += io::copy(&mut input, &mut output)?;
+            break;
+        }
+        if !is_first && !MemberReader::peek_magic(&mut input)? {
+            match options.trailing_garbage {
+                TrailingGarbage::Error => {}
+                TrailingGarbage::Ignore => break,
+                TrailingGarbage::IgnoreZeros => {
+                    ensure!(
+                        skip_zeros(&mut input)? > 0,
+                        "trailing garbage is not zero padding"
+                    );
+                    continue;
+                }
+                TrailingGarbage::Warn => {
+                    warn!("decompression OK, trailing garbage ignored");
+                    break;
+                }
+            }
+        }
+        is_first = false;
+
         let member_reader = MemberReader::new(input);
         let (header, mut deflate_reader) = member_reader.into_deflate_reader()?;
         ensure!(
             header.compression_method == CompressionMethod::Deflate,
             "unsupported compression method"
         );
+        deflate_reader.set_strict(options.strict);
+        stats.members += 1;
+        let member_start = stats.output_bytes;
         while let Some(block) = deflate_reader.next_block() {
             let block = block?;
             output.write_all(&block)?;
+
+            match deflate_reader
+                .last_header()
+                .map(|header| header.compression_type)
+            {
+                Some(CompressionType::Uncompressed) => stats.stored_blocks += 1,
+                Some(CompressionType::FixedTree) => stats.fixed_blocks += 1,
+                Some(CompressionType::DynamicTree) => stats.dynamic_blocks += 1,
+                _ => {}
+            }
+            stats.input_bytes = deflate_reader.bit_reader().position() / 8;
+            stats.output_bytes = member_start + deflate_reader.tracker().byte_count() as u64;
+            progress(&stats);
         }
         let (reader, writer) = deflate_reader.into_inners();
         input = reader;
-        let footer = MemberReader::read_footer(&mut input)?;
-        ensure!(
-            writer.byte_count() == footer.data_size as usize,
-            "length check failed"
-        );
-        ensure!(writer.crc32() == footer.data_crc32, "crc32 check failed");
+        MemberReader::read_footer(&mut input)?.check(writer)?;
+    }
+    stats.input_bytes = input.count();
+    progress(&stats);
+    Ok(stats)
+}
+
+pub fn compress<R: Read, W: Write>(input: R, output: W) -> Result<()> {
+    compress_with_options(input, output, &CompressOptions::default())
+}
+
+/// Compresses `input` into a single gzip member.
+pub fn compress_with_options<R: Read, W: Write>(
+    mut input: R,
+    output: W,
+    options: &CompressOptions,
+) -> Result<()> {
+    let mut header = options.header.clone();
+    if options.reproducible {
+        header.make_reproducible();
     }
+    let mut writer = MemberWriter::with_level(output, &header, options.level)?;
+    writer.set_rsyncable(options.rsyncable);
+    io::copy(&mut input, &mut writer)?;
+    writer.finish()?.flush()?;
     Ok(())
 }
+
+/// Reads the header of the gzip member at the start of `input` and returns a
+/// reader of the member's LZ77 tokens. The size and CRC32 of the member are
+/// not checked, as the data is never produced.
+pub fn read_member_tokens<R: BufRead>(input: R) -> Result<(MemberHeader, TokenReader<R>)> {
+    MemberReader::new(input).into_token_reader()
+}
+
+fn skip_zeros<R: BufRead>(input: &mut R) -> Result<usize> {
+    let mut skipped = 0;
+    loop {
+        let buf = input.fill_buf()?;
+        let zeros = buf.iter().take_while(|byte| **byte == 0).count();
+        if zeros == 0 {
+            return Ok(skipped);
+        }
+        input.consume(zeros);
+        skipped += zeros;
+    }
+}
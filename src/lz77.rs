@@ -0,0 +1,364 @@
+#![forbid(unsafe_code)]
+
+use anyhow::{bail, Result};
+
+use crate::deflate::Token;
+use crate::tracking_writer::HISTORY_SIZE;
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub const MIN_MATCH: usize = 3;
+pub const MAX_MATCH: usize = 258;
+
+/// Input needed after a position to find the longest match there and at the
+/// next position.
+const MIN_LOOKAHEAD: usize = MAX_MATCH + 1;
+
+const HASH_BITS: u32 = 15;
+pub const HASH_SIZE: usize = 1 << HASH_BITS;
+
+/// Matches of the minimal length farther back than this take more bits than
+/// three literals, see zlib's `TOO_FAR`.
+const TOO_FAR: usize = 4096;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Parameters of a [`MatchFinder`], trading speed for compression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MatchParams {
+    /// Maximum number of earlier positions to check for a match.
+    pub max_chain: usize,
+    /// Stop searching once a match of this length is found.
+    pub nice_length: usize,
+    /// Look for a longer match at the next position when the match found is
+    /// shorter than this (lazy matching). Zero for greedy matching.
+    pub max_lazy: usize,
+    /// Check only a quarter of `max_chain` positions for the lazy match when
+    /// the current one is at least this long.
+    pub good_length: usize,
+}
+
+impl MatchParams {
+    /// Parameters of compression levels 1 (fastest) to 9 (best), as in zlib.
+    pub fn for_level(level: u32) -> Result<Self> {
+        let (good_length, max_lazy, nice_length, max_chain) = match level {
+            1 => (4, 0, 8, 4),
+            2 => (4, 0, 16, 8),
+            3 => (4, 0, 32, 32),
+            4 => (4, 4, 16, 16),
+            5 => (8, 16, 32, 32),
+            6 => (8, 16, 128, 128),
+            7 => (8, 32, 128, 256),
+            8 => (32, 128, 258, 1024),
+            9 => (32, 258, 258, 4096),
+            _ => bail!("unsupported compression level: {}", level),
+        };
+        Ok(Self {
+            max_chain,
+            nice_length,
+            max_lazy,
+            good_length,
+        })
+    }
+}
+
+impl Default for MatchParams {
+    /// Level 6, the default of gzip.
+    fn default() -> Self {
+        Self::for_level(6).expect("level 6 exists")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Match {
+    length: usize,
+    distance: usize,
+}
+
+/// Splits a stream into LZ77 literals and matches, finding matches within the
+/// last 32 KiB through chains of earlier positions with the same hash of
+/// their first three bytes.
+///
+/// Positions are counted from the start of the stream. Chain links store a
+/// position plus one, so that zero ends a chain.
+pub struct MatchFinder {
+    params: MatchParams,
+    /// Input from position `base` on: the window followed by the input not
+    /// tokenized yet.
+    data: Vec<u8>,
+    base: usize,
+    /// Position of the next byte to tokenize.
+    pos: usize,
+    /// Position of the next byte to add to the hash chains.
+    hashed: usize,
+    /// Latest position with each hash.
+    head: Vec<usize>,
+    /// Previous position with the same hash, indexed by position modulo the
+    /// window size.
+    prev: Vec<usize>,
+    /// The match at a position found by lazy matching, to avoid searching
+    /// twice.
+    next_match: Option<(usize, Option<Match>)>,
+}
+
+impl MatchFinder {
+    pub fn new(params: MatchParams) -> Self {
+        Self {
+            params,
+            data: Vec::new(),
+            base: 0,
+            pos: 0,
+            hashed: 0,
+            head: vec![0; HASH_SIZE],
+            prev: vec![0; HISTORY_SIZE],
+            next_match: None,
+        }
+    }
+
+    pub fn params(&self) -> &MatchParams {
+        &self.params
+    }
+
+    /// Appends input to tokenize.
+    pub fn push(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    /// Number of bytes pushed but not tokenized yet.
+    pub fn pending(&self) -> usize {
+        self.base + self.data.len() - self.pos
+    }
+
+    /// Tokenizes the pushed input. Unless `flush` is set, the last
+    /// `MAX_MATCH` bytes or so are kept back, as a match starting there may
+    /// extend into the input pushed later.
+    pub fn tokenize(&mut self, flush: bool, tokens: &mut Vec<Token>) {
+        let end = self.base + self.data.len();
+        while self.pos < end && (flush || end - self.pos >= MIN_LOOKAHEAD) {
+            let pos = self.pos;
+            let found = match self.next_match.take() {
+                Some((match_pos, found)) if match_pos == pos => found,
+                _ => self.find_match(pos, self.params.max_chain),
+            };
+            let Some(found) = found else {
+                tokens.push(Token::Literal(self.data[pos - self.base]));
+                self.pos += 1;
+                continue;
+            };
+
+            if found.length < self.params.max_lazy {
+                let max_chain = if found.length >= self.params.good_length {
+                    self.params.max_chain / 4
+                } else {
+                    self.params.max_chain
+                };
+                let next = self.find_match(pos + 1, max_chain);
+                self.next_match = Some((pos + 1, next));
+                if next.is_some_and(|next| next.length > found.length) {
+                    tokens.push(Token::Literal(self.data[pos - self.base]));
+                    self.pos += 1;
+                    continue;
+                }
+            }
+            tokens.push(Token::Match {
+                length: found.length as u32,
+                distance: found.distance as u32,
+            });
+            self.pos += found.length;
+        }
+
+        // Drop input that can't be referenced anymore, a window at a time.
+        let window_start = self
+            .pos
+            .saturating_sub(HISTORY_SIZE)
+            .clamp(self.base, self.hashed);
+        if window_start - self.base >= HISTORY_SIZE {
+            self.data.drain(..window_start - self.base);
+            self.base = window_start;
+        }
+    }
+
+    /// Forgets the input tokenized so far, so that later matches don't refer
+    /// to it. Input not tokenized yet is kept.
+    pub fn reset(&mut self) {
+        self.data.drain(..self.pos - self.base);
+        self.base = self.pos;
+        self.hashed = self.pos;
+        self.head.fill(0);
+        self.next_match = None;
+    }
+
+    /// Finds the longest match at `pos`, checking at most `max_chain` earlier
+    /// positions.
+    fn find_match(&mut self, pos: usize, max_chain: usize) -> Option<Match> {
+        let end = self.base + self.data.len();
+        let max_length = MAX_MATCH.min(end - pos);
+        if max_length < MIN_MATCH {
+            return None;
+        }
+        self.insert_until(pos);
+
+        let current = &self.data[pos - self.base..][..max_length];
+        let limit = pos.saturating_sub(HISTORY_SIZE);
+        let mut best: Option<Match> = None;
+        let mut best_length = MIN_MATCH - 1;
+        let mut candidate = self.head[hash(current)];
+        for _ in 0..max_chain {
+            if candidate == 0 || candidate - 1 < limit {
+                break;
+            }
+            let earlier = &self.data[candidate - 1 - self.base..];
+            if earlier[best_length] == current[best_length] {
+                let length = earlier
+                    .iter()
+                    .zip(current)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best = Some(Match {
+                        length,
+                        distance: pos - (candidate - 1),
+                    });
+                    if length >= self.params.nice_length || length == max_length {
+                        break;
+                    }
+                }
+            }
+            let next = self.prev[(candidate - 1) % HISTORY_SIZE];
+            if next >= candidate {
+                // The link was overwritten by a position a window later.
+                break;
+            }
+            candidate = next;
+        }
+        best.filter(|best| best.length > MIN_MATCH || best.distance <= TOO_FAR)
+    }
+
+    /// Adds the positions before `pos` to the hash chains.
+    fn insert_until(&mut self, pos: usize) {
+        let end = self.base + self.data.len();
+        while self.hashed < pos && self.hashed + MIN_MATCH <= end {
+            let hash = hash(&self.data[self.hashed - self.base..]);
+            self.prev[self.hashed % HISTORY_SIZE] = self.head[hash];
+            self.head[hash] = self.hashed + 1;
+            self.hashed += 1;
+        }
+    }
+}
+
+/// Multiplicative hash of the first three bytes.
+pub fn hash(bytes: &[u8]) -> usize {
+    let value = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::sample;
+
+    /// Decodes `tokens`, checking that every match is valid.
+    fn expand(tokens: &[Token]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for token in tokens {
+            match *token {
+                Token::Literal(byte) => output.push(byte),
+                Token::Match { length, distance } => {
+                    let (length, distance) = (length as usize, distance as usize);
+                    assert!((MIN_MATCH..=MAX_MATCH).contains(&length));
+                    assert!((1..=HISTORY_SIZE).contains(&distance));
+                    assert!(distance <= output.len());
+                    for _ in 0..length {
+                        output.push(output[output.len() - distance]);
+                    }
+                }
+                Token::BlockBoundary(_) => unreachable!(),
+            }
+        }
+        output
+    }
+
+    fn tokenize(data: &[u8], params: MatchParams, chunk_size: usize) -> Vec<Token> {
+        let mut finder = MatchFinder::new(params);
+        let mut tokens = Vec::new();
+        for chunk in data.chunks(chunk_size) {
+            finder.push(chunk);
+            finder.tokenize(false, &mut tokens);
+        }
+        finder.tokenize(true, &mut tokens);
+        assert_eq!(finder.pending(), 0);
+        tokens
+    }
+
+    #[test]
+    fn levels() -> Result<()> {
+        let data = sample();
+        let mut token_counts = Vec::new();
+        for level in 1..=9 {
+            let tokens = tokenize(&data, MatchParams::for_level(level)?, 1 << 16);
+            assert_eq!(expand(&tokens), data);
+            token_counts.push(tokens.len());
+        }
+        // Much of the input repeats.
+        assert!(token_counts.iter().all(|count| *count < data.len() / 2));
+        // More effort finds longer matches.
+        assert!(token_counts[8] < token_counts[0]);
+        assert!(MatchParams::for_level(10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_input() -> Result<()> {
+        let data = sample();
+        let params = MatchParams::default();
+        let expected = tokenize(&data, params, data.len());
+        for chunk_size in [1, 100, 4096] {
+            assert_eq!(tokenize(&data, params, chunk_size), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn runs() {
+        let data = vec![0; 100000];
+        let tokens = tokenize(&data, MatchParams::default(), 1000);
+        assert_eq!(expand(&tokens), data);
+        assert_eq!(tokens[0], Token::Literal(0));
+        assert_eq!(
+            tokens[1],
+            Token::Match {
+                length: MAX_MATCH as u32,
+                distance: 1
+            }
+        );
+        assert!(tokens.len() < 400);
+    }
+
+    #[test]
+    fn reset() -> Result<()> {
+        let data = sample();
+        let (first, second) = data.split_at(30000);
+        let mut finder = MatchFinder::new(MatchParams::default());
+        let mut tokens = Vec::new();
+        finder.push(first);
+        finder.tokenize(true, &mut tokens);
+        finder.reset();
+        let mut second_tokens = Vec::new();
+        finder.push(second);
+        finder.tokenize(true, &mut second_tokens);
+
+        assert_eq!(expand(&tokens), first);
+        assert_eq!(expand(&second_tokens), second);
+
+        // Little input after a reset far into the stream.
+        finder.reset();
+        let mut tokens = Vec::new();
+        finder.push(&first[..100]);
+        finder.tokenize(true, &mut tokens);
+        assert_eq!(expand(&tokens), &first[..100]);
+        Ok(())
+    }
+}
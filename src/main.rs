@@ -1,36 +1,675 @@
 #![forbid(unsafe_code)]
 
-use std::io::{stdin, stdout};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{
+    self, stdin, stdout, BufRead, BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write,
+};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
+use anyhow::{bail, ensure, Context, Result};
 use log::*;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
-use ripgzip::decompress;
+use ripgzip::{
+    compress_with_options, decompress_with_progress, dump_blocks, verify, CompressOptions,
+    DecompressOptions, DecompressStats, DictzipReader, DictzipWriter, DosDateTime, DumpFormat,
+    DumpOptions, Grep, GrepOptions, MemberHeader, TrailingGarbage, ZipArchive, ZipEntryOptions,
+    ZipMethod, ZipWriter, DEFAULT_CHUNK_LEN, ZOPFLI_LEVEL,
+};
 
 #[derive(StructOpt, Debug)]
-#[structopt()]
+#[structopt(
+    after_help = "Use -- before files named like a subcommand: ripgzip -- zip",
+    setting = AppSettings::ArgsNegateSubcommands,
+    setting = AppSettings::DisableHelpSubcommand
+)]
 struct Opts {
     /// Decompress data
     #[structopt(short = "d", long = "decompress")]
     decompress: bool,
+    /// Write to standard output and keep the input files
+    #[structopt(short = "c", long = "stdout")]
+    stdout: bool,
+    /// Overwrite existing files, compress to a terminal and, with -dc, copy
+    /// input that isn't gzip data unchanged
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+    /// Keep the input files
+    #[structopt(short = "k", long = "keep")]
+    keep: bool,
+    /// Check the integrity of the compressed data
+    #[structopt(short = "t", long = "test")]
+    test: bool,
+    /// Compression level: 0 (none), 1 (fastest) to 9 (best) or 11 (optimal
+    /// parsing, very slow)
+    #[structopt(long = "level", default_value = "6")]
+    level: u32,
+    /// Compress as much as possible, same as --level 11
+    #[structopt(long = "zopfli")]
+    zopfli: bool,
+    /// Make the output rsync-friendly: unchanged parts of the input compress
+    /// to the same bytes, at the cost of a larger output
+    #[structopt(long = "rsyncable")]
+    rsyncable: bool,
+    /// Make the output depend on the data only: no name, modification time
+    /// or OS in the header
+    #[structopt(long = "reproducible")]
+    reproducible: bool,
+    /// Write dictzip files with a .dz suffix, whose data can be read from any
+    /// offset with --offset and --length
+    #[structopt(long = "dictzip", conflicts_with = "rsyncable")]
+    dictzip: bool,
+    /// Decompress the data of a dictzip file from this offset to standard
+    /// output
+    #[structopt(long = "offset")]
+    offset: Option<u64>,
+    /// Decompress at most this many bytes of a dictzip file to standard output
+    #[structopt(long = "length")]
+    length: Option<u64>,
+    /// What to do with data after the last member: error, ignore, ignore-zeros or warn
+    #[structopt(long = "trailing-garbage", default_value = "error")]
+    trailing_garbage: TrailingGarbage,
+    /// Reject malformed Huffman codes like zlib does
+    #[structopt(long = "strict")]
+    strict: bool,
+    /// Show decompression progress and throughput on stderr
+    #[structopt(long = "progress")]
+    progress: bool,
+    /// Print a summary with member and block counts after decompression
+    #[structopt(long = "stats")]
+    stats: bool,
+    /// Print the structure of every deflate block instead of the data
+    #[structopt(long = "dump-blocks")]
+    dump_blocks: bool,
+    /// Format of --dump-blocks output: text or json
+    #[structopt(long = "dump-format", default_value = "text")]
+    dump_format: DumpFormat,
+    /// Include the code lengths of dynamic blocks in --dump-blocks output
+    #[structopt(long = "dump-code-lengths")]
+    dump_code_lengths: bool,
+    /// Include every literal and match in --dump-blocks output
+    #[structopt(long = "dump-tokens")]
+    dump_tokens: bool,
     /// Verbose mode (-v, -vv, -vvv, etc)
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: usize,
+    /// Files to process, standard input if none or "-"
+    #[structopt(parse(from_os_str))]
+    files: Vec<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Searches compressed files like zgrep
+    Grep(GrepOpts),
+    /// Lists or extracts the entries of a ZIP archive
+    Unzip(UnzipOpts),
+    /// Writes files into a ZIP archive, directories recursively
+    Zip(ZipOpts),
+}
+
+/// `ripgzip grep`: searches compressed files like zgrep.
+#[derive(StructOpt, Debug)]
+struct GrepOpts {
+    /// Ignore case distinctions
+    #[structopt(short = "i", long = "ignore-case")]
+    ignore_case: bool,
+    /// Print only a count of matching lines per file
+    #[structopt(short = "c", long = "count")]
+    count: bool,
+    /// Print only the names of files with matches
+    #[structopt(short = "l", long = "files-with-matches")]
+    files_with_matches: bool,
+    /// Prefix lines with their line number
+    #[structopt(short = "n", long = "line-number")]
+    line_number: bool,
+    /// Prefix lines with the file name, the default for several files
+    #[structopt(short = "H", long = "with-filename")]
+    with_filename: bool,
+    /// Regular expression to search for
+    pattern: String,
+    /// Files to search, standard input if none or "-"
+    #[structopt(parse(from_os_str))]
+    files: Vec<PathBuf>,
+}
+
+/// `ripgzip unzip`: lists or extracts the entries of a ZIP archive.
+#[derive(StructOpt, Debug)]
+struct UnzipOpts {
+    /// List the entries
+    #[structopt(short = "l", long = "list")]
+    list: bool,
+    /// Extract the entries into the current directory
+    #[structopt(short = "x", long = "extract")]
+    extract: bool,
+    /// Overwrite existing files
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+    /// Archive to read
+    #[structopt(parse(from_os_str))]
+    archive: PathBuf,
+    /// Entries to list or extract, all if none
+    names: Vec<String>,
+}
+
+/// `ripgzip zip`: writes files into a ZIP archive, directories recursively.
+#[derive(StructOpt, Debug)]
+struct ZipOpts {
+    /// Compression level: 0 stores the files (in deflate blocks from 2 GiB),
+    /// 1 (fastest) to 9 (best) or 11 (optimal parsing, very slow)
+    #[structopt(long = "level", default_value = "6")]
+    level: u32,
+    /// Overwrite an existing archive
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+    /// Archive to write
+    #[structopt(parse(from_os_str))]
+    archive: PathBuf,
+    /// Files and directories to add
+    #[structopt(parse(from_os_str), required = true)]
+    files: Vec<PathBuf>,
+}
+
+/// Command line arguments, with the options implied by the name the binary is
+/// invoked as: `zcat` is `ripgzip -dc` and `gunzip` is `ripgzip -d`.
+fn multi_call_args() -> Vec<OsString> {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let name = args
+        .first()
+        .and_then(|arg| Path::new(arg).file_stem())
+        .and_then(|name| name.to_str());
+    let implied: &[&str] = match name {
+        Some("zcat") => &["-d", "-c"],
+        Some("gunzip") => &["-d"],
+        _ => &[],
+    };
+    let at = 1.min(args.len());
+    args.splice(at..at, implied.iter().map(OsString::from));
+    args
 }
 
 fn main() {
-    let opts = Opts::from_args();
+    let mut opts = Opts::from_iter(multi_call_args());
+    init_logging(opts.verbose);
+    match opts.command.take() {
+        Some(Command::Grep(opts)) => std::process::exit(grep(opts)),
+        Some(Command::Unzip(opts)) => {
+            if let Err(err) = unzip(&opts) {
+                error!("{}: {:#}", opts.archive.display(), err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Zip(opts)) => {
+            if let Err(err) = zip(&opts) {
+                error!("{}: {:#}", opts.archive.display(), err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+
+    let mut is_ok = true;
+    for input in inputs(&opts.files) {
+        if let Err(err) = process(&opts, input) {
+            match input {
+                Some(path) => error!("{}: {:#}", path.display(), err),
+                None => error!("{:#}", err),
+            }
+            is_ok = false;
+        }
+    }
+    if !is_ok {
+        std::process::exit(1);
+    }
+}
 
+fn init_logging(verbose: usize) {
     stderrlog::new()
-        .verbosity(1 + opts.verbose)
+        .verbosity(1 + verbose)
         .timestamp(stderrlog::Timestamp::Off)
         .init()
         .expect("failed to initialize logging");
+}
 
-    if opts.decompress {
-        if let Err(err) = decompress(stdin().lock(), stdout().lock()) {
+/// Input files, `None` standing for standard input.
+fn inputs(files: &[PathBuf]) -> Vec<Option<&Path>> {
+    if files.is_empty() {
+        vec![None]
+    } else {
+        files
+            .iter()
+            .map(|path| (path.as_os_str() != "-").then_some(path.as_path()))
+            .collect()
+    }
+}
+
+fn open(input: Option<&Path>) -> Result<Box<dyn BufRead>> {
+    Ok(match input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(stdin().lock()),
+    })
+}
+
+/// Runs `ripgzip grep`, returns the exit code: 0 if a line matched, 1 if
+/// none did and 2 on errors, like grep.
+fn grep(opts: GrepOpts) -> i32 {
+    let options = GrepOptions {
+        ignore_case: opts.ignore_case,
+        count: opts.count,
+        files_with_matches: opts.files_with_matches,
+        line_number: opts.line_number,
+        with_filename: opts.with_filename || opts.files.len() > 1,
+    };
+    let grep = match Grep::new(&opts.pattern, options) {
+        Ok(grep) => grep,
+        Err(err) => {
             error!("{:#}", err);
-            std::process::exit(1);
+            return 2;
+        }
+    };
+
+    let mut is_ok = true;
+    let mut has_matches = false;
+    for input in inputs(&opts.files) {
+        let name = input.map_or("(standard input)".into(), Path::to_string_lossy);
+        let result = open(input).and_then(|reader| grep.search(reader, &name, stdout().lock()));
+        match result {
+            Ok(count) => has_matches |= count > 0,
+            Err(err) => {
+                error!("{}: {:#}", name, err);
+                is_ok = false;
+            }
+        }
+    }
+    match (is_ok, has_matches) {
+        (false, _) => 2,
+        (true, true) => 0,
+        (true, false) => 1,
+    }
+}
+
+/// Runs `ripgzip unzip`.
+fn unzip(opts: &UnzipOpts) -> Result<()> {
+    ensure!(
+        opts.list != opts.extract,
+        "exactly one of -l and -x is required"
+    );
+    let mut archive = ZipArchive::new(BufReader::new(File::open(&opts.archive)?))?;
+    let indices = if opts.names.is_empty() {
+        (0..archive.entries().len()).collect()
+    } else {
+        opts.names
+            .iter()
+            .map(|name| {
+                archive
+                    .find(name)
+                    .ok_or_else(|| anyhow::anyhow!("{}: not found in archive", name))
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    if opts.list {
+        let mut out = stdout().lock();
+        writeln!(
+            out,
+            "{:>10}  {:<9}  {:<19}  Name",
+            "Length", "Method", "Modified"
+        )?;
+        let mut total = 0;
+        for index in &indices {
+            let entry = &archive.entries()[*index];
+            writeln!(
+                out,
+                "{:>10}  {:<9}  {}  {}",
+                entry.size,
+                entry.method.to_string(),
+                entry.modified,
+                entry.name
+            )?;
+            total += entry.size;
+        }
+        writeln!(out, "{:>10}  {} entries", total, indices.len())?;
+        return Ok(());
+    }
+
+    for index in indices {
+        let entry = archive.entries()[index].clone();
+        let path = entry_path(&entry.name)?;
+        if entry.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        ensure!(
+            opts.force || !path.exists(),
+            "{} already exists",
+            path.display()
+        );
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let result = archive.extract(index, &mut writer).and_then(|()| {
+            writer.flush()?;
+            Ok(())
+        });
+        if let Err(err) = result {
+            let _ = fs::remove_file(&path);
+            return Err(err.context(entry.name));
+        }
+    }
+    Ok(())
+}
+
+/// Path to extract an entry to, which must stay in the current directory.
+fn entry_path(name: &str) -> Result<PathBuf> {
+    let path = Path::new(name);
+    ensure!(
+        path.components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir)),
+        "{}: path leaves the current directory",
+        name
+    );
+    Ok(path.to_path_buf())
+}
+
+/// Runs `ripgzip zip`.
+fn zip(opts: &ZipOpts) -> Result<()> {
+    ensure!(
+        opts.force || !opts.archive.exists(),
+        "{} already exists",
+        opts.archive.display()
+    );
+    let options = ZipEntryOptions {
+        method: if opts.level == 0 {
+            ZipMethod::Stored
+        } else {
+            ZipMethod::Deflate
+        },
+        level: opts.level,
+        ..ZipEntryOptions::default()
+    };
+    let mut writer = ZipWriter::new(BufWriter::new(File::create(&opts.archive)?));
+    let archive = fs::canonicalize(&opts.archive)?;
+    let result = opts
+        .files
+        .iter()
+        .try_for_each(|path| add_to_zip(&mut writer, path, &options, &archive))
+        .and_then(|()| writer.finish()?.flush().map_err(Into::into));
+    if result.is_err() {
+        let _ = fs::remove_file(&opts.archive);
+    }
+    result
+}
+
+/// Adds a file, or a directory and what it contains, to a ZIP archive,
+/// skipping the archive itself.
+fn add_to_zip<W: Write>(
+    writer: &mut ZipWriter<W>,
+    path: &Path,
+    options: &ZipEntryOptions,
+    archive: &Path,
+) -> Result<()> {
+    let metadata = fs::metadata(path).with_context(|| path.display().to_string())?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let options = ZipEntryOptions {
+        modified: DosDateTime::from_unix_time(modified),
+        unix_time: u32::try_from(modified).ok(),
+        ..options.clone()
+    };
+    let name = zip_name(path)?;
+    if metadata.is_dir() {
+        if !name.is_empty() {
+            let options = ZipEntryOptions {
+                method: ZipMethod::Stored,
+                ..options.clone()
+            };
+            writer.start_entry(&format!("{}/", name), &options)?;
+        }
+        let mut children = fs::read_dir(path)?
+            .map(|child| Ok(child?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        children.sort();
+        for child in children {
+            add_to_zip(writer, &child, &options, archive)?;
+        }
+        return Ok(());
+    }
+    if fs::canonicalize(path)? == archive {
+        return Ok(());
+    }
+    // Leaves room for data that doesn't compress to grow.
+    let large_file = metadata.len() >= 1 << 31;
+    let options = ZipEntryOptions {
+        // Stored entries are kept in memory until they are finished.
+        method: if large_file {
+            ZipMethod::Deflate
+        } else {
+            options.method
+        },
+        large_file,
+        ..options
+    };
+    let mut file = File::open(path).with_context(|| path.display().to_string())?;
+    writer.start_entry(&name, &options)?;
+    io::copy(&mut file, writer).with_context(|| path.display().to_string())?;
+    Ok(())
+}
+
+/// Name of the entry of a file: its path without the root, with slashes.
+fn zip_name(path: &Path) -> Result<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(
+                part.to_str()
+                    .ok_or_else(|| anyhow::anyhow!("{}: name is not UTF-8", path.display()))?,
+            ),
+            Component::ParentDir => bail!("{}: path leaves the current directory", path.display()),
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+fn process(opts: &Opts, input: Option<&Path>) -> Result<()> {
+    if opts.offset.is_some() || opts.length.is_some() {
+        let path = input.context("--offset and --length need a file")?;
+        return read_dictzip_range(opts, path);
+    }
+    let reader = open(input)?;
+
+    if opts.dump_blocks {
+        let options = DumpOptions {
+            format: opts.dump_format,
+            code_lengths: opts.dump_code_lengths,
+            tokens: opts.dump_tokens,
+        };
+        return dump_blocks(reader, stdout().lock(), &options);
+    }
+    if opts.test {
+        for (i, summary) in verify(reader)?.iter().enumerate() {
+            ensure!(summary.size_matches(), "member {}: length check failed", i);
+            ensure!(summary.crc32_matches(), "member {}: crc32 check failed", i);
+        }
+        return Ok(());
+    }
+
+    let output_path = match input {
+        Some(path) if !opts.stdout => Some(output_path(opts, path)?),
+        _ => None,
+    };
+    let Some(output_path) = output_path else {
+        ensure!(
+            opts.decompress || opts.force || !stdout().is_terminal(),
+            "compressed data not written to a terminal, use -f to force compression"
+        );
+        return convert(opts, input, reader, stdout().lock());
+    };
+
+    ensure!(
+        opts.force || !output_path.exists(),
+        "{} already exists",
+        output_path.display()
+    );
+    let mut writer = BufWriter::new(File::create(&output_path)?);
+    let result = convert(opts, input, reader, &mut writer).and_then(|()| {
+        writer.flush()?;
+        Ok(())
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&output_path);
+    } else if !opts.keep {
+        fs::remove_file(input.unwrap())?;
+    }
+    result
+}
+
+/// Decompresses the range of a dictzip file given by `--offset` and
+/// `--length` to standard output, reading only the chunks it covers.
+fn read_dictzip_range(opts: &Opts, path: &Path) -> Result<()> {
+    let mut reader = DictzipReader::new(File::open(path)?)?;
+    reader.seek(SeekFrom::Start(opts.offset.unwrap_or(0)))?;
+    let mut out = stdout().lock();
+    io::copy(&mut reader.take(opts.length.unwrap_or(u64::MAX)), &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Name of the file to write, gzip style: `.gz`, or `.dz` for dictzip, is
+/// appended or removed.
+fn output_path(opts: &Opts, input: &Path) -> Result<PathBuf> {
+    let suffix = if opts.dictzip { ".dz" } else { ".gz" };
+    let name = input.as_os_str().to_string_lossy();
+    let stem = name.strip_suffix(suffix);
+    Ok(match (opts.decompress, stem) {
+        (true, Some(stem)) if !stem.is_empty() => PathBuf::from(stem),
+        (true, _) => bail!("unknown suffix -- ignored"),
+        (false, Some(_)) => bail!("already has {} suffix -- unchanged", suffix),
+        (false, None) => {
+            let mut name = input.as_os_str().to_owned();
+            name.push(suffix);
+            PathBuf::from(name)
+        }
+    })
+}
+
+fn convert<R: BufRead, W: Write>(
+    opts: &Opts,
+    input: Option<&Path>,
+    mut reader: R,
+    writer: W,
+) -> Result<()> {
+    if opts.decompress {
+        let options = DecompressOptions {
+            trailing_garbage: opts.trailing_garbage,
+            strict: opts.strict,
+            // Like gzip, only when the output is standard output.
+            passthrough: opts.force && (opts.stdout || input.is_none()),
+        };
+        let start = Instant::now();
+        let mut last_report = start;
+        let stats = decompress_with_progress(reader, writer, &options, |stats| {
+            if opts.progress && last_report.elapsed() >= PROGRESS_INTERVAL {
+                last_report = Instant::now();
+                eprint!("\r{:<72}", format_progress(stats, start.elapsed()));
+            }
+        })?;
+        if opts.progress {
+            eprintln!("\r{:<72}", format_progress(&stats, start.elapsed()));
         }
+        if opts.stats {
+            eprintln!(
+                "members: {}, blocks: {} stored, {} fixed, {} dynamic",
+                stats.members, stats.stored_blocks, stats.fixed_blocks, stats.dynamic_blocks
+            );
+            eprintln!("{}", format_progress(&stats, start.elapsed()));
+        }
+        Ok(())
+    } else {
+        let options = CompressOptions {
+            header: match input {
+                Some(path) if !opts.reproducible => file_header(path)?,
+                _ => MemberHeader::default(),
+            },
+            level: if opts.zopfli {
+                ZOPFLI_LEVEL
+            } else {
+                opts.level
+            },
+            rsyncable: opts.rsyncable,
+            reproducible: opts.reproducible,
+        };
+        if opts.dictzip {
+            let mut header = options.header;
+            if opts.reproducible {
+                header.make_reproducible();
+            }
+            let mut writer =
+                DictzipWriter::with_level(writer, header, DEFAULT_CHUNK_LEN, options.level)?;
+            io::copy(&mut reader, &mut writer)?;
+            writer.finish()?;
+            return Ok(());
+        }
+        compress_with_options(reader, writer, &options)
+    }
+}
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+fn format_progress(stats: &DecompressStats, elapsed: Duration) -> String {
+    const MIB: f64 = (1 << 20) as f64;
+    let ratio = if stats.input_bytes > 0 {
+        stats.output_bytes as f64 / stats.input_bytes as f64
+    } else {
+        0.0
+    };
+    format!(
+        "{:.1} MiB in, {:.1} MiB out, ratio {:.2}, {:.1} MiB/s",
+        stats.input_bytes as f64 / MIB,
+        stats.output_bytes as f64 / MIB,
+        ratio,
+        stats.output_bytes as f64 / MIB / elapsed.as_secs_f64().max(1e-9)
+    )
+}
+
+/// Header recording the name and modification time of a file, like gzip.
+fn file_header(path: &Path) -> Result<MemberHeader> {
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(MemberHeader {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned()),
+        modification_time: modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs().try_into().unwrap_or(0)),
+        ..MemberHeader::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_units() {
+        let stats = DecompressStats {
+            input_bytes: 3 << 20,
+            output_bytes: 12 << 20,
+            ..DecompressStats::default()
+        };
+        assert_eq!(
+            format_progress(&stats, Duration::from_secs(2)),
+            "3.0 MiB in, 12.0 MiB out, ratio 4.00, 6.0 MiB/s"
+        );
     }
 }
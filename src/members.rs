@@ -0,0 +1,189 @@
+#![forbid(unsafe_code)]
+
+use std::io::{self, BufRead, Read};
+
+use anyhow::{ensure, Result};
+
+use crate::deflate::DeflateReader;
+use crate::gzip::{CompressionMethod, MemberHeader, MemberReader};
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct MemberState<R> {
+    deflate_reader: DeflateReader<R>,
+    block: Vec<u8>,
+    block_pos: usize,
+}
+
+/// Reads a multi-member gzip stream one member at a time.
+pub struct MultiMemberReader<R> {
+    /// The input between members.
+    input: Option<R>,
+    /// The member being read. Both are `None` after an error.
+    current: Option<MemberState<R>>,
+}
+
+impl<R: BufRead> MultiMemberReader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input: Some(input),
+            current: None,
+        }
+    }
+
+    /// Starts the next member, skipping the rest of the current one. Returns
+    /// `None` at the end of the input or after an error.
+    pub fn next_member(&mut self) -> Option<Result<Member<'_, R>>> {
+        match self.start_member() {
+            Ok(Some(header)) => Some(Ok(Member {
+                header,
+                reader: self,
+            })),
+            Ok(None) => None,
+            Err(err) => {
+                self.input = None;
+                self.current = None;
+                Some(Err(err))
+            }
+        }
+    }
+
+    fn start_member(&mut self) -> Result<Option<MemberHeader>> {
+        if let Some(state) = &mut self.current {
+            while let Some(block) = state.deflate_reader.skip_block() {
+                block?;
+            }
+            self.end_member()?;
+        }
+        let Some(mut input) = self.input.take() else {
+            return Ok(None);
+        };
+        if input.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let (header, deflate_reader) = MemberReader::new(input).into_deflate_reader()?;
+        ensure!(
+            header.compression_method == CompressionMethod::Deflate,
+            "unsupported compression method"
+        );
+        self.current = Some(MemberState {
+            deflate_reader,
+            block: Vec::new(),
+            block_pos: 0,
+        });
+        Ok(Some(header))
+    }
+
+    /// Checks the footer of the current member, which must be fully read.
+    fn end_member(&mut self) -> Result<()> {
+        let state = self.current.take().expect("a member is being read");
+        let (mut input, writer) = state.deflate_reader.into_inners();
+        MemberReader::read_footer(&mut input)?.check(writer)?;
+        self.input = Some(input);
+        Ok(())
+    }
+
+    fn read_member(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let Some(state) = &mut self.current else {
+                return Ok(0);
+            };
+            if state.block_pos < state.block.len() {
+                let available = &state.block[state.block_pos..];
+                let size = available.len().min(buf.len());
+                buf[..size].copy_from_slice(&available[..size]);
+                state.block_pos += size;
+                return Ok(size);
+            }
+            match state.deflate_reader.next_block() {
+                Some(block) => {
+                    state.block = block?;
+                    state.block_pos = 0;
+                }
+                None => self.end_member()?,
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// A single member of a [`MultiMemberReader`]. Reading it yields the data of
+/// this member only and checks its size and CRC32 at the end.
+pub struct Member<'a, R> {
+    header: MemberHeader,
+    reader: &'a mut MultiMemberReader<R>,
+}
+
+impl<R: BufRead> Member<'_, R> {
+    pub fn header(&self) -> &MemberHeader {
+        &self.header
+    }
+}
+
+impl<R: BufRead> Read for Member<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.reader.read_member(buf).map_err(|err| {
+            self.reader.input = None;
+            self.reader.current = None;
+            io::Error::new(io::ErrorKind::InvalidData, err)
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST: &[u8] = include_bytes!("../data/ok/00-Cargo.toml.gz");
+    const SECOND: &[u8] = include_bytes!("../data/ok/01-page.gz");
+
+    fn decompressed(data: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        crate::decompress(data, &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn members() -> Result<()> {
+        let data = [FIRST, SECOND, FIRST].concat();
+        let mut reader = MultiMemberReader::new(data.as_slice());
+
+        let mut member = reader.next_member().unwrap()?;
+        let mut output = Vec::new();
+        member.read_to_end(&mut output)?;
+        assert_eq!(output, decompressed(FIRST)?);
+
+        // The second member is skipped without reading it.
+        let member = reader.next_member().unwrap()?;
+        assert_eq!(
+            member.header().modification_time,
+            MemberReader::new(SECOND).read_header()?.modification_time
+        );
+
+        let mut member = reader.next_member().unwrap()?;
+        let mut output = Vec::new();
+        member.read_to_end(&mut output)?;
+        assert_eq!(output, decompressed(FIRST)?);
+
+        assert!(reader.next_member().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn bad_crc() -> Result<()> {
+        let data = include_bytes!("../data/corrupted/01-bad-crc32.gz");
+        let mut reader = MultiMemberReader::new(&data[..]);
+        let mut member = reader.next_member().unwrap()?;
+        let err = member.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("crc32 check failed"));
+        assert!(reader.next_member().is_none());
+        Ok(())
+    }
+}
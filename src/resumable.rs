@@ -0,0 +1,271 @@
+#![forbid(unsafe_code)]
+
+use std::io;
+use std::mem;
+
+use anyhow::{ensure, Context, Result};
+use byteorder::WriteBytesExt;
+
+use crate::bit_reader::{BitReader, CountingReader};
+use crate::deflate::{Token, TokenReader, TokenReaderState};
+use crate::gzip::{CompressionMethod, MemberReader};
+use crate::tracking_writer::{TrackingWriter, HISTORY_SIZE};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Result of a [`ResumableDecoder::step`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Output decoded since the last step: a deflate block or a part of one,
+    /// possibly empty.
+    Block(Vec<u8>),
+    /// More input is needed, see [`ResumableDecoder::feed`].
+    NeedInput,
+    /// The end of the input is reached after a complete member.
+    Finished,
+}
+
+enum State {
+    Header,
+    Body {
+        tracker: TrackingWriter<Vec<u8>>,
+        /// State of the decoding in the middle of a block.
+        block: Option<Box<TokenReaderState>>,
+    },
+    Footer {
+        crc32: u32,
+        size: u64,
+    },
+}
+
+/// Gzip decoder that is fed the input instead of reading it, for callers that
+/// can't block on a reader.
+///
+/// Decoding advances a header, a footer or as much of a deflate block as the
+/// buffered input holds. When the input ends in the middle of a header or of
+/// a literal or match, that part is thrown away and redone as soon as more
+/// input has arrived.
+pub struct ResumableDecoder {
+    input: Vec<u8>,
+    /// Number of bits of `input[0]` already consumed.
+    skip_bits: u8,
+    is_input_finished: bool,
+    /// Length of `input` when the last step ran out of it, so that the step
+    /// is retried as soon as anything is fed.
+    failed_len: Option<usize>,
+    state: State,
+}
+
+impl Default for ResumableDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResumableDecoder {
+    pub fn new() -> Self {
+        Self {
+            input: Vec::new(),
+            skip_bits: 0,
+            is_input_finished: false,
+            failed_len: None,
+            state: State::Header,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.input.extend_from_slice(data);
+    }
+
+    /// Signals that there's no more input.
+    pub fn finish_input(&mut self) {
+        self.is_input_finished = true;
+    }
+
+    pub fn step(&mut self) -> Result<Step> {
+        if !self.is_input_finished && self.failed_len == Some(self.input.len()) {
+            return Ok(Step::NeedInput);
+        }
+        loop {
+            match self.try_step() {
+                Ok(Some(step)) => {
+                    self.failed_len = None;
+                    return Ok(step);
+                }
+                Ok(None) => self.failed_len = None,
+                Err(err) if !self.is_input_finished && is_unexpected_eof(&err) => {
+                    self.failed_len = Some(self.input.len());
+                    return Ok(Step::NeedInput);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Makes one step, returns `None` if it produced nothing to report.
+    fn try_step(&mut self) -> Result<Option<Step>> {
+        match &mut self.state {
+            State::Header => {
+                if self.input.is_empty() {
+                    return Ok(Some(if self.is_input_finished {
+                        Step::Finished
+                    } else {
+                        Step::NeedInput
+                    }));
+                }
+                let mut reader = CountingReader::new(self.input.as_slice());
+                let header = MemberReader::new(&mut reader).read_header()?;
+                ensure!(
+                    header.compression_method == CompressionMethod::Deflate,
+                    "unsupported compression method"
+                );
+                let consumed = reader.count() as usize;
+                self.input.drain(..consumed);
+                self.state = State::Body {
+                    tracker: TrackingWriter::new(Vec::new()),
+                    block: None,
+                };
+                Ok(None)
+            }
+            State::Body { tracker, block } => {
+                let mut bit_reader = BitReader::new(CountingReader::new(self.input.as_slice()));
+                bit_reader.read_bits(self.skip_bits)?;
+                let start = self.skip_bits as u64;
+                let mut token_reader = match block.take() {
+                    Some(state) => TokenReader::from_parts(bit_reader, *state),
+                    None => {
+                        let history = tracker.byte_count().min(HISTORY_SIZE);
+                        let mut token_reader = TokenReader::from_bit_reader(bit_reader, history);
+                        token_reader
+                            .read_block_header()?
+                            .context("no more blocks")?;
+                        token_reader
+                    }
+                };
+
+                let (is_block_finished, mut position) = loop {
+                    let token_start = token_reader.bit_reader().position();
+                    match token_reader.read_token() {
+                        Ok(Some(Token::Literal(byte))) => tracker.write_u8(byte)?,
+                        Ok(Some(Token::Match { length, distance })) => {
+                            tracker.write_previous(distance as usize, length as usize)?
+                        }
+                        Ok(Some(Token::BlockBoundary(_))) => unreachable!(),
+                        Ok(None) => break (true, token_reader.bit_reader().position()),
+                        Err(err) if !self.is_input_finished && is_unexpected_eof(&err) => {
+                            break (false, token_start)
+                        }
+                        Err(err) => return Err(err),
+                    }
+                };
+                let is_final = is_block_finished && token_reader.is_finished();
+                if !is_block_finished {
+                    *block = Some(Box::new(token_reader.into_parts().1));
+                    if position == start {
+                        return Ok(Some(Step::NeedInput));
+                    }
+                }
+                let output = mem::take(tracker.get_mut_ref_inner());
+                if is_final {
+                    // The footer starts at the next byte boundary.
+                    position = position.next_multiple_of(8);
+                    let size = tracker.byte_count() as u64;
+                    let tracker = mem::replace(tracker, TrackingWriter::new(Vec::new()));
+                    self.state = State::Footer {
+                        crc32: tracker.crc32(),
+                        size,
+                    };
+                }
+                self.input.drain(..(position / 8) as usize);
+                self.skip_bits = (position % 8) as u8;
+                Ok(Some(Step::Block(output)))
+            }
+            State::Footer { crc32, size } => {
+                let footer = MemberReader::read_footer(&mut self.input.as_slice())?;
+                ensure!(*size as u32 == footer.data_size, "length check failed");
+                ensure!(*crc32 == footer.data_crc32, "crc32 check failed");
+                self.input.drain(..8);
+                self.state = State::Header;
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn is_unexpected_eof(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::UnexpectedEof)
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_in_chunks(data: &[u8], chunk_size: usize) -> Result<Vec<u8>> {
+        let mut decoder = ResumableDecoder::new();
+        let mut chunks = data.chunks(chunk_size);
+        let mut output = Vec::new();
+        loop {
+            match decoder.step()? {
+                Step::Block(block) => output.extend_from_slice(&block),
+                Step::NeedInput => match chunks.next() {
+                    Some(chunk) => decoder.feed(chunk),
+                    None => decoder.finish_input(),
+                },
+                Step::Finished => return Ok(output),
+            }
+        }
+    }
+
+    #[test]
+    fn chunked_input() -> Result<()> {
+        for (data, chunk_sizes) in [
+            (
+                &include_bytes!("../data/ok/01-page.gz")[..],
+                &[1, 7, 33024][..],
+            ),
+            (&include_bytes!("../data/ok/09-concat.gz")[..], &[4096][..]),
+        ] {
+            let mut expected = Vec::new();
+            crate::decompress(data, &mut expected)?;
+            for chunk_size in chunk_sizes {
+                assert_eq!(decode_in_chunks(data, *chunk_size)?, expected);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_input() {
+        let data = include_bytes!("../data/ok/00-Cargo.toml.gz");
+        assert!(decode_in_chunks(&data[..data.len() - 3], 100).is_err());
+        let data = include_bytes!("../data/corrupted/01-bad-crc32.gz");
+        assert!(decode_in_chunks(data, 100).is_err());
+    }
+
+    #[test]
+    fn decodes_what_has_arrived() -> Result<()> {
+        let data = include_bytes!("../data/ok/00-Cargo.toml.gz");
+        let mut expected = Vec::new();
+        crate::decompress(&data[..], &mut expected)?;
+
+        let mut decoder = ResumableDecoder::new();
+        let mut output = Vec::new();
+        for chunk in [&data[..data.len() - 20], &data[data.len() - 20..]] {
+            decoder.feed(chunk);
+            while let Step::Block(block) = decoder.step()? {
+                output.extend_from_slice(&block);
+            }
+        }
+        // The member is complete without waiting for the end of the input.
+        assert_eq!(output, expected);
+        decoder.finish_input();
+        assert_eq!(decoder.step()?, Step::Finished);
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+#![forbid(unsafe_code)]
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Number of trailing input bytes that the rolling sum covers, and the
+/// minimal distance between two boundaries.
+pub const RSYNC_WINDOW: usize = 4096;
+
+/// Chooses content-defined points to reset the compressor at, like
+/// `gzip --rsyncable`: a boundary follows every byte where the sum of the
+/// last `RSYNC_WINDOW` bytes hits a fixed value modulo `RSYNC_WINDOW`. As the
+/// boundaries only depend on the bytes right before them, an edit of the
+/// input moves the boundaries near it only, and the compressed data between
+/// the unchanged boundaries stays the same.
+///
+/// The value is chosen so that runs of a single byte have no boundaries.
+pub struct RsyncSplitter {
+    window: Vec<u8>,
+    /// Position of the oldest byte in `window`.
+    index: usize,
+    sum: u32,
+    /// Bytes since the last boundary.
+    chunk_len: usize,
+}
+
+impl Default for RsyncSplitter {
+    fn default() -> Self {
+        Self {
+            window: vec![0; RSYNC_WINDOW],
+            index: 0,
+            sum: 0,
+            chunk_len: 0,
+        }
+    }
+}
+
+impl RsyncSplitter {
+    /// Adds `data` to the rolling sum up to the first boundary and returns
+    /// the length of the data before it, or `None` if there is no boundary
+    /// and all of `data` was added.
+    pub fn find_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        for (offset, byte) in data.iter().enumerate() {
+            self.sum = self.sum + *byte as u32 - self.window[self.index] as u32;
+            self.window[self.index] = *byte;
+            self.index = (self.index + 1) % RSYNC_WINDOW;
+            self.chunk_len += 1;
+            if self.chunk_len >= RSYNC_WINDOW
+                && self.sum as usize % RSYNC_WINDOW == RSYNC_WINDOW - 1
+            {
+                self.chunk_len = 0;
+                return Some(offset + 1);
+            }
+        }
+        None
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::random_bytes;
+
+    fn boundaries(data: &[u8], chunk_size: usize) -> Vec<usize> {
+        let mut splitter = RsyncSplitter::default();
+        let mut boundaries = Vec::new();
+        let mut start = 0;
+        for chunk in data.chunks(chunk_size) {
+            let mut rest = chunk;
+            while let Some(len) = splitter.find_boundary(rest) {
+                start += len;
+                boundaries.push(start);
+                rest = &rest[len..];
+            }
+            start += rest.len();
+        }
+        boundaries
+    }
+
+    #[test]
+    fn content_defined() {
+        let data = random_bytes(1 << 20, 1);
+        let expected = boundaries(&data, data.len());
+        assert!(expected.len() > 50, "{}", expected.len());
+        assert!(expected
+            .windows(2)
+            .all(|pair| pair[1] - pair[0] >= RSYNC_WINDOW));
+        for chunk_size in [1, 1000, 65536] {
+            assert_eq!(boundaries(&data, chunk_size), expected);
+        }
+
+        // Inserting data only moves the boundaries after it by its length,
+        // once the next boundary is found.
+        let mut edited = random_bytes(10000, 2);
+        edited.extend_from_slice(&data);
+        let edited_boundaries = boundaries(&edited, edited.len());
+        let resynced = edited_boundaries
+            .iter()
+            .filter_map(|boundary| boundary.checked_sub(10000))
+            .filter(|boundary| expected.contains(boundary))
+            .count();
+        assert!(resynced + 3 >= expected.len());
+
+        assert!(boundaries(&[0; 100000], 1000).is_empty());
+    }
+}
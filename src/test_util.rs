@@ -0,0 +1,33 @@
+#![forbid(unsafe_code)]
+
+//! Inputs shared by the unit tests.
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// The text of `data/ok/01-page.gz`.
+pub fn text() -> Vec<u8> {
+    let mut data = Vec::new();
+    crate::decompress(&include_bytes!("../data/ok/01-page.gz")[..], &mut data)
+        .expect("the sample page decompresses");
+    data
+}
+
+/// Incompressible bytes, the same for the same `seed`.
+pub fn random_bytes(len: usize, mut seed: u32) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect()
+}
+
+/// Input of every kind: text, a long run, incompressible bytes and a repeat
+/// of the text too far back to refer to.
+pub fn sample() -> Vec<u8> {
+    let mut data = text();
+    data.extend(std::iter::repeat_n(b'a', 1000));
+    data.extend(random_bytes(40000, 1));
+    data.extend_from_within(..20000);
+    data
+}
@@ -2,43 +2,90 @@
 
 use std::collections::VecDeque;
 use std::io::{self, Write};
+use std::mem;
 
 use anyhow::{ensure, Result};
-use byteorder::WriteBytesExt;
-use crc::{Crc, Digest, CRC_32_ISO_HDLC};
+
+use crate::crc32::Crc32;
 
 ////////////////////////////////////////////////////////////////////////////////
 
-const HISTORY_SIZE: usize = 32768;
-static CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+pub const HISTORY_SIZE: usize = 32768;
+
+/// The last `size` written bytes, oldest first.
+struct RingBuffer {
+    bytes: VecDeque<u8>,
+    size: usize,
+}
 
-struct RingBuffer(VecDeque<u8>);
 impl RingBuffer {
+    fn new(size: usize) -> Self {
+        Self {
+            bytes: VecDeque::with_capacity(size),
+            size,
+        }
+    }
+
     fn write_slice(&mut self, buf: &[u8]) {
-        for byte in buf {
-            if self.0.len() >= HISTORY_SIZE {
-                self.0.pop_back();
-            }
-            self.0.push_front(*byte);
+        let buf = &buf[buf.len().saturating_sub(self.size)..];
+        let excess = (self.bytes.len() + buf.len()).saturating_sub(self.size);
+        self.bytes.drain(..excess);
+        self.bytes.extend(buf);
+    }
+
+    /// Byte written `dist` bytes ago.
+    fn get(&self, dist: usize) -> u8 {
+        self.bytes[self.bytes.len() - dist]
+    }
+
+    /// The last `len` bytes, as at most two slices.
+    fn last(&self, len: usize) -> (&[u8], &[u8]) {
+        let (front, back) = self.bytes.as_slices();
+        let start = self.bytes.len() - len;
+        if start >= front.len() {
+            (&back[start - front.len()..], &[])
+        } else {
+            (&front[start..], back)
         }
     }
 }
 
+/// Writes data to the inner writer while keeping the size, CRC32 and the last
+/// 32 KiB of it, or another history size.
+///
+/// The CRC32 is updated lazily, in batches taken from the history, because
+/// the decoder mostly writes a byte or a short match at a time.
 pub struct TrackingWriter<T> {
-    digest: Digest<'static, u32>,
+    crc: Crc32,
     inner: T,
     byte_n: usize,
     buffer: RingBuffer,
+    /// Number of the newest bytes in the history missing from `crc`.
+    pending: usize,
+    /// Whether writes bypass `inner`, see [`TrackingWriter::set_discard`].
+    discard: bool,
+    /// Bytes of the last match, kept to reuse the allocation.
+    scratch: Vec<u8>,
 }
 
 impl<T: Write> Write for TrackingWriter<T> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let size = self.inner.write(buf)?;
-        // if size != buf.len() {
-        //     eprint!("size != buf.len(): {} and {}", size, buf.len());
-        // }
+        let size = if self.discard {
+            buf.len()
+        } else {
+            self.inner.write(buf)?
+        };
         let eff_buf = &buf[0..size];
-        self.digest.update(eff_buf);
+        if self.pending + size > self.buffer.size {
+            self.update_crc();
+            if size > self.buffer.size {
+                self.crc.update(eff_buf);
+            } else {
+                self.pending = size;
+            }
+        } else {
+            self.pending += size;
+        }
         self.buffer.write_slice(eff_buf);
         self.byte_n += size;
         Ok(size)
@@ -51,30 +98,81 @@ impl<T: Write> Write for TrackingWriter<T> {
 
 impl<T: Write> TrackingWriter<T> {
     pub fn new(inner: T) -> Self {
+        Self::with_window(inner, &[])
+    }
+
+    /// Creates a writer whose history is pre-filled with `window`, so that
+    /// back references into it can be resolved. The window itself is not
+    /// written to `inner` and does not count towards size and CRC32.
+    pub fn with_window(inner: T, window: &[u8]) -> Self {
+        let mut writer = Self::with_history_size(inner, HISTORY_SIZE);
+        writer.buffer.write_slice(window);
+        writer
+    }
+
+    /// Creates a writer that keeps the last `history_size` bytes instead of
+    /// 32 KiB, for formats with a larger window.
+    pub fn with_history_size(inner: T, history_size: usize) -> Self {
         Self {
-            digest: CRC.digest(),
+            crc: Crc32::new(),
             inner,
             byte_n: 0,
-            buffer: RingBuffer(VecDeque::new()),
+            buffer: RingBuffer::new(history_size),
+            pending: 0,
+            discard: false,
+            scratch: Vec::new(),
         }
     }
 
+    fn update_crc(&mut self) {
+        let (first, second) = self.buffer.last(self.pending);
+        self.crc.update(first);
+        self.crc.update(second);
+        self.pending = 0;
+    }
+
     /// Write a sequence of `len` bytes written `dist` bytes ago.
     pub fn write_previous(&mut self, dist: usize, len: usize) -> Result<()> {
-        ensure!(dist <= self.byte_n, "Trying to go back in time");
-        ensure!(dist <= HISTORY_SIZE, "Trying to rewrite to much history");
-        for _i in 0..len {
-            self.write_u8(self.buffer.0[dist - 1])?;
+        ensure!(dist <= self.buffer.bytes.len(), "Trying to go back in time");
+        ensure!(
+            dist <= self.buffer.size,
+            "Trying to rewrite to much history"
+        );
+        // The copy overlaps its source when `len` exceeds `dist`.
+        let mut bytes = mem::take(&mut self.scratch);
+        bytes.clear();
+        for i in 0..len {
+            let byte = if i < dist {
+                self.buffer.get(dist - i)
+            } else {
+                bytes[i - dist]
+            };
+            bytes.push(byte);
         }
+        let result = self.write_all(&bytes);
+        self.scratch = bytes;
+        result?;
         Ok(())
     }
 
+    /// Stops writing to the inner writer while still keeping the size,
+    /// CRC32 and history, for checking data without producing it.
+    pub fn set_discard(&mut self, discard: bool) {
+        self.discard = discard;
+    }
+
     pub fn byte_count(&self) -> usize {
         self.byte_n
     }
 
-    pub fn crc32(self) -> u32 {
-        self.digest.finalize()
+    /// Returns the last (up to the history size) written bytes, oldest first.
+    pub fn window(&self) -> Vec<u8> {
+        self.buffer.bytes.iter().copied().collect()
+    }
+
+    pub fn crc32(mut self) -> u32 {
+        self.update_crc();
+        self.crc.finalize()
     }
 
     pub fn get_mut_ref_inner(&mut self) -> &mut T {
@@ -85,6 +183,7 @@ impl<T: Write> TrackingWriter<T> {
 ////////////////////////////////////////////////////////////////////////////////
 
 #[cfg(test)]
+#[allow(clippy::unused_io_amount)]
 mod tests {
     use super::*;
     use byteorder::WriteBytesExt;
@@ -168,4 +267,36 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn with_window() -> Result<()> {
+        let mut writer = TrackingWriter::with_window(Vec::new(), b"abcdef");
+        assert_eq!(writer.byte_count(), 0);
+
+        writer.write_previous(4, 6)?;
+        assert!(writer.write_previous(13, 1).is_err());
+        assert_eq!(writer.byte_count(), 6);
+        assert_eq!(writer.window(), b"abcdefcdefcd");
+        assert_eq!(writer.get_mut_ref_inner(), b"cdefcd");
+
+        Ok(())
+    }
+
+    #[test]
+    fn discard() -> Result<()> {
+        let mut expected = TrackingWriter::new(Vec::new());
+        let mut writer = TrackingWriter::new(Vec::new());
+        for discard in [false, true, false] {
+            writer.set_discard(discard);
+            for writer in [&mut expected, &mut writer] {
+                writer.write_all(b"abcdef")?;
+                writer.write_previous(3, 5)?;
+            }
+        }
+        assert_eq!(writer.get_mut_ref_inner(), b"abcdefdefdeabcdefdefde");
+        assert_eq!(writer.byte_count(), expected.byte_count());
+        assert_eq!(writer.window(), expected.window());
+        assert_eq!(writer.crc32(), expected.crc32());
+        Ok(())
+    }
 }
@@ -0,0 +1,109 @@
+#![forbid(unsafe_code)]
+
+use std::io::BufRead;
+
+use anyhow::{ensure, Result};
+
+use crate::bit_reader::CountingReader;
+use crate::gzip::{CompressionMethod, MemberHeader, MemberReader};
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Result of checking a single gzip member.
+#[derive(Debug)]
+pub struct MemberSummary {
+    pub header: MemberHeader,
+    /// Size of the whole member, header and footer included.
+    pub compressed_size: u64,
+    /// Size of the decompressed data.
+    pub size: u64,
+    pub crc32: u32,
+    /// ISIZE and CRC32 stored in the footer.
+    pub expected_size: u32,
+    pub expected_crc32: u32,
+}
+
+impl MemberSummary {
+    pub fn size_matches(&self) -> bool {
+        self.size as u32 == self.expected_size
+    }
+
+    pub fn crc32_matches(&self) -> bool {
+        self.crc32 == self.expected_crc32
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.size_matches() && self.crc32_matches()
+    }
+}
+
+/// Decodes every member of `input` without producing the output and reports
+/// whether its size and CRC32 match the footer.
+///
+/// A mismatch is reported in the summary, while a malformed stream is an
+/// error.
+pub fn verify<R: BufRead>(input: R) -> Result<Vec<MemberSummary>> {
+    let mut input = CountingReader::new(input);
+    let mut summaries = Vec::new();
+    while !input.fill_buf()?.is_empty() {
+        let start = input.count();
+        let (header, mut deflate_reader) = MemberReader::new(input).into_deflate_reader()?;
+        ensure!(
+            header.compression_method == CompressionMethod::Deflate,
+            "unsupported compression method"
+        );
+        while let Some(size) = deflate_reader.skip_block() {
+            size?;
+        }
+
+        let (reader, writer) = deflate_reader.into_inners();
+        input = reader;
+        let footer = MemberReader::read_footer(&mut input)?;
+        summaries.push(MemberSummary {
+            header,
+            compressed_size: input.count() - start,
+            size: writer.byte_count() as u64,
+            crc32: writer.crc32(),
+            expected_size: footer.data_size,
+            expected_crc32: footer.data_crc32,
+        });
+    }
+    Ok(summaries)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_members() -> Result<()> {
+        let data = include_bytes!("../data/ok/09-concat.gz");
+        let summaries = verify(&data[..])?;
+        assert!(summaries.len() > 1);
+        assert!(summaries.iter().all(MemberSummary::is_ok));
+        assert_eq!(
+            summaries.iter().map(|s| s.compressed_size).sum::<u64>(),
+            data.len() as u64
+        );
+
+        let mut expected = Vec::new();
+        crate::decompress(&data[..], &mut expected)?;
+        assert_eq!(
+            summaries.iter().map(|s| s.size).sum::<u64>(),
+            expected.len() as u64
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verify_bad_crc() -> Result<()> {
+        let data = include_bytes!("../data/corrupted/01-bad-crc32.gz");
+        let summaries = verify(&data[..])?;
+        assert_eq!(summaries.len(), 1);
+        assert!(summaries[0].size_matches());
+        assert!(!summaries[0].crc32_matches());
+        Ok(())
+    }
+}
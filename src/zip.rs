@@ -0,0 +1,1163 @@
+#![forbid(unsafe_code)]
+
+use std::fmt;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::mem;
+
+use anyhow::{bail, ensure, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::bit_reader::BitReader;
+use crate::bit_writer::BitWriter;
+use crate::deflate::{DeflateFormat, DeflateReader, DeflateWriter};
+use crate::tracking_writer::TrackingWriter;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// See APPNOTE.TXT of PKWARE, sections 4.3 and 4.4.
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+
+const LOCAL_HEADER_SIZE: u64 = 30;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE: u64 = 56;
+const ZIP64_LOCATOR_SIZE: u64 = 20;
+
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+// The extended timestamp of Info-ZIP, with flags of the times it has. Only
+// the modification time is written, which the central directory repeats.
+const EXTENDED_TIMESTAMP_EXTRA_ID: u16 = 0x5455;
+const EXTENDED_TIMESTAMP_SIZE: u16 = 5;
+const TIMESTAMP_MODIFIED: u8 = 1 << 0;
+
+const FLAG_ENCRYPTED: u16 = 1 << 0;
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const FLAG_UTF8: u16 = 1 << 11;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+const METHOD_DEFLATE64: u16 = 9;
+
+// Version 4.5 of the format adds ZIP64, the high byte of "version made by"
+// is the host system, 3 for Unix.
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+const VERSION_MADE_BY: u16 = 3 << 8 | VERSION_ZIP64;
+
+// Unix modes in the high half of the external attributes, and the MS-DOS
+// directory attribute.
+const FILE_ATTRIBUTES: u32 = 0o100644 << 16;
+const DIR_ATTRIBUTES: u32 = 0o40755 << 16 | 0x10;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZipMethod {
+    Stored,
+    Deflate,
+    Deflate64,
+    Unknown(u16),
+}
+
+impl From<u16> for ZipMethod {
+    fn from(value: u16) -> Self {
+        match value {
+            METHOD_STORED => Self::Stored,
+            METHOD_DEFLATE => Self::Deflate,
+            METHOD_DEFLATE64 => Self::Deflate64,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+impl From<ZipMethod> for u16 {
+    fn from(method: ZipMethod) -> u16 {
+        match method {
+            ZipMethod::Stored => METHOD_STORED,
+            ZipMethod::Deflate => METHOD_DEFLATE,
+            ZipMethod::Deflate64 => METHOD_DEFLATE64,
+            ZipMethod::Unknown(x) => x,
+        }
+    }
+}
+
+impl fmt::Display for ZipMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stored => f.write_str("stored"),
+            Self::Deflate => f.write_str("deflate"),
+            Self::Deflate64 => f.write_str("deflate64"),
+            Self::Unknown(x) => write!(f, "method {}", x),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Modification time of a ZIP entry in MS-DOS format: local time with a
+/// resolution of two seconds, from 1980 to 2107.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DosDateTime {
+    pub date: u16,
+    pub time: u16,
+}
+
+impl Default for DosDateTime {
+    /// 1980-01-01 00:00:00, the earliest time there is.
+    fn default() -> Self {
+        Self {
+            date: 1 << 5 | 1,
+            time: 0,
+        }
+    }
+}
+
+impl DosDateTime {
+    /// Converts seconds since the Unix epoch, rounding down to two seconds
+    /// and clamping to the years the format covers. The archive has no time
+    /// zone, and this takes the time as UTC, unlike tools that use the local
+    /// time of the machine. Those read the time of an extended timestamp
+    /// instead when there is one, see [`ZipEntryOptions::unix_time`].
+    pub fn from_unix_time(seconds: u64) -> Self {
+        let days = seconds / 86400;
+        let seconds = seconds % 86400;
+        let (year, month, day) = civil_from_days(days);
+        if year < 1980 {
+            return Self::default();
+        }
+        if year > 2107 {
+            return Self {
+                date: 127 << 9 | 12 << 5 | 31,
+                time: 23 << 11 | 59 << 5 | 29,
+            };
+        }
+        Self {
+            date: ((year - 1980) << 9 | month << 5 | day) as u16,
+            time: ((seconds / 3600) << 11 | (seconds / 60 % 60) << 5 | (seconds % 60 / 2)) as u16,
+        }
+    }
+
+    pub fn year(&self) -> u16 {
+        1980 + (self.date >> 9)
+    }
+
+    pub fn month(&self) -> u8 {
+        (self.date >> 5 & 0xf) as u8
+    }
+
+    pub fn day(&self) -> u8 {
+        (self.date & 0x1f) as u8
+    }
+
+    pub fn hour(&self) -> u8 {
+        (self.time >> 11) as u8
+    }
+
+    pub fn minute(&self) -> u8 {
+        (self.time >> 5 & 0x3f) as u8
+    }
+
+    pub fn second(&self) -> u8 {
+        (self.time & 0x1f) as u8 * 2
+    }
+}
+
+impl fmt::Display for DosDateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year(),
+            self.month(),
+            self.day(),
+            self.hour(),
+            self.minute(),
+            self.second()
+        )
+    }
+}
+
+/// Year, month and day of the given day since 1970-01-01 in the Gregorian
+/// calendar, see <http://howardhinnant.github.io/date_algorithms.html>.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Days since 0000-03-01, so that leap days end the year.
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = era * 400 + year_of_era + (month <= 2) as u64;
+    (year, month, day)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// An entry of the central directory of a ZIP archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ZipEntry {
+    pub name: String,
+    pub method: ZipMethod,
+    pub flags: u16,
+    pub modified: DosDateTime,
+    /// Modification time in seconds since the Unix epoch, from an extended
+    /// timestamp.
+    pub unix_time: Option<u32>,
+    pub crc32: u32,
+    pub compressed_size: u64,
+    pub size: u64,
+    /// Offset of the local header in the archive.
+    pub header_offset: u64,
+    pub comment: String,
+}
+
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    fn read_from<T: Read>(rdr: &mut T) -> Result<Self> {
+        ensure!(
+            rdr.read_u32::<LittleEndian>()? == CENTRAL_HEADER_SIGNATURE,
+            "invalid central directory header"
+        );
+        let _version_made_by = rdr.read_u16::<LittleEndian>()?;
+        let _version_needed = rdr.read_u16::<LittleEndian>()?;
+        let flags = rdr.read_u16::<LittleEndian>()?;
+        let method = rdr.read_u16::<LittleEndian>()?.into();
+        let time = rdr.read_u16::<LittleEndian>()?;
+        let date = rdr.read_u16::<LittleEndian>()?;
+        let crc32 = rdr.read_u32::<LittleEndian>()?;
+        let compressed_size = rdr.read_u32::<LittleEndian>()?;
+        let size = rdr.read_u32::<LittleEndian>()?;
+        let name_len = rdr.read_u16::<LittleEndian>()?;
+        let extra_len = rdr.read_u16::<LittleEndian>()?;
+        let comment_len = rdr.read_u16::<LittleEndian>()?;
+        let _disk = rdr.read_u16::<LittleEndian>()?;
+        let _internal_attributes = rdr.read_u16::<LittleEndian>()?;
+        let _external_attributes = rdr.read_u32::<LittleEndian>()?;
+        let header_offset = rdr.read_u32::<LittleEndian>()?;
+        let name = read_bytes(rdr, name_len)?;
+        let extra = read_bytes(rdr, extra_len)?;
+        let comment = read_bytes(rdr, comment_len)?;
+
+        // The ZIP64 field has the values that don't fit, in this order.
+        let mut zip64 = extra_field(&extra, ZIP64_EXTRA_ID).unwrap_or_default();
+        let mut widen = |value: u32| -> Result<u64> {
+            Ok(if value == u32::MAX {
+                zip64
+                    .read_u64::<LittleEndian>()
+                    .context("ZIP64 extra field is too short")?
+            } else {
+                value as u64
+            })
+        };
+        let size = widen(size)?;
+        let compressed_size = widen(compressed_size)?;
+        let header_offset = widen(header_offset)?;
+
+        let unix_time = extra_field(&extra, EXTENDED_TIMESTAMP_EXTRA_ID)
+            .filter(|timestamp| {
+                timestamp.len() >= EXTENDED_TIMESTAMP_SIZE as usize
+                    && timestamp[0] & TIMESTAMP_MODIFIED != 0
+            })
+            .map(|timestamp| {
+                u32::from_le_bytes([timestamp[1], timestamp[2], timestamp[3], timestamp[4]])
+            });
+
+        Ok(Self {
+            name: decode_string(name, flags)?,
+            method,
+            flags,
+            modified: DosDateTime { date, time },
+            unix_time,
+            crc32,
+            compressed_size,
+            size,
+            header_offset,
+            comment: decode_string(comment, flags)?,
+        })
+    }
+}
+
+fn read_bytes<T: Read>(rdr: &mut T, len: u16) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; len as usize];
+    rdr.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Returns the data of the field with the given ID of an extra field.
+fn extra_field(mut extra: &[u8], id: u16) -> Option<&[u8]> {
+    while extra.len() >= 4 {
+        let field_id = u16::from_le_bytes([extra[0], extra[1]]);
+        let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        let data = extra.get(4..4 + len)?;
+        if field_id == id {
+            return Some(data);
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+/// Names and comments are UTF-8 with the flag set and CP437 otherwise. The
+/// two agree on ASCII, which is what CP437 names mostly are, so those are
+/// decoded as UTF-8 too, replacing what isn't valid.
+fn decode_string(bytes: Vec<u8>, flags: u16) -> Result<String> {
+    if flags & FLAG_UTF8 != 0 {
+        String::from_utf8(bytes).context("invalid UTF-8 name or comment")
+    } else {
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Reader of a ZIP archive, with the entries listed in its central directory.
+pub struct ZipArchive<R> {
+    inner: R,
+    entries: Vec<ZipEntry>,
+    comment: Vec<u8>,
+}
+
+impl<R: Read + Seek> ZipArchive<R> {
+    pub fn new(mut inner: R) -> Result<Self> {
+        // The end of central directory record is followed by a comment of up
+        // to 64 KiB.
+        let archive_len = inner.seek(SeekFrom::End(0))?;
+        let tail_len = archive_len.min((END_OF_CENTRAL_DIRECTORY_SIZE + u16::MAX as usize) as u64);
+        let tail_offset = archive_len - tail_len;
+        inner.seek(SeekFrom::Start(tail_offset))?;
+        let mut tail = vec![0; tail_len as usize];
+        inner.read_exact(&mut tail)?;
+        let record_pos = (0..(tail.len() + 1).saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE))
+            .rev()
+            .find(|pos| tail[*pos..].starts_with(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes()))
+            .context("not a zip archive")?;
+
+        let mut record = &tail[record_pos + 4..];
+        let disk = record.read_u16::<LittleEndian>()?;
+        let directory_disk = record.read_u16::<LittleEndian>()?;
+        let _disk_entry_count = record.read_u16::<LittleEndian>()?;
+        let entry_count = record.read_u16::<LittleEndian>()?;
+        let directory_size = record.read_u32::<LittleEndian>()?;
+        let directory_offset = record.read_u32::<LittleEndian>()?;
+        let comment_len = record.read_u16::<LittleEndian>()? as usize;
+        let comment = record[..comment_len.min(record.len())].to_vec();
+        ensure!(
+            disk == directory_disk || disk == u16::MAX,
+            "multi-disk archives are not supported"
+        );
+
+        let (entry_count, directory_size, directory_offset) = if entry_count == u16::MAX
+            || directory_size == u32::MAX
+            || directory_offset == u32::MAX
+        {
+            let record_offset = tail_offset + record_pos as u64;
+            read_zip64_record(&mut inner, record_offset)?
+        } else {
+            (
+                entry_count as u64,
+                directory_size as u64,
+                directory_offset as u64,
+            )
+        };
+
+        inner.seek(SeekFrom::Start(directory_offset))?;
+        let mut directory = BufReader::new((&mut inner).take(directory_size));
+        let mut entries = Vec::new();
+        for _i in 0..entry_count {
+            entries.push(ZipEntry::read_from(&mut directory).context("bad central directory")?);
+        }
+
+        Ok(Self {
+            inner,
+            entries,
+            comment,
+        })
+    }
+
+    pub fn entries(&self) -> &[ZipEntry] {
+        &self.entries
+    }
+
+    pub fn comment(&self) -> &[u8] {
+        &self.comment
+    }
+
+    /// Index of the entry with the given name.
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.name == name)
+    }
+
+    /// Decompresses the data of an entry into `output`, checking its size
+    /// and CRC32 against the central directory and the data descriptor.
+    pub fn extract<W: Write>(&mut self, index: usize, output: W) -> Result<()> {
+        let entry = self.entries.get(index).context("no such entry")?.clone();
+        ensure!(!entry.is_encrypted(), "encrypted entries are not supported");
+
+        self.inner.seek(SeekFrom::Start(entry.header_offset))?;
+        let mut header = [0; LOCAL_HEADER_SIZE as usize];
+        self.inner.read_exact(&mut header)?;
+        ensure!(
+            header[..4] == LOCAL_HEADER_SIGNATURE.to_le_bytes(),
+            "invalid local header"
+        );
+        // The rest of the header repeats the central directory, or is zero
+        // with a data descriptor.
+        let name_len = u16::from_le_bytes([header[26], header[27]]);
+        let extra_len = u16::from_le_bytes([header[28], header[29]]);
+        self.inner.seek(SeekFrom::Current(name_len as i64))?;
+        let extra = read_bytes(&mut self.inner, extra_len)?;
+        let data_offset =
+            entry.header_offset + LOCAL_HEADER_SIZE + name_len as u64 + extra_len as u64;
+
+        let mut data = BufReader::new((&mut self.inner).take(entry.compressed_size));
+        let (size, crc32) = match entry.method {
+            ZipMethod::Stored => {
+                let mut tracker = TrackingWriter::new(output);
+                io::copy(&mut data, &mut tracker)?;
+                (tracker.byte_count(), tracker.crc32())
+            }
+            ZipMethod::Deflate => inflate(&mut data, output, DeflateFormat::DEFLATE)?,
+            ZipMethod::Deflate64 => inflate(&mut data, output, DeflateFormat::DEFLATE64)?,
+            ZipMethod::Unknown(_) => bail!("unsupported compression method: {}", entry.method),
+        };
+        ensure!(size as u64 == entry.size, "length check failed");
+        ensure!(crc32 == entry.crc32, "crc32 check failed");
+
+        if entry.flags & FLAG_DATA_DESCRIPTOR != 0 {
+            self.inner
+                .seek(SeekFrom::Start(data_offset + entry.compressed_size))?;
+            let is_zip64 = extra_field(&extra, ZIP64_EXTRA_ID).is_some();
+            self.check_data_descriptor(&entry, is_zip64)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the data descriptor after the data of an entry, whose sizes
+    /// take eight bytes if the local header has a ZIP64 field.
+    fn check_data_descriptor(&mut self, entry: &ZipEntry, is_zip64: bool) -> Result<()> {
+        let mut crc32 = self.inner.read_u32::<LittleEndian>()?;
+        // The signature is optional.
+        if crc32 == DATA_DESCRIPTOR_SIGNATURE && entry.crc32 != DATA_DESCRIPTOR_SIGNATURE {
+            crc32 = self.inner.read_u32::<LittleEndian>()?;
+        }
+        let (compressed_size, size) = if is_zip64 {
+            (
+                self.inner.read_u64::<LittleEndian>()?,
+                self.inner.read_u64::<LittleEndian>()?,
+            )
+        } else {
+            (
+                self.inner.read_u32::<LittleEndian>()? as u64,
+                self.inner.read_u32::<LittleEndian>()? as u64,
+            )
+        };
+        ensure!(
+            (crc32, compressed_size, size) == (entry.crc32, entry.compressed_size, entry.size),
+            "data descriptor doesn't match the central directory"
+        );
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// Reads the ZIP64 end of central directory record through its locator
+/// right before the end of central directory record at `record_offset`, and
+/// returns the number of entries and the size and offset of the central
+/// directory.
+fn read_zip64_record<R: Read + Seek>(inner: &mut R, record_offset: u64) -> Result<(u64, u64, u64)> {
+    let locator_offset = record_offset
+        .checked_sub(ZIP64_LOCATOR_SIZE)
+        .context("missing ZIP64 end of central directory locator")?;
+    inner.seek(SeekFrom::Start(locator_offset))?;
+    ensure!(
+        inner.read_u32::<LittleEndian>()? == ZIP64_LOCATOR_SIGNATURE,
+        "missing ZIP64 end of central directory locator"
+    );
+    let _disk = inner.read_u32::<LittleEndian>()?;
+    let zip64_record_offset = inner.read_u64::<LittleEndian>()?;
+
+    inner.seek(SeekFrom::Start(zip64_record_offset))?;
+    ensure!(
+        inner.read_u32::<LittleEndian>()? == ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE,
+        "invalid ZIP64 end of central directory record"
+    );
+    let _record_size = inner.read_u64::<LittleEndian>()?;
+    let _version_made_by = inner.read_u16::<LittleEndian>()?;
+    let _version_needed = inner.read_u16::<LittleEndian>()?;
+    let disk = inner.read_u32::<LittleEndian>()?;
+    let directory_disk = inner.read_u32::<LittleEndian>()?;
+    ensure!(
+        disk == directory_disk,
+        "multi-disk archives are not supported"
+    );
+    let _disk_entry_count = inner.read_u64::<LittleEndian>()?;
+    let entry_count = inner.read_u64::<LittleEndian>()?;
+    let directory_size = inner.read_u64::<LittleEndian>()?;
+    let directory_offset = inner.read_u64::<LittleEndian>()?;
+    Ok((entry_count, directory_size, directory_offset))
+}
+
+/// Decodes a raw deflate stream, returns the size and CRC32 of the output.
+fn inflate<R: Read, W: Write>(
+    input: &mut BufReader<R>,
+    mut output: W,
+    format: DeflateFormat,
+) -> Result<(usize, u32)> {
+    let mut deflate_reader = DeflateReader::with_format(BitReader::new(input), format);
+    while let Some(block) = deflate_reader.next_block() {
+        output.write_all(&block?)?;
+    }
+    let (_, tracker) = deflate_reader.into_inners();
+    Ok((tracker.byte_count(), tracker.crc32()))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Options of an entry written by [`ZipWriter`].
+#[derive(Clone, Debug)]
+pub struct ZipEntryOptions {
+    /// [`ZipMethod::Stored`] or [`ZipMethod::Deflate`].
+    pub method: ZipMethod,
+    /// Compression level of deflate entries, see
+    /// [`CompressOptions::level`](crate::CompressOptions::level).
+    pub level: u32,
+    pub modified: DosDateTime,
+    /// Modification time in seconds since the Unix epoch, written in an
+    /// extended timestamp next to `modified`. Unlike the MS-DOS time, it
+    /// doesn't depend on the time zone of the reader.
+    pub unix_time: Option<u32>,
+    /// Writes the sizes of the entry in the ZIP64 format, which entries of
+    /// 4 GiB or more need. As the data is streamed, this has to be chosen
+    /// before it is written. Stored entries can't be large files, see
+    /// [`ZipWriter`].
+    pub large_file: bool,
+}
+
+impl Default for ZipEntryOptions {
+    fn default() -> Self {
+        Self {
+            method: ZipMethod::Deflate,
+            level: 6,
+            modified: DosDateTime::default(),
+            unix_time: None,
+            large_file: false,
+        }
+    }
+}
+
+/// Counts the bytes written, for the offsets in the central directory.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// An entry written to the archive, with whether its sizes are in the
+/// ZIP64 format.
+struct WrittenEntry {
+    entry: ZipEntry,
+    is_zip64: bool,
+    data_offset: u64,
+}
+
+enum WriterState<W: Write> {
+    Idle(CountingWriter<W>),
+    /// The local header and the data are written when the entry is finished.
+    Stored(WrittenEntry, CountingWriter<W>, TrackingWriter<Vec<u8>>),
+    Deflate(WrittenEntry, DeflateWriter<CountingWriter<W>>),
+    /// After an error, which leaves the archive incomplete.
+    Broken,
+}
+
+/// Writer of a ZIP archive. The data of the entry started last is written
+/// through [`Write`]. The CRC32 and sizes of deflate entries follow their
+/// data in a data descriptor, so the output doesn't have to be seekable.
+/// Readers only accept data descriptors after deflate data, so stored
+/// entries are kept in memory until they are finished and have these in
+/// their local header. That takes as much memory as their data, up to
+/// 4 GiB, and they can't be [large files](ZipEntryOptions::large_file).
+/// Deflate entries at level 0 are streamed with the data stored in blocks.
+pub struct ZipWriter<W: Write> {
+    state: WriterState<W>,
+    entries: Vec<WrittenEntry>,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            state: WriterState::Idle(CountingWriter { inner, count: 0 }),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Finishes the current entry and starts a new one. The names of
+    /// directories end with a slash, and they have no data.
+    pub fn start_entry(&mut self, name: &str, options: &ZipEntryOptions) -> Result<()> {
+        ensure!(
+            !(options.large_file && options.method == ZipMethod::Stored),
+            "stored entries are kept in memory and can't be large files"
+        );
+        let mut inner = self.finish_entry()?;
+        ensure!(name.len() <= u16::MAX as usize, "entry name is too long");
+        let mut flags = 0;
+        if options.method == ZipMethod::Deflate {
+            flags |= FLAG_DATA_DESCRIPTOR;
+        }
+        if !name.is_ascii() {
+            flags |= FLAG_UTF8;
+        }
+        let entry = ZipEntry {
+            name: name.to_owned(),
+            method: options.method,
+            flags,
+            modified: options.modified,
+            unix_time: options.unix_time,
+            crc32: 0,
+            compressed_size: 0,
+            size: 0,
+            header_offset: inner.count,
+            comment: String::new(),
+        };
+
+        let mut written = WrittenEntry {
+            entry,
+            is_zip64: options.large_file,
+            data_offset: 0,
+        };
+        self.state = match options.method {
+            ZipMethod::Stored => {
+                WriterState::Stored(written, inner, TrackingWriter::new(Vec::new()))
+            }
+            ZipMethod::Deflate => {
+                write_local_header(&mut inner, &mut written)?;
+                WriterState::Deflate(
+                    written,
+                    DeflateWriter::with_level(BitWriter::new(inner), options.level)?,
+                )
+            }
+            method => bail!("unsupported compression method: {}", method),
+        };
+        Ok(())
+    }
+
+    /// Finishes the current entry and writes the central directory, returns
+    /// the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        let mut inner = self.finish_entry()?;
+        let directory_offset = inner.count;
+        for entry in &self.entries {
+            write_central_header(&mut inner, entry)?;
+        }
+        let directory_size = inner.count - directory_offset;
+        let entry_count = self.entries.len() as u64;
+
+        if entry_count >= u16::MAX as u64
+            || directory_size >= u32::MAX as u64
+            || directory_offset >= u32::MAX as u64
+        {
+            let record_offset = inner.count;
+            inner.write_u32::<LittleEndian>(ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE)?;
+            // The size of the record excludes the signature and the size.
+            inner.write_u64::<LittleEndian>(ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE - 12)?;
+            inner.write_u16::<LittleEndian>(VERSION_MADE_BY)?;
+            inner.write_u16::<LittleEndian>(VERSION_ZIP64)?;
+            inner.write_u32::<LittleEndian>(0)?;
+            inner.write_u32::<LittleEndian>(0)?;
+            inner.write_u64::<LittleEndian>(entry_count)?;
+            inner.write_u64::<LittleEndian>(entry_count)?;
+            inner.write_u64::<LittleEndian>(directory_size)?;
+            inner.write_u64::<LittleEndian>(directory_offset)?;
+
+            inner.write_u32::<LittleEndian>(ZIP64_LOCATOR_SIGNATURE)?;
+            inner.write_u32::<LittleEndian>(0)?;
+            inner.write_u64::<LittleEndian>(record_offset)?;
+            inner.write_u32::<LittleEndian>(1)?;
+        }
+
+        let entry_count = entry_count.min(u16::MAX as u64) as u16;
+        inner.write_u32::<LittleEndian>(END_OF_CENTRAL_DIRECTORY_SIGNATURE)?;
+        inner.write_u16::<LittleEndian>(0)?;
+        inner.write_u16::<LittleEndian>(0)?;
+        inner.write_u16::<LittleEndian>(entry_count)?;
+        inner.write_u16::<LittleEndian>(entry_count)?;
+        inner.write_u32::<LittleEndian>(saturate(directory_size))?;
+        inner.write_u32::<LittleEndian>(saturate(directory_offset))?;
+        inner.write_u16::<LittleEndian>(0)?;
+        inner.flush()?;
+        Ok(inner.inner)
+    }
+
+    /// Writes the rest of the current entry, if any: the local header and
+    /// the data of a stored entry, or the data descriptor of a deflate one.
+    /// Returns the inner writer.
+    fn finish_entry(&mut self) -> Result<CountingWriter<W>> {
+        let (written, inner) = match mem::replace(&mut self.state, WriterState::Broken) {
+            WriterState::Idle(inner) => return Ok(inner),
+            WriterState::Stored(mut written, mut inner, mut tracker) => {
+                let data = mem::take(tracker.get_mut_ref_inner());
+                let entry = &mut written.entry;
+                entry.size = data.len() as u64;
+                entry.compressed_size = data.len() as u64;
+                entry.crc32 = tracker.crc32();
+                check_sizes(entry, written.is_zip64)?;
+                write_local_header(&mut inner, &mut written)?;
+                inner.write_all(&data)?;
+                (written, inner)
+            }
+            WriterState::Deflate(mut written, deflate_writer) => {
+                let (mut inner, tracker) = deflate_writer.finish()?;
+                let entry = &mut written.entry;
+                entry.size = tracker.byte_count() as u64;
+                entry.crc32 = tracker.crc32();
+                entry.compressed_size = inner.count - written.data_offset;
+                check_sizes(entry, written.is_zip64)?;
+                inner.write_u32::<LittleEndian>(DATA_DESCRIPTOR_SIGNATURE)?;
+                inner.write_u32::<LittleEndian>(entry.crc32)?;
+                if written.is_zip64 {
+                    inner.write_u64::<LittleEndian>(entry.compressed_size)?;
+                    inner.write_u64::<LittleEndian>(entry.size)?;
+                } else {
+                    inner.write_u32::<LittleEndian>(entry.compressed_size as u32)?;
+                    inner.write_u32::<LittleEndian>(entry.size as u32)?;
+                }
+                (written, inner)
+            }
+            WriterState::Broken => bail!("the archive is incomplete after an earlier error"),
+        };
+        self.entries.push(written);
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for ZipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.state {
+            WriterState::Stored(_, _, tracker) => tracker.write(buf),
+            WriterState::Deflate(_, deflate_writer) => deflate_writer.write(buf),
+            WriterState::Idle(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no entry was started",
+            )),
+            WriterState::Broken => Err(io::Error::other(
+                "the archive is incomplete after an earlier error",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.state {
+            WriterState::Idle(inner) | WriterState::Stored(_, inner, _) => inner.flush(),
+            WriterState::Deflate(_, deflate_writer) => deflate_writer.flush(),
+            WriterState::Broken => Ok(()),
+        }
+    }
+}
+
+/// Writes the local header of an entry and records where its data starts.
+/// The CRC32 and the sizes are zero until the data descriptor if the entry
+/// has one. Sizes in the ZIP64 format go in a ZIP64 field, and are saturated
+/// in the header.
+fn write_local_header<W: Write>(
+    writer: &mut CountingWriter<W>,
+    written: &mut WrittenEntry,
+) -> Result<()> {
+    let entry = &written.entry;
+    let mut extra = Vec::new();
+    let (version, compressed_size, size) = if written.is_zip64 {
+        extra.write_u16::<LittleEndian>(ZIP64_EXTRA_ID)?;
+        extra.write_u16::<LittleEndian>(16)?;
+        extra.write_u64::<LittleEndian>(entry.size)?;
+        extra.write_u64::<LittleEndian>(entry.compressed_size)?;
+        (VERSION_ZIP64, u32::MAX, u32::MAX)
+    } else {
+        (
+            VERSION_DEFAULT,
+            entry.compressed_size as u32,
+            entry.size as u32,
+        )
+    };
+    write_extended_timestamp(&mut extra, entry)?;
+    writer.write_u32::<LittleEndian>(LOCAL_HEADER_SIGNATURE)?;
+    writer.write_u16::<LittleEndian>(version)?;
+    writer.write_u16::<LittleEndian>(entry.flags)?;
+    writer.write_u16::<LittleEndian>(entry.method.into())?;
+    writer.write_u16::<LittleEndian>(entry.modified.time)?;
+    writer.write_u16::<LittleEndian>(entry.modified.date)?;
+    writer.write_u32::<LittleEndian>(entry.crc32)?;
+    writer.write_u32::<LittleEndian>(compressed_size)?;
+    writer.write_u32::<LittleEndian>(size)?;
+    writer.write_u16::<LittleEndian>(entry.name.len() as u16)?;
+    writer.write_u16::<LittleEndian>(extra.len() as u16)?;
+    writer.write_all(entry.name.as_bytes())?;
+    writer.write_all(&extra)?;
+    written.data_offset = writer.count;
+    Ok(())
+}
+
+/// Checks that the sizes of an entry fit in 32 bits unless they are in the
+/// ZIP64 format.
+fn check_sizes(entry: &ZipEntry, is_zip64: bool) -> Result<()> {
+    ensure!(
+        is_zip64 || (entry.compressed_size <= u32::MAX as u64 && entry.size <= u32::MAX as u64),
+        "entry {} is 4 GiB or more and needs the large file option",
+        entry.name
+    );
+    Ok(())
+}
+
+/// Writes the central directory header of an entry. Sizes and the offset
+/// that don't fit in 32 bits go in a ZIP64 field, and so do the sizes of
+/// entries that have them in the ZIP64 format in the local header.
+fn write_central_header<W: Write>(writer: &mut W, written: &WrittenEntry) -> Result<()> {
+    let entry = &written.entry;
+    let mut zip64 = Vec::new();
+    let mut widen = |value: u64, force: bool| -> Result<u32> {
+        Ok(if force || value >= u32::MAX as u64 {
+            zip64.write_u64::<LittleEndian>(value)?;
+            u32::MAX
+        } else {
+            value as u32
+        })
+    };
+    let size = widen(entry.size, written.is_zip64)?;
+    let compressed_size = widen(entry.compressed_size, written.is_zip64)?;
+    let header_offset = widen(entry.header_offset, false)?;
+    let mut extra = Vec::new();
+    let version = if zip64.is_empty() {
+        VERSION_DEFAULT
+    } else {
+        extra.write_u16::<LittleEndian>(ZIP64_EXTRA_ID)?;
+        extra.write_u16::<LittleEndian>(zip64.len() as u16)?;
+        extra.extend_from_slice(&zip64);
+        VERSION_ZIP64
+    };
+    write_extended_timestamp(&mut extra, entry)?;
+    let attributes = if entry.is_dir() {
+        DIR_ATTRIBUTES
+    } else {
+        FILE_ATTRIBUTES
+    };
+
+    writer.write_u32::<LittleEndian>(CENTRAL_HEADER_SIGNATURE)?;
+    writer.write_u16::<LittleEndian>(VERSION_MADE_BY)?;
+    writer.write_u16::<LittleEndian>(version)?;
+    writer.write_u16::<LittleEndian>(entry.flags)?;
+    writer.write_u16::<LittleEndian>(entry.method.into())?;
+    writer.write_u16::<LittleEndian>(entry.modified.time)?;
+    writer.write_u16::<LittleEndian>(entry.modified.date)?;
+    writer.write_u32::<LittleEndian>(entry.crc32)?;
+    writer.write_u32::<LittleEndian>(compressed_size)?;
+    writer.write_u32::<LittleEndian>(size)?;
+    writer.write_u16::<LittleEndian>(entry.name.len() as u16)?;
+    writer.write_u16::<LittleEndian>(extra.len() as u16)?;
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.write_u32::<LittleEndian>(attributes)?;
+    writer.write_u32::<LittleEndian>(header_offset)?;
+    writer.write_all(entry.name.as_bytes())?;
+    writer.write_all(&extra)?;
+    Ok(())
+}
+
+/// Appends the extended timestamp of an entry to its extra field, if it has
+/// a Unix time.
+fn write_extended_timestamp(extra: &mut Vec<u8>, entry: &ZipEntry) -> Result<()> {
+    if let Some(unix_time) = entry.unix_time {
+        extra.write_u16::<LittleEndian>(EXTENDED_TIMESTAMP_EXTRA_ID)?;
+        extra.write_u16::<LittleEndian>(EXTENDED_TIMESTAMP_SIZE)?;
+        extra.write_u8(TIMESTAMP_MODIFIED)?;
+        extra.write_u32::<LittleEndian>(unix_time)?;
+    }
+    Ok(())
+}
+
+fn saturate(value: u64) -> u32 {
+    value.min(u32::MAX as u64) as u32
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn open(data: &[u8]) -> Result<ZipArchive<Cursor<&[u8]>>> {
+        ZipArchive::new(Cursor::new(data))
+    }
+
+    fn extract<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<Vec<u8>> {
+        let index = archive.find(name).context("missing entry")?;
+        let mut output = Vec::new();
+        archive.extract(index, &mut output)?;
+        Ok(output)
+    }
+
+    fn cargo_toml() -> Vec<u8> {
+        let mut data = Vec::new();
+        crate::decompress(
+            &include_bytes!("../data/ok/00-Cargo.toml.gz")[..],
+            &mut data,
+        )
+        .unwrap();
+        data
+    }
+
+    #[test]
+    fn entries() -> Result<()> {
+        let mut archive = open(include_bytes!("../data/zip/00-info-zip.zip"))?;
+        let names: Vec<_> = archive.entries().iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["Cargo.toml", "random.bin", "sub/", "sub/nested.txt"]
+        );
+        let entry = &archive.entries()[0];
+        assert_eq!(entry.method, ZipMethod::Deflate);
+        assert_eq!(entry.size, 295);
+        assert_eq!(entry.modified.to_string(), "2024-03-05 14:15:16");
+        assert_eq!(entry.unix_time, None);
+        assert_eq!(archive.entries()[1].method, ZipMethod::Stored);
+        assert!(archive.entries()[2].is_dir());
+
+        assert_eq!(extract(&mut archive, "Cargo.toml")?, cargo_toml());
+        assert_eq!(extract(&mut archive, "random.bin")?.len(), 3000);
+        assert_eq!(extract(&mut archive, "sub/")?, b"");
+        assert_eq!(extract(&mut archive, "sub/nested.txt")?, b"nested file\n");
+        Ok(())
+    }
+
+    #[test]
+    fn zip64_and_data_descriptors() -> Result<()> {
+        let mut archive = open(include_bytes!("../data/zip/01-zip64.zip"))?;
+        assert_eq!(archive.entries()[0].size, 295);
+        assert_eq!(extract(&mut archive, "Cargo.toml")?, cargo_toml());
+
+        let mut archive = open(include_bytes!("../data/zip/02-data-descriptor.zip"))?;
+        assert_ne!(archive.entries()[0].flags & FLAG_DATA_DESCRIPTOR, 0);
+        assert_eq!(extract(&mut archive, "-")?, cargo_toml());
+
+        let mut archive = open(include_bytes!("../data/zip/03-zip64-data-descriptor.zip"))?;
+        assert_eq!(extract(&mut archive, "nested/ünïcode.txt")?, cargo_toml());
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted() -> Result<()> {
+        let data = include_bytes!("../data/zip/02-data-descriptor.zip");
+        assert!(open(&data[..100]).is_err());
+
+        // The CRC32 in the data descriptor.
+        let mut corrupted = data.to_vec();
+        let pos = corrupted
+            .windows(4)
+            .position(|window| window == DATA_DESCRIPTOR_SIGNATURE.to_le_bytes())
+            .unwrap();
+        corrupted[pos + 4] ^= 1;
+        let err = extract(&mut open(&corrupted)?, "-").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "data descriptor doesn't match the central directory"
+        );
+
+        // The data.
+        let mut corrupted = include_bytes!("../data/zip/00-info-zip.zip").to_vec();
+        let entry = open(&corrupted)?.entries()[3].clone();
+        corrupted[entry.header_offset as usize + 30 + entry.name.len()] ^= 1;
+        let err = extract(&mut open(&corrupted)?, &entry.name).unwrap_err();
+        assert_eq!(err.to_string(), "crc32 check failed");
+        Ok(())
+    }
+
+    #[test]
+    fn deflate64() -> Result<()> {
+        // The deflate stream of this entry has no lengths of 258, so it reads
+        // the same as Deflate64.
+        let mut data = include_bytes!("../data/zip/00-info-zip.zip").to_vec();
+        let directory_offset = data
+            .windows(4)
+            .position(|window| window == CENTRAL_HEADER_SIGNATURE.to_le_bytes())
+            .unwrap();
+        for method_offset in [8, directory_offset + 10] {
+            data[method_offset..method_offset + 2].copy_from_slice(&METHOD_DEFLATE64.to_le_bytes());
+        }
+        let mut archive = open(&data)?;
+        assert_eq!(archive.entries()[0].method, ZipMethod::Deflate64);
+        assert_eq!(extract(&mut archive, "Cargo.toml")?, cargo_toml());
+        Ok(())
+    }
+
+    #[test]
+    fn dos_date_time() {
+        let format = |seconds| DosDateTime::from_unix_time(seconds).to_string();
+        assert_eq!(format(1709648116), "2024-03-05 14:15:16");
+        // Rounded down to two seconds, on a leap day.
+        assert_eq!(format(951868799), "2000-02-29 23:59:58");
+        assert_eq!(format(0), "1980-01-01 00:00:00");
+        assert_eq!(format(u64::MAX / 2), "2107-12-31 23:59:58");
+        assert_eq!(DosDateTime::from_unix_time(0), DosDateTime::default());
+    }
+
+    fn write_archive(entries: &[(&str, ZipEntryOptions, &[u8])]) -> Result<Vec<u8>> {
+        let mut writer = ZipWriter::new(Vec::new());
+        for (name, options, data) in entries {
+            writer.start_entry(name, options)?;
+            writer.write_all(data)?;
+        }
+        writer.finish()
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let text = cargo_toml();
+        let modified = DosDateTime::from_unix_time(1709648116);
+        let stored = ZipEntryOptions {
+            method: ZipMethod::Stored,
+            modified,
+            unix_time: Some(1709648116),
+            ..ZipEntryOptions::default()
+        };
+        let large_file = ZipEntryOptions {
+            level: 9,
+            large_file: true,
+            ..ZipEntryOptions::default()
+        };
+        let data = write_archive(&[
+            ("Cargo.toml", ZipEntryOptions::default(), &text),
+            ("sub/", stored.clone(), b""),
+            ("sub/stored.toml", stored.clone(), &text),
+            ("ünïcode.toml", large_file, &text),
+            ("empty", ZipEntryOptions::default(), b""),
+        ])?;
+
+        let mut archive = open(&data)?;
+        let entries = archive.entries().to_vec();
+        let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "Cargo.toml",
+                "sub/",
+                "sub/stored.toml",
+                "ünïcode.toml",
+                "empty"
+            ]
+        );
+        assert_eq!(entries[0].method, ZipMethod::Deflate);
+        assert!(entries[0].compressed_size < entries[0].size);
+        assert_eq!(entries[0].modified, DosDateTime::default());
+        assert!(entries[1].is_dir());
+        assert_eq!(entries[2].method, ZipMethod::Stored);
+        assert_eq!(entries[2].compressed_size, text.len() as u64);
+        assert_eq!(entries[2].modified.to_string(), "2024-03-05 14:15:16");
+        assert_eq!(entries[0].unix_time, None);
+        assert_eq!(entries[2].unix_time, Some(1709648116));
+        assert_eq!(entries[0].flags & FLAG_UTF8, 0);
+        assert_ne!(entries[3].flags & FLAG_UTF8, 0);
+        for entry in &entries {
+            let expected: &[u8] = if entry.is_dir() || entry.name == "empty" {
+                b""
+            } else {
+                &text
+            };
+            assert_eq!(extract(&mut archive, &entry.name)?, expected);
+
+            // Only deflate data is followed by a data descriptor.
+            let header = &data[entry.header_offset as usize..];
+            let flags = u16::from_le_bytes([header[6], header[7]]);
+            let crc32 = u32::from_le_bytes([header[14], header[15], header[16], header[17]]);
+            if entry.method == ZipMethod::Stored {
+                assert_eq!(flags & FLAG_DATA_DESCRIPTOR, 0);
+                assert_eq!(crc32, entry.crc32);
+            } else {
+                assert_ne!(flags & FLAG_DATA_DESCRIPTOR, 0);
+                assert_eq!(crc32, 0);
+            }
+        }
+
+        // The data descriptors are checked against the central directory.
+        let pos = data
+            .windows(4)
+            .rposition(|window| window == DATA_DESCRIPTOR_SIGNATURE.to_le_bytes())
+            .unwrap();
+        let mut corrupted = data.clone();
+        corrupted[pos + 8] ^= 1;
+        assert!(extract(&mut open(&corrupted)?, "empty").is_err());
+
+        // Stored entries are kept in memory.
+        let mut writer = ZipWriter::new(Vec::new());
+        let options = ZipEntryOptions {
+            large_file: true,
+            ..stored
+        };
+        assert!(writer.start_entry("stored64.toml", &options).is_err());
+        writer.start_entry("stored.toml", &stored)?;
+        writer.write_all(&text)?;
+        assert_eq!(open(&writer.finish()?)?.entries().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn zip64_end_of_central_directory() -> Result<()> {
+        let options = ZipEntryOptions {
+            method: ZipMethod::Stored,
+            ..ZipEntryOptions::default()
+        };
+        let mut writer = ZipWriter::new(Vec::new());
+        for i in 0..u16::MAX as usize + 10 {
+            writer.start_entry(&i.to_string(), &options)?;
+        }
+        writer.write_all(b"last")?;
+        let data = writer.finish()?;
+
+        let mut archive = open(&data)?;
+        assert_eq!(archive.entries().len(), u16::MAX as usize + 10);
+        let last = archive.entries().last().unwrap().name.clone();
+        assert_eq!(extract(&mut archive, &last)?, b"last");
+        Ok(())
+    }
+
+    #[test]
+    fn writer_errors() {
+        let mut writer = ZipWriter::new(Vec::new());
+        assert!(writer.write_all(b"data").is_err());
+        let options = ZipEntryOptions {
+            method: ZipMethod::Deflate64,
+            ..ZipEntryOptions::default()
+        };
+        assert!(writer.start_entry("entry", &options).is_err());
+        assert!(writer.finish().is_err());
+    }
+}
@@ -0,0 +1,390 @@
+#![forbid(unsafe_code)]
+
+use std::ops::Range;
+
+use crate::block_writer::{distance_symbol, dynamic_block_size, length_symbol, SymbolCounts};
+use crate::deflate::Token;
+use crate::huffman_coding::fixed_code_lengths;
+use crate::lz77::{hash, HASH_SIZE, MAX_MATCH, MIN_MATCH};
+use crate::tracking_writer::HISTORY_SIZE;
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Blocks shorter than this aren't split further.
+const MIN_SPLIT_TOKENS: usize = 64;
+
+/// Number of split points tried per round of narrowing the best one down.
+const SPLIT_SAMPLES: usize = 9;
+
+/// Parameters of the optimal parsing, see [`optimal_tokens`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZopfliParams {
+    /// Rounds of parsing, each with the symbol costs of the previous one.
+    pub iterations: usize,
+    /// Maximum number of earlier positions to check for matches.
+    pub max_chain: usize,
+    /// Maximum number of blocks the tokens of a segment are split into.
+    pub max_blocks: usize,
+}
+
+impl Default for ZopfliParams {
+    fn default() -> Self {
+        Self {
+            iterations: 15,
+            max_chain: 4096,
+            max_blocks: 15,
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// All useful matches of every position of a segment: for every length, the
+/// closest match at least that long.
+struct MatchTable {
+    /// Start of the runs of every position in `runs`, and the end.
+    offsets: Vec<usize>,
+    /// Longest length with the distance, and the distance, by increasing
+    /// length. Shorter lengths use the distance of the previous run.
+    runs: Vec<(u16, u16)>,
+}
+
+impl MatchTable {
+    /// Finds matches for `data[start..]` with `data[..start]` as history.
+    fn new(data: &[u8], start: usize, max_chain: usize) -> Self {
+        // Chain links store a position plus one, so that zero ends a chain.
+        let mut head = vec![0; HASH_SIZE];
+        let mut prev = vec![0; data.len()];
+        let mut offsets = Vec::with_capacity(data.len() - start + 1);
+        let mut runs = Vec::new();
+        for pos in 0..data.len() {
+            let max_length = MAX_MATCH.min(data.len() - pos);
+            if max_length < MIN_MATCH {
+                if pos >= start {
+                    offsets.push(runs.len());
+                }
+                continue;
+            }
+            let current = &data[pos..pos + max_length];
+            let hash = hash(current);
+
+            if pos >= start {
+                offsets.push(runs.len());
+                let mut best_length = MIN_MATCH - 1;
+                let mut candidate = head[hash];
+                for _ in 0..max_chain {
+                    if candidate == 0 || pos - (candidate - 1) > HISTORY_SIZE {
+                        break;
+                    }
+                    let earlier = &data[candidate - 1..];
+                    if earlier[best_length] == current[best_length] {
+                        let length = earlier
+                            .iter()
+                            .zip(current)
+                            .take_while(|(a, b)| a == b)
+                            .count();
+                        if length > best_length {
+                            best_length = length;
+                            runs.push((length as u16, (pos - (candidate - 1)) as u16));
+                            if length == max_length {
+                                break;
+                            }
+                        }
+                    }
+                    candidate = prev[candidate - 1];
+                }
+            }
+            prev[pos] = head[hash];
+            head[hash] = pos + 1;
+        }
+        offsets.push(runs.len());
+        Self { offsets, runs }
+    }
+
+    fn runs(&self, index: usize) -> &[(u16, u16)] {
+        &self.runs[self.offsets[index]..self.offsets[index + 1]]
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Estimated size in bits of every symbol.
+struct CostModel {
+    litlen: Vec<f64>,
+    distance: Vec<f64>,
+}
+
+impl CostModel {
+    /// Costs of the fixed Huffman codes, see RFC 1951, section 3.2.6.
+    fn fixed() -> Self {
+        let (litlen_lengths, distance_lengths) = fixed_code_lengths();
+        Self {
+            litlen: litlen_lengths.iter().map(|len| *len as f64).collect(),
+            distance: distance_lengths.iter().map(|len| *len as f64).collect(),
+        }
+    }
+
+    /// Costs of symbols occurring with the given frequencies. Unused symbols
+    /// cost as much as the rarest possible one.
+    fn from_counts(counts: &SymbolCounts) -> Self {
+        fn costs(counts: &[u32]) -> Vec<f64> {
+            let total = counts.iter().map(|count| *count as f64).sum::<f64>();
+            let log_total = if total > 0.0 { total.log2() } else { 0.0 };
+            counts
+                .iter()
+                .map(|count| match count {
+                    0 => log_total,
+                    count => log_total - (*count as f64).log2(),
+                })
+                .collect()
+        }
+        Self {
+            litlen: costs(&counts.litlen),
+            distance: costs(&counts.distance),
+        }
+    }
+
+    fn length(&self, length: u16) -> f64 {
+        let (symbol, extra) = length_symbol(length.into());
+        self.litlen[symbol] + extra.len() as f64
+    }
+
+    fn distance(&self, distance: u16) -> f64 {
+        let (symbol, extra) = distance_symbol(distance.into());
+        self.distance[symbol] + extra.len() as f64
+    }
+}
+
+/// Finds the cheapest tokens for `data[start..]` under `model`, as a shortest
+/// path through the positions.
+fn parse(data: &[u8], start: usize, table: &MatchTable, model: &CostModel) -> Vec<Token> {
+    let size = data.len() - start;
+    let length_costs: Vec<f64> = (0..=MAX_MATCH as u16)
+        .map(|length| {
+            if length as usize >= MIN_MATCH {
+                model.length(length)
+            } else {
+                0.0
+            }
+        })
+        .collect();
+
+    // The cheapest way to reach every position, and the length and distance
+    // of the last step, a length of one being a literal.
+    let mut costs = vec![f64::INFINITY; size + 1];
+    let mut steps = vec![(0_u16, 0_u16); size + 1];
+    costs[0] = 0.0;
+    for i in 0..size {
+        let cost = costs[i];
+        let literal_cost = cost + model.litlen[data[start + i] as usize];
+        if literal_cost < costs[i + 1] {
+            costs[i + 1] = literal_cost;
+            steps[i + 1] = (1, 0);
+        }
+
+        let mut length = MIN_MATCH;
+        for (max_length, distance) in table.runs(i) {
+            let base_cost = cost + model.distance(*distance);
+            for len in length..=*max_length as usize {
+                let match_cost = base_cost + length_costs[len];
+                if match_cost < costs[i + len] {
+                    costs[i + len] = match_cost;
+                    steps[i + len] = (len as u16, *distance);
+                }
+            }
+            length = *max_length as usize + 1;
+        }
+    }
+
+    let mut tokens = Vec::new();
+    let mut i = size;
+    while i > 0 {
+        let (length, distance) = steps[i];
+        tokens.push(if length == 1 {
+            Token::Literal(data[start + i - 1])
+        } else {
+            Token::Match {
+                length: length.into(),
+                distance: distance.into(),
+            }
+        });
+        i -= length as usize;
+    }
+    tokens.reverse();
+    tokens
+}
+
+/// Splits `data[start..]` into LZ77 tokens, with `data[..start]` (up to a
+/// window) as history, making the output as small as possible rather than
+/// fast. Tokens are chosen by cost, starting from the costs of fixed codes
+/// and then using the codes that the previous tokens would get, like zopfli.
+pub fn optimal_tokens(data: &[u8], start: usize, params: &ZopfliParams) -> Vec<Token> {
+    let table = MatchTable::new(data, start, params.max_chain);
+    let mut model = CostModel::fixed();
+    let mut best: Option<(u64, Vec<Token>)> = None;
+    for _ in 0..params.iterations.max(1) {
+        let tokens = parse(data, start, &table, &model);
+        let counts = SymbolCounts::from_tokens(&tokens);
+        let size = dynamic_block_size(&counts);
+        if best.as_ref().is_none_or(|(best_size, _)| size < *best_size) {
+            best = Some((size, tokens));
+        }
+        model = CostModel::from_counts(&counts);
+    }
+    best.expect("there is at least one iteration").1
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Splits `tokens` into at most `max_blocks` blocks where separate Huffman
+/// codes make the output smaller. Returns the ends of the blocks.
+pub fn split_blocks(tokens: &[Token], max_blocks: usize) -> Vec<usize> {
+    let cost = |range: Range<usize>| dynamic_block_size(&SymbolCounts::from_tokens(&tokens[range]));
+    let mut ends = vec![tokens.len()];
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    ranges.push(0..tokens.len());
+    while ends.len() < max_blocks {
+        let Some(range) = ranges.pop() else {
+            break;
+        };
+        if range.len() < 2 * MIN_SPLIT_TOKENS {
+            continue;
+        }
+        let (split, split_cost) = find_minimum(
+            range.start + MIN_SPLIT_TOKENS..range.end - MIN_SPLIT_TOKENS,
+            |split| cost(range.start..split) + cost(split..range.end),
+        );
+        if split_cost < cost(range.clone()) {
+            ends.push(split);
+            ranges.push(range.start..split);
+            ranges.push(split..range.end);
+        }
+    }
+    ends.sort_unstable();
+    ends
+}
+
+/// Finds the minimum of `f` in `range`, assuming that it has few local
+/// minima, by sampling it at a few points and narrowing the range down to the
+/// neighbourhood of the best one.
+fn find_minimum<F: FnMut(usize) -> u64>(mut range: Range<usize>, mut f: F) -> (usize, u64) {
+    let mut best = (range.start, u64::MAX);
+    loop {
+        if range.len() <= SPLIT_SAMPLES {
+            for point in range {
+                let value = f(point);
+                if value < best.1 {
+                    best = (point, value);
+                }
+            }
+            return best;
+        }
+
+        let points: Vec<usize> = (1..=SPLIT_SAMPLES)
+            .map(|k| range.start + range.len() * k / (SPLIT_SAMPLES + 1))
+            .collect();
+        let mut best_index = 0;
+        let mut best_value = u64::MAX;
+        for (index, point) in points.iter().enumerate() {
+            let value = f(*point);
+            if value < best_value {
+                best_index = index;
+                best_value = value;
+            }
+        }
+        if best_value < best.1 {
+            best = (points[best_index], best_value);
+        }
+        let start = if best_index == 0 {
+            range.start
+        } else {
+            points[best_index - 1] + 1
+        };
+        let end = points.get(best_index + 1).copied().unwrap_or(range.end);
+        range = start..end;
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lz77::{MatchFinder, MatchParams};
+    use crate::test_util::{random_bytes, text};
+
+    fn sample() -> Vec<u8> {
+        let mut data = text();
+        data.truncate(20000);
+        data
+    }
+
+    fn expand(history: &[u8], tokens: &[Token]) -> Vec<u8> {
+        let mut output = history.to_vec();
+        for token in tokens {
+            match *token {
+                Token::Literal(byte) => output.push(byte),
+                Token::Match { length, distance } => {
+                    assert!(distance as usize <= HISTORY_SIZE);
+                    for _ in 0..length {
+                        output.push(output[output.len() - distance as usize]);
+                    }
+                }
+                Token::BlockBoundary(_) => unreachable!(),
+            }
+        }
+        output.split_off(history.len())
+    }
+
+    #[test]
+    fn smaller_than_lazy_matching() {
+        let data = sample();
+        let params = ZopfliParams {
+            iterations: 5,
+            ..ZopfliParams::default()
+        };
+        let tokens = optimal_tokens(&data, 0, &params);
+        assert_eq!(expand(&[], &tokens), data);
+
+        let mut finder = MatchFinder::new(MatchParams::for_level(9).unwrap());
+        let mut lazy_tokens = Vec::new();
+        finder.push(&data);
+        finder.tokenize(true, &mut lazy_tokens);
+        assert!(
+            dynamic_block_size(&SymbolCounts::from_tokens(&tokens))
+                < dynamic_block_size(&SymbolCounts::from_tokens(&lazy_tokens))
+        );
+    }
+
+    #[test]
+    fn history() {
+        let data = sample();
+        let tokens = optimal_tokens(&data, 10000, &ZopfliParams::default());
+        assert_eq!(expand(&data[..10000], &tokens), &data[10000..]);
+        assert!(tokens
+            .iter()
+            .any(|token| matches!(token, Token::Match { distance, .. } if *distance > 5000)));
+    }
+
+    #[test]
+    fn splitting() {
+        // Text followed by data with a very different distribution.
+        let mut data = sample();
+        data.extend(random_bytes(20000, 7).iter().map(|byte| b'0' + byte % 4));
+        let tokens = optimal_tokens(&data, 0, &ZopfliParams::default());
+        let ends = split_blocks(&tokens, 15);
+        assert!(ends.len() >= 2);
+        assert_eq!(ends.last(), Some(&tokens.len()));
+
+        let split_size: u64 = [0]
+            .iter()
+            .chain(&ends)
+            .zip(&ends)
+            .map(|(start, end)| {
+                dynamic_block_size(&SymbolCounts::from_tokens(&tokens[*start..*end]))
+            })
+            .sum();
+        assert!(split_size < dynamic_block_size(&SymbolCounts::from_tokens(&tokens)));
+        assert_eq!(split_blocks(&tokens, 1), [tokens.len()]);
+    }
+}
@@ -0,0 +1,132 @@
+#![cfg(feature = "tokio")]
+
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use ripgzip::{AsyncGzipDecoder, AsyncGzipEncoder, CompressOptions};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn decode_duplex() -> anyhow::Result<()> {
+    let data = include_bytes!("../data/ok/01-page.gz");
+    let mut expected = Vec::new();
+    ripgzip::decompress(&data[..], &mut expected)?;
+
+    // A small duplex buffer makes the decoder wait for input many times.
+    let (mut client, server) = duplex(64);
+    let writer = tokio::spawn(async move {
+        client.write_all(data).await?;
+        client.shutdown().await
+    });
+    let mut decoder = AsyncGzipDecoder::new(BufReader::new(server));
+    let mut output = Vec::new();
+    decoder.read_to_end(&mut output).await?;
+    writer.await??;
+
+    assert_eq!(output, expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn decode_after_flush() -> anyhow::Result<()> {
+    let data: Vec<u8> = (0..100_000_u32).map(|i| (i % 251) as u8).collect();
+
+    let (client, server) = duplex(1 << 16);
+    let mut encoder = AsyncGzipEncoder::new(client);
+    let mut decoder = AsyncGzipDecoder::new(BufReader::new(server));
+    for chunk in data.chunks(30_000) {
+        encoder.write_all(chunk).await?;
+        encoder.flush().await?;
+        // The flushed data decodes while the stream is still open.
+        let mut output = vec![0; chunk.len()];
+        timeout(Duration::from_secs(10), decoder.read_exact(&mut output)).await??;
+        assert_eq!(output, chunk);
+    }
+
+    encoder.shutdown().await?;
+    let mut rest = Vec::new();
+    decoder.read_to_end(&mut rest).await?;
+    assert!(rest.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn encode_duplex() -> anyhow::Result<()> {
+    let data: Vec<u8> = (0..200_000_u32).map(|i| (i % 251) as u8).collect();
+
+    let (client, mut server) = duplex(1024);
+    let input = data.clone();
+    let writer = tokio::spawn(async move {
+        let mut encoder = AsyncGzipEncoder::new(client);
+        encoder.write_all(&input).await?;
+        encoder.shutdown().await
+    });
+    let mut compressed = Vec::new();
+    server.read_to_end(&mut compressed).await?;
+    writer.await??;
+
+    let mut output = Vec::new();
+    ripgzip::decompress(compressed.as_slice(), &mut output)?;
+    assert_eq!(output, data);
+    // The repeated pattern compresses at the default level.
+    assert!(compressed.len() < data.len() / 10, "{}", compressed.len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn encode_levels() -> anyhow::Result<()> {
+    let data = include_bytes!("../data/ok/01-page.gz");
+    let mut text = Vec::new();
+    ripgzip::decompress(&data[..], &mut text)?;
+
+    let mut sizes = Vec::new();
+    for level in [0, 1, 9] {
+        let options = CompressOptions {
+            level,
+            ..CompressOptions::default()
+        };
+        let mut encoder = AsyncGzipEncoder::with_options(Vec::new(), &options)?;
+        encoder.write_all(&text).await?;
+        encoder.shutdown().await?;
+        let compressed = encoder.into_inner();
+
+        let mut output = Vec::new();
+        ripgzip::decompress(compressed.as_slice(), &mut output)?;
+        assert_eq!(output, text, "level {}", level);
+        sizes.push(compressed.len());
+    }
+    assert!(sizes[0] > text.len());
+    assert!(sizes[1] < text.len() / 2);
+    assert!(sizes[2] <= sizes[1]);
+
+    let options = CompressOptions {
+        level: 10,
+        ..CompressOptions::default()
+    };
+    assert!(AsyncGzipEncoder::with_options(Vec::new(), &options).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn encode_flush() -> anyhow::Result<()> {
+    let data = b"flushed before the end of the stream";
+
+    let (client, server) = duplex(1 << 16);
+    let mut encoder = AsyncGzipEncoder::new(client);
+    let mut decoder = AsyncGzipDecoder::new(BufReader::new(server));
+    encoder.write_all(data).await?;
+    encoder.flush().await?;
+
+    // Everything written so far decodes before the end of the member.
+    let mut output = vec![0; data.len()];
+    timeout(Duration::from_secs(10), decoder.read_exact(&mut output)).await??;
+    assert_eq!(output, data);
+
+    // Without a shutdown the member is truncated after the flushed blocks.
+    drop(encoder);
+    let err = decoder.read_to_end(&mut Vec::new()).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "failed to fill whole buffer");
+    Ok(())
+}
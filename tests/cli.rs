@@ -0,0 +1,245 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+const MEMBER: &[u8] = include_bytes!("../data/ok/00-Cargo.toml.gz");
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ripgzip-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the binary under the name `name`, like a symlink in a container.
+fn run(dir: &Path, name: &str, args: &[&str]) -> Output {
+    let link = dir.join(name);
+    if !link.exists() {
+        symlink(env!("CARGO_BIN_EXE_ripgzip"), &link).unwrap();
+    }
+    Command::new(link)
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+fn decompressed() -> Vec<u8> {
+    let mut output = Vec::new();
+    ripgzip::decompress(MEMBER, &mut output).unwrap();
+    output
+}
+
+#[test]
+fn zcat() {
+    let dir = temp_dir("zcat");
+    fs::write(dir.join("a.gz"), MEMBER).unwrap();
+    fs::write(dir.join("plain.txt"), b"plain\n").unwrap();
+
+    let output = run(&dir, "zcat", &["a.gz"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, decompressed());
+    assert!(dir.join("a.gz").exists());
+
+    assert!(!run(&dir, "zcat", &["plain.txt"]).status.success());
+    let output = run(&dir, "zcat", &["-f", "a.gz", "plain.txt"]);
+    assert!(output.status.success());
+    assert_eq!(
+        output.stdout,
+        [decompressed(), b"plain\n".to_vec()].concat()
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn gzip_and_gunzip() {
+    let dir = temp_dir("gzip");
+    let data: Vec<u8> = (0..100_000_u32).map(|i| (i % 13) as u8).collect();
+    fs::write(dir.join("data.bin"), &data).unwrap();
+
+    assert!(run(&dir, "gzip", &["data.bin"]).status.success());
+    assert!(!dir.join("data.bin").exists());
+    assert!(!run(&dir, "gzip", &["data.bin.gz"]).status.success());
+
+    fs::write(dir.join("data.bin"), b"in the way").unwrap();
+    assert!(!run(&dir, "gunzip", &["data.bin.gz"]).status.success());
+    assert!(run(&dir, "gunzip", &["-f", "data.bin.gz"]).status.success());
+    assert!(!dir.join("data.bin.gz").exists());
+    assert_eq!(fs::read(dir.join("data.bin")).unwrap(), data);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn reproducible() {
+    let dir = temp_dir("reproducible");
+    fs::write(dir.join("a.txt"), b"same data\n").unwrap();
+    fs::write(dir.join("b.txt"), b"same data\n").unwrap();
+    let file = fs::File::options()
+        .write(true)
+        .open(dir.join("b.txt"))
+        .unwrap();
+    file.set_modified(std::time::UNIX_EPOCH).unwrap();
+
+    let compress = |args: &[&str]| {
+        let output = run(&dir, "gzip", args);
+        assert!(output.status.success());
+        output.stdout
+    };
+    assert_ne!(compress(&["-c", "a.txt"]), compress(&["-c", "b.txt"]));
+    let compressed = compress(&["-c", "--reproducible", "a.txt"]);
+    assert_eq!(compressed, compress(&["-c", "--reproducible", "b.txt"]));
+    assert_eq!(compressed[4..8], [0; 4]);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn files_named_like_subcommands() {
+    let dir = temp_dir("subcommands");
+    for name in ["zip", "grep", "help"] {
+        fs::write(dir.join(name), b"data\n").unwrap();
+        assert!(run(&dir, "ripgzip", &["--", name]).status.success());
+        assert!(dir.join(format!("{}.gz", name)).exists(), "{}", name);
+    }
+    fs::write(dir.join("unzip"), b"data\n").unwrap();
+    assert!(run(&dir, "ripgzip", &["-k", "unzip"]).status.success());
+    assert!(dir.join("unzip.gz").exists());
+    // Otherwise the first operand picks the subcommand.
+    let output = run(&dir, "ripgzip", &["grep", "-c", "data", "zip.gz"]);
+    assert_eq!(output.stdout, b"1\n");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn dictzip() {
+    let dir = temp_dir("dictzip");
+    let data: Vec<u8> = (0..200_000_u32)
+        .flat_map(|i| format!("line {}\n", i % 1000).into_bytes())
+        .collect();
+    fs::write(dir.join("dict.txt"), &data).unwrap();
+
+    assert!(run(&dir, "ripgzip", &["--dictzip", "dict.txt"])
+        .status
+        .success());
+    assert!(!dir.join("dict.txt").exists());
+    let compressed = fs::read(dir.join("dict.txt.dz")).unwrap();
+    assert!(compressed.len() < data.len() / 4);
+
+    let output = run(
+        &dir,
+        "ripgzip",
+        &["--offset", "1000000", "--length", "1000", "dict.txt.dz"],
+    );
+    assert!(output.status.success());
+    assert_eq!(output.stdout, &data[1_000_000..1_001_000]);
+    let output = run(&dir, "ripgzip", &["--offset", "1500000", "dict.txt.dz"]);
+    assert_eq!(output.stdout, &data[1_500_000..]);
+
+    assert!(run(&dir, "ripgzip", &["-d", "--dictzip", "dict.txt.dz"])
+        .status
+        .success());
+    assert_eq!(fs::read(dir.join("dict.txt")).unwrap(), data);
+    assert!(
+        !run(&dir, "ripgzip", &["--dictzip", "--rsyncable", "dict.txt"])
+            .status
+            .success()
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn unzip() {
+    let dir = temp_dir("unzip");
+    let archive = include_bytes!("../data/zip/00-info-zip.zip");
+    fs::write(dir.join("a.zip"), archive).unwrap();
+
+    let output = run(&dir, "ripgzip", &["unzip", "-l", "a.zip"]);
+    assert!(output.status.success());
+    let listing = String::from_utf8(output.stdout).unwrap();
+    assert!(listing.contains("2024-03-05 14:15:16  sub/nested.txt"));
+    assert!(listing.contains("4 entries"));
+
+    let output = run(&dir, "ripgzip", &["unzip", "-x", "a.zip", "sub/nested.txt"]);
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read(dir.join("sub/nested.txt")).unwrap(),
+        b"nested file\n"
+    );
+    assert!(!dir.join("Cargo.toml").exists());
+    assert!(!run(&dir, "ripgzip", &["unzip", "-x", "a.zip"])
+        .status
+        .success());
+    assert!(run(&dir, "ripgzip", &["unzip", "-x", "-f", "a.zip"])
+        .status
+        .success());
+    assert_eq!(fs::read(dir.join("Cargo.toml")).unwrap(), decompressed());
+
+    // Entries can't be extracted outside of the current directory.
+    let mut evil = archive.to_vec();
+    for pos in (0..evil.len() - 14).rev() {
+        if &evil[pos..pos + 14] == b"sub/nested.txt" {
+            evil[pos..pos + 14].copy_from_slice(b"..//nested.txt");
+        }
+    }
+    fs::create_dir(dir.join("inner")).unwrap();
+    fs::write(dir.join("inner/evil.zip"), evil).unwrap();
+    let output = run(&dir.join("inner"), "ripgzip", &["unzip", "-x", "evil.zip"]);
+    assert!(!output.status.success());
+    assert!(!dir.join("nested.txt").exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn zip() {
+    let dir = temp_dir("zip");
+    fs::create_dir_all(dir.join("files/sub")).unwrap();
+    fs::write(dir.join("files/Cargo.toml"), decompressed()).unwrap();
+    fs::write(dir.join("files/sub/nested.txt"), b"nested file\n").unwrap();
+
+    let output = run(&dir, "ripgzip", &["zip", "a.zip", "files"]);
+    assert!(output.status.success());
+    assert!(!run(&dir, "ripgzip", &["zip", "a.zip", "files"])
+        .status
+        .success());
+    let output = run(&dir, "ripgzip", &["unzip", "-l", "a.zip"]);
+    let listing = String::from_utf8(output.stdout).unwrap();
+    assert!(listing.contains("deflate"));
+    assert!(listing.contains("files/sub/nested.txt"));
+    assert!(listing.contains("4 entries"));
+
+    fs::create_dir(dir.join("out")).unwrap();
+    let output = run(&dir.join("out"), "ripgzip", &["unzip", "-x", "../a.zip"]);
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read(dir.join("out/files/Cargo.toml")).unwrap(),
+        decompressed()
+    );
+    assert_eq!(
+        fs::read(dir.join("out/files/sub/nested.txt")).unwrap(),
+        b"nested file\n"
+    );
+
+    // The archive doesn't contain itself.
+    let output = run(&dir, "ripgzip", &["zip", "files/self.zip", "files"]);
+    assert!(output.status.success());
+    let output = run(&dir, "ripgzip", &["unzip", "-l", "files/self.zip"]);
+    assert!(!String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("self.zip"));
+
+    // A missing file leaves no archive behind.
+    let output = run(&dir, "ripgzip", &["zip", "b.zip", "missing"]);
+    assert!(!output.status.success());
+    assert!(!dir.join("b.zip").exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
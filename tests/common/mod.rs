@@ -0,0 +1,22 @@
+#![allow(dead_code)]
+
+//! Inputs shared by the integration tests.
+
+use ripgzip::decompress;
+
+/// The text of `data/ok/01-page.gz`.
+pub fn text() -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    decompress(&include_bytes!("../../data/ok/01-page.gz")[..], &mut data)?;
+    Ok(data)
+}
+
+/// Incompressible bytes, the same for the same `seed`.
+pub fn random_bytes(len: usize, mut seed: u32) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect()
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::{random_bytes, text};
+use ripgzip::{
+    compress_with_options, decompress_with_options, decompress_with_progress, CompressOptions,
+    DecompressOptions, ZOPFLI_LEVEL,
+};
+
+/// Text, an incompressible part and a long run.
+fn sample() -> anyhow::Result<Vec<u8>> {
+    let mut data = text()?;
+    data.extend(random_bytes(20_000, 1));
+    data.extend(std::iter::repeat_n(b'x', 10_000));
+    Ok(data)
+}
+
+fn compressed_size(data: &[u8], level: u32) -> anyhow::Result<usize> {
+    let options = CompressOptions {
+        level,
+        ..CompressOptions::default()
+    };
+    let mut compressed = Vec::new();
+    compress_with_options(data, &mut compressed, &options)?;
+
+    let options = DecompressOptions {
+        strict: true,
+        ..DecompressOptions::default()
+    };
+    let mut output = Vec::new();
+    decompress_with_options(compressed.as_slice(), &mut output, &options)?;
+    assert_eq!(output, data, "level {}", level);
+    Ok(compressed.len())
+}
+
+#[test]
+fn levels() -> anyhow::Result<()> {
+    let data = sample()?;
+    let sizes = [0, 1, 6, 9, ZOPFLI_LEVEL]
+        .into_iter()
+        .map(|level| compressed_size(&data, level))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    assert!(sizes[0] > data.len());
+    for pair in sizes.windows(2) {
+        assert!(pair[1] < pair[0], "{:?}", sizes);
+    }
+
+    for level in [2, 3, 4, 5, 7, 8] {
+        compressed_size(&data, level)?;
+    }
+    assert_eq!(
+        compressed_size(b"", 6)?,
+        compressed_size(b"", ZOPFLI_LEVEL)?
+    );
+
+    let options = CompressOptions {
+        level: 10,
+        ..CompressOptions::default()
+    };
+    assert!(compress_with_options(&data[..], Vec::new(), &options).is_err());
+    Ok(())
+}
+
+#[test]
+fn mixed_content_blocks() -> anyhow::Result<()> {
+    let data = [text()?, random_bytes(100_000, 1), text()?].concat();
+    let mut compressed = Vec::new();
+    compress_with_options(
+        data.as_slice(),
+        &mut compressed,
+        &CompressOptions::default(),
+    )?;
+    let stats = decompress_with_progress(
+        compressed.as_slice(),
+        std::io::sink(),
+        &DecompressOptions::default(),
+        |_| {},
+    )?;
+    // The random part is stored, the rest gets its own codes.
+    assert!(stats.stored_blocks >= 1, "{:?}", stats);
+    assert!(stats.dynamic_blocks >= 1, "{:?}", stats);
+    Ok(())
+}
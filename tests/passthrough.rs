@@ -0,0 +1,37 @@
+use ripgzip::{compress, decompress, decompress_with_options, DecompressOptions};
+
+fn run(data: &[u8], passthrough: bool) -> anyhow::Result<Vec<u8>> {
+    let options = DecompressOptions {
+        passthrough,
+        ..DecompressOptions::default()
+    };
+    let mut output = Vec::new();
+    decompress_with_options(data, &mut output, &options)?;
+    Ok(output)
+}
+
+#[test]
+fn passthrough() -> anyhow::Result<()> {
+    let plain = b"not compressed at all\n";
+    assert!(run(plain, false).is_err());
+    assert_eq!(run(plain, true)?, plain);
+    assert_eq!(run(b"", true)?, b"");
+
+    let mut member = Vec::new();
+    compress(&b"compressed\n"[..], &mut member)?;
+    let data = [&member[..], plain].concat();
+    assert!(run(&data, false).is_err());
+    assert_eq!(run(&data, true)?, b"compressed\nnot compressed at all\n");
+    Ok(())
+}
+
+#[test]
+fn compress_round_trip() -> anyhow::Result<()> {
+    let data: Vec<u8> = (0..300_000_u32).map(|i| (i % 7) as u8).collect();
+    let mut compressed = Vec::new();
+    compress(data.as_slice(), &mut compressed)?;
+    let mut output = Vec::new();
+    decompress(compressed.as_slice(), &mut output)?;
+    assert_eq!(output, data);
+    Ok(())
+}
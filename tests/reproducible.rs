@@ -0,0 +1,86 @@
+mod common;
+
+use std::io::{self, Read};
+
+use common::text;
+use ripgzip::{compress_with_options, CompressOptions, MemberHeader, ZOPFLI_LEVEL};
+
+/// Reads at most `chunk_size` bytes at a time.
+struct ChunkedReader<'a> {
+    data: &'a [u8],
+    chunk_size: usize,
+}
+
+impl Read for ChunkedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.chunk_size).min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+}
+
+fn compress(data: &[u8], chunk_size: usize, options: &CompressOptions) -> anyhow::Result<Vec<u8>> {
+    let mut compressed = Vec::new();
+    compress_with_options(ChunkedReader { data, chunk_size }, &mut compressed, options)?;
+    Ok(compressed)
+}
+
+#[test]
+fn header() -> anyhow::Result<()> {
+    let header = MemberHeader {
+        name: Some("data.txt".to_owned()),
+        comment: Some("kept".to_owned()),
+        modification_time: 1_700_000_000,
+        os: 3,
+        ..MemberHeader::default()
+    };
+    let options = CompressOptions {
+        header: header.clone(),
+        reproducible: true,
+        ..CompressOptions::default()
+    };
+    let compressed = compress(b"data", usize::MAX, &options)?;
+    // FCOMMENT only, no MTIME and the unknown OS.
+    assert_eq!(compressed[3], 0x10);
+    assert_eq!(compressed[4..8], [0; 4]);
+    assert_eq!(compressed[9], 255);
+    assert_eq!(&compressed[10..15], b"kept\0");
+
+    let mut stripped = header;
+    stripped.make_reproducible();
+    let options = CompressOptions {
+        header: stripped,
+        ..CompressOptions::default()
+    };
+    assert_eq!(compress(b"data", usize::MAX, &options)?, compressed);
+    Ok(())
+}
+
+#[test]
+fn independent_of_reads() -> anyhow::Result<()> {
+    let mut data = text()?;
+    data.truncate(30_000);
+
+    for level in [0, 1, 6, 9, ZOPFLI_LEVEL] {
+        for rsyncable in [false, true] {
+            let options = CompressOptions {
+                level,
+                rsyncable,
+                reproducible: true,
+                ..CompressOptions::default()
+            };
+            let expected = compress(&data, usize::MAX, &options)?;
+            for chunk_size in [1, 1000, 4096] {
+                assert!(
+                    compress(&data, chunk_size, &options)? == expected,
+                    "level {}, rsyncable {}, chunks of {}",
+                    level,
+                    rsyncable,
+                    chunk_size
+                );
+            }
+        }
+    }
+    Ok(())
+}
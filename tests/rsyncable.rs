@@ -0,0 +1,56 @@
+mod common;
+
+use common::text;
+use ripgzip::{compress_with_options, decompress, CompressOptions, ZOPFLI_LEVEL};
+
+fn compress(data: &[u8], level: u32, rsyncable: bool) -> anyhow::Result<Vec<u8>> {
+    let options = CompressOptions {
+        level,
+        rsyncable,
+        ..CompressOptions::default()
+    };
+    let mut compressed = Vec::new();
+    compress_with_options(data, &mut compressed, &options)?;
+    let mut output = Vec::new();
+    decompress(compressed.as_slice(), &mut output)?;
+    assert_eq!(output, data);
+    Ok(compressed)
+}
+
+/// Length of the common prefix and suffix of two members, without their
+/// footers.
+fn unchanged_bytes(first: &[u8], second: &[u8]) -> usize {
+    let (first, second) = (&first[..first.len() - 8], &second[..second.len() - 8]);
+    let prefix = first.iter().zip(second).take_while(|(a, b)| a == b).count();
+    let suffix = first
+        .iter()
+        .rev()
+        .zip(second.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    prefix + suffix
+}
+
+#[test]
+fn local_changes() -> anyhow::Result<()> {
+    let mut data = text()?;
+    data.truncate(100_000);
+    let mut edited = data.clone();
+    edited.splice(40_000..40_010, b"inserted".repeat(3));
+
+    for level in [1, 6, ZOPFLI_LEVEL] {
+        let first = compress(&data, level, true)?;
+        let second = compress(&edited, level, true)?;
+        assert!(
+            unchanged_bytes(&first, &second) > first.len() * 9 / 10,
+            "level {}",
+            level
+        );
+
+        // Compression suffers from the lost history, but not much.
+        let plain = compress(&data, level, false)?;
+        assert!(first.len() < plain.len() * 5 / 4, "level {}", level);
+        assert!(unchanged_bytes(&plain, &compress(&edited, level, false)?) < first.len() / 2);
+    }
+    Ok(())
+}
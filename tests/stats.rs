@@ -0,0 +1,28 @@
+use ripgzip::{decompress_with_progress, DecompressOptions};
+
+#[test]
+fn stats() -> anyhow::Result<()> {
+    let data = include_bytes!("../data/ok/09-concat.gz");
+    let mut output = Vec::new();
+    let mut calls = 0;
+    let mut last_output_bytes = 0;
+    let stats = decompress_with_progress(
+        &data[..],
+        &mut output,
+        &DecompressOptions::default(),
+        |stats| {
+            assert!(stats.output_bytes >= last_output_bytes);
+            last_output_bytes = stats.output_bytes;
+            calls += 1;
+        },
+    )?;
+
+    assert_eq!(stats.members, 3);
+    assert_eq!(
+        stats.stored_blocks + stats.fixed_blocks + stats.dynamic_blocks + 1,
+        calls
+    );
+    assert_eq!(stats.input_bytes, data.len() as u64);
+    assert_eq!(stats.output_bytes, output.len() as u64);
+    Ok(())
+}
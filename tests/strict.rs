@@ -0,0 +1,38 @@
+use ripgzip::{decompress_with_options, DecompressOptions};
+
+fn run_strict(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let options = DecompressOptions {
+        strict: true,
+        ..DecompressOptions::default()
+    };
+    let mut output = Vec::new();
+    decompress_with_options(data, &mut output, &options)?;
+    Ok(output)
+}
+
+#[test]
+fn strict_accepts_valid_files() -> anyhow::Result<()> {
+    for data in [
+        &include_bytes!("../data/ok/00-Cargo.toml.gz")[..],
+        &include_bytes!("../data/ok/01-page.gz")[..],
+        &include_bytes!("../data/ok/09-concat.gz")[..],
+    ] {
+        let mut expected = Vec::new();
+        ripgzip::decompress(data, &mut expected)?;
+        assert_eq!(run_strict(data)?, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn reserved_literal_length_code() {
+    // A fixed block holding the literal/length code 286.
+    let data = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x1b, 0x03, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let err = ripgzip::decompress(&data[..], std::io::sink()).unwrap_err();
+    assert_eq!(err.to_string(), "invalid literal/length code");
+    let err = run_strict(&data).unwrap_err();
+    assert_eq!(err.to_string(), "invalid literal/length code");
+}
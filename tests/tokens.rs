@@ -0,0 +1,35 @@
+use ripgzip::{read_member_tokens, CompressionType, Token};
+
+#[test]
+fn tokens() -> anyhow::Result<()> {
+    let data: &[u8] = include_bytes!("../data/ok/01-page.gz");
+    let mut expected = Vec::new();
+    ripgzip::decompress(data, &mut expected)?;
+
+    let (_, token_reader) = read_member_tokens(data)?;
+    let mut output: Vec<u8> = Vec::new();
+    let mut blocks = Vec::new();
+    for token in token_reader {
+        match token? {
+            Token::Literal(byte) => output.push(byte),
+            Token::Match { length, distance } => {
+                for _i in 0..length {
+                    output.push(output[output.len() - distance as usize]);
+                }
+            }
+            Token::BlockBoundary(header) => blocks.push(header),
+        }
+    }
+
+    assert_eq!(output, expected);
+    assert!(!blocks.is_empty());
+    assert!(blocks.last().unwrap().is_final);
+    assert!(blocks[..blocks.len() - 1]
+        .iter()
+        .all(|block| !block.is_final));
+    assert!(blocks
+        .iter()
+        .all(|block| block.compression_type != CompressionType::Reserved));
+
+    Ok(())
+}
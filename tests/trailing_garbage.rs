@@ -0,0 +1,77 @@
+use std::io::BufReader;
+
+use ripgzip::{decompress, decompress_with_options, DecompressOptions, TrailingGarbage};
+
+const MEMBER: &[u8] = include_bytes!("../data/ok/00-Cargo.toml.gz");
+
+fn with_trailer(trailer: &[u8]) -> Vec<u8> {
+    let mut data = MEMBER.to_vec();
+    data.extend_from_slice(trailer);
+    data
+}
+
+fn run(data: &[u8], policy: TrailingGarbage) -> anyhow::Result<Vec<u8>> {
+    let options = DecompressOptions {
+        trailing_garbage: policy,
+        ..DecompressOptions::default()
+    };
+    let mut output = Vec::new();
+    decompress_with_options(data, &mut output, &options)?;
+    Ok(output)
+}
+
+#[test]
+fn trailing_garbage() -> anyhow::Result<()> {
+    let mut expected = Vec::new();
+    decompress(MEMBER, &mut expected)?;
+
+    let zeros = with_trailer(&[0; 1000]);
+    let garbage = with_trailer(b"garbage");
+    let zeros_then_member = [&[0; 512][..], MEMBER].concat();
+    let zeros_then_member = with_trailer(&zeros_then_member);
+
+    assert!(run(&zeros, TrailingGarbage::Error).is_err());
+    assert!(run(&garbage, TrailingGarbage::Error).is_err());
+
+    assert_eq!(run(&zeros, TrailingGarbage::Ignore)?, expected);
+    assert_eq!(run(&garbage, TrailingGarbage::Ignore)?, expected);
+
+    assert_eq!(run(&zeros, TrailingGarbage::IgnoreZeros)?, expected);
+    assert!(run(&garbage, TrailingGarbage::IgnoreZeros).is_err());
+    assert_eq!(
+        run(&zeros_then_member, TrailingGarbage::IgnoreZeros)?,
+        [&expected[..], &expected[..]].concat()
+    );
+
+    assert_eq!(run(&garbage, TrailingGarbage::Warn)?, expected);
+
+    Ok(())
+}
+
+#[test]
+fn magic_across_buffers() -> anyhow::Result<()> {
+    let mut expected = Vec::new();
+    decompress(MEMBER, &mut expected)?;
+    // After the member, the buffer holds a single byte.
+    let run_split = |data: &[u8], policy| {
+        let options = DecompressOptions {
+            trailing_garbage: policy,
+            ..DecompressOptions::default()
+        };
+        let input = BufReader::with_capacity(MEMBER.len() + 1, data);
+        let mut output = Vec::new();
+        decompress_with_options(input, &mut output, &options).map(|_| output)
+    };
+
+    let garbage = with_trailer(b"\x1fgarbage");
+    assert_eq!(run_split(&garbage, TrailingGarbage::Ignore)?, expected);
+    assert_eq!(
+        run(&with_trailer(b"\x1f"), TrailingGarbage::Ignore)?,
+        expected
+    );
+    assert_eq!(
+        run_split(&with_trailer(MEMBER), TrailingGarbage::Error)?,
+        [&expected[..], &expected[..]].concat()
+    );
+    Ok(())
+}